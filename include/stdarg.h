@@ -1,7 +1,12 @@
-
 #ifndef __STDARG_H
 #define __STDARG_H
 
+// the class of the type for va_arg: 0 integer, 1 floating point, 2 memory (struct, union)
+#define __va_class(type) __builtin_reg_class((type *)0)
+
+#if defined(__x86_64__)
+
+// System V AMD64 ABI
 typedef struct {
     unsigned int gp_offset;
     unsigned int fp_offset;
@@ -11,33 +16,113 @@ typedef struct {
 
 typedef __va_elem va_list[1];
 
+static void *__va_arg_mem(__va_elem *ap, unsigned long size) {
+    void *r = ap->overflow_arg_area;
+    ap->overflow_arg_area = (char *)r + (size + 7) / 8 * 8;
+    return r;
+}
+
 static void *__va_arg_gp(__va_elem *ap) {
+    // 6 registers of 8 bytes
+    if (ap->gp_offset >= 48)
+        return __va_arg_mem(ap, 8);
     void *r = (char *)ap->reg_save_area + ap->gp_offset;
     ap->gp_offset += 8;
     return r;
 }
 
 static void *__va_arg_fp(__va_elem *ap) {
+    // 8 registers of 16 bytes after the general purpose registers
+    if (ap->fp_offset >= 176)
+        return __va_arg_mem(ap, 8);
     void *r = (char *)ap->reg_save_area + ap->fp_offset;
     ap->fp_offset += 16;
     return r;
 }
 
-static void *__va_arg_mem(__va_elem *ap) {
-    1 / 0; // unimplemented
+#define va_start(ap, last) __builtin_va_start(ap)
+#define va_arg(ap, type)                                           \
+    (*(type *)(__va_class(type) == 0 ? __va_arg_gp(ap) :           \
+               __va_class(type) == 1 ? __va_arg_fp(ap) :           \
+               __va_arg_mem(ap, sizeof(type))))
+#define va_end(ap) __builtin_va_end(ap)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+
+#elif defined(__aarch64__)
+
+// Procedure Call Standard for the Arm 64-bit Architecture.
+// va_list is an array so that passing it to a function passes a pointer as the ABI does
+typedef struct {
+    void *__stack;
+    void *__gr_top;
+    void *__vr_top;
+    int __gr_offs;
+    int __vr_offs;
+} __va_elem;
+
+typedef __va_elem va_list[1];
+
+static void *__va_arg_mem(__va_elem *ap, unsigned long size) {
+    void *r = ap->__stack;
+    ap->__stack = (char *)r + (size + 7) / 8 * 8;
+    return r;
+}
+
+static void *__va_arg_gp(__va_elem *ap, unsigned long size) {
+    // a struct larger than 16 bytes is passed as a pointer to its copy
+    if (size > 16)
+        return *(void **)__va_arg_gp(ap, 8);
+    int regs_size = (size + 7) / 8 * 8;
+    if (ap->__gr_offs + regs_size > 0) {
+        ap->__gr_offs = 0;
+        return __va_arg_mem(ap, size);
+    }
+    void *r = (char *)ap->__gr_top + ap->__gr_offs;
+    ap->__gr_offs += regs_size;
+    return r;
+}
+
+static void *__va_arg_fp(__va_elem *ap) {
+    if (ap->__vr_offs >= 0)
+        return __va_arg_mem(ap, 8);
+    void *r = (char *)ap->__vr_top + ap->__vr_offs;
+    ap->__vr_offs += 16;
+    return r;
 }
 
 #define va_start(ap, last) __builtin_va_start(ap)
-#define va_arg(ap, type)                                \
-    ({                                                  \
-        int klass = __builtin_reg_class((type *)0);     \
-        *(type *)(klass == 0 ? __va_arg_gp(ap) :        \
-                  klass == 1 ? __va_arg_fp(ap) :        \
-                  __va_arg_mem(ap));                    \
-    })
-
-#define va_end(ap) 1
-#define va_copy(dest, src) ((dest)[0] = (src)[0])
+#define va_arg(ap, type)                                                     \
+    (*(type *)(__va_class(type) == 1 ? __va_arg_fp(ap) : __va_arg_gp(ap, sizeof(type))))
+#define va_end(ap) __builtin_va_end(ap)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+
+#elif defined(__riscv) || defined(__i386__)
+
+// the variable arguments are in slots of the size of a register.
+// on riscv64, the arguments in registers are saved right below the ones on the stack
+// and a struct larger than two registers is passed as a pointer to its copy.
+// on i386, all of them are on the stack
+typedef char *va_list;
+
+#define __va_slot_size(type) ((sizeof(type) + sizeof(long) - 1) / sizeof(long) * sizeof(long))
+#define __va_next(ap, size) (((ap) += (size)) - (size))
+#ifdef __riscv
+#define __va_by_ref(type) (sizeof(type) > 2 * sizeof(long))
+#else
+#define __va_by_ref(type) 0
+#endif
+
+#define va_start(ap, last) __builtin_va_start(&(ap))
+#define va_arg(ap, type)                                                \
+    (*(type *)(__va_by_ref(type) ?                                      \
+               *(char **)__va_next(ap, sizeof(long)) :                  \
+               __va_next(ap, __va_slot_size(type))))
+#define va_end(ap) __builtin_va_end(&(ap))
+#define va_copy(dest, src) ((dest) = (src))
+
+#else
+#error "stdarg.h: variable arguments are not supported on this target"
+#endif
 
 // Workaround to load stdio.h properly
 #define __GNUC_VA_LIST 1
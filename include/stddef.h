@@ -3,9 +3,9 @@
 
 #define NULL ((void *)0)
 
-typedef unsigned long size_t;
-typedef long ptrdiff_t;
-typedef unsigned int wchar_t;
+typedef __SIZE_TYPE__ size_t;
+typedef __PTRDIFF_TYPE__ ptrdiff_t;
+typedef __WCHAR_TYPE__ wchar_t;
 typedef long double max_align_t;
 
 #define offsetof(type, member) ((size_t)&(((type *)0)->member))
@@ -0,0 +1,554 @@
+use node::{ASTKind, Bits, AST};
+use types::{Sign, StorageClass, Type};
+use lexer::{escape_c_chars, Pos};
+
+// --dump-ast=sexpr|json|dot
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Sexpr,
+    Json,
+    Dot,
+}
+
+impl Format {
+    pub fn from_str(s: &str) -> Option<Format> {
+        match s {
+            "sexpr" => Some(Format::Sexpr),
+            "json" => Some(Format::Json),
+            "dot" => Some(Format::Dot),
+            _ => None,
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Sexpr => "sexpr",
+            Format::Json => "json",
+            Format::Dot => "dot",
+        }
+    }
+}
+
+// AST and Type are converted into this tree first, then printed in each format
+struct Node {
+    kind: &'static str,
+    pos: Option<Pos>,
+    fields: Vec<(&'static str, Value)>,
+}
+
+enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Node(Node),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn is_scalar(&self) -> bool {
+        match *self {
+            Value::Node(_) => false,
+            Value::List(ref elems) => elems.iter().all(|e| e.is_scalar()),
+            _ => true,
+        }
+    }
+}
+
+// dump the toplevels of the translation unit 'filename'
+pub fn dump(asts: &Vec<AST>, filename: &str, format: Format) -> String {
+    let root = Node {
+        kind: "translation-unit",
+        pos: None,
+        fields: vec![
+            ("file", Value::Str(filename.to_string())),
+            ("decls", Value::List(asts.iter().map(ast_value).collect())),
+        ],
+    };
+    match format {
+        Format::Sexpr => {
+            let mut out = "".to_string();
+            sexpr_node(&root, 0, &mut out);
+            out + "\n"
+        }
+        Format::Json => {
+            let mut out = "".to_string();
+            json_node(&root, 0, &mut out);
+            out + "\n"
+        }
+        Format::Dot => {
+            let mut out = "digraph ast {\n    node [shape=box];\n".to_string();
+            let mut id = 0;
+            dot_node(&root, &mut id, &mut out);
+            out + "}\n"
+        }
+    }
+}
+
+fn ast_value(ast: &AST) -> Value {
+    Value::Node(ast_node(ast))
+}
+
+fn opt_ast_value(ast: &Option<Box<AST>>) -> Value {
+    match *ast {
+        Some(ref ast) => ast_value(ast),
+        None => Value::Null,
+    }
+}
+
+fn asts_value(asts: &Vec<AST>) -> Value {
+    Value::List(asts.iter().map(ast_value).collect())
+}
+
+fn ast_node(ast: &AST) -> Node {
+    let (kind, fields) = match ast.kind {
+        ASTKind::Int(n, ref bits) => (
+            "int",
+            vec![("value", Value::Int(n)), ("bits", Value::Int(bits_value(bits)))],
+        ),
+        ASTKind::Float(f) => ("float", vec![("value", Value::Float(f))]),
+        ASTKind::Char(c) => ("char", vec![("value", Value::Int(c as i64))]),
+        ASTKind::String(ref s) => ("string", vec![("value", Value::Str(s.to_string()))]),
+        ASTKind::Typedef(ref from, ref to) => (
+            "typedef",
+            vec![("type", type_value(from)), ("name", Value::Str(to.to_string()))],
+        ),
+        ASTKind::TypeCast(ref expr, ref ty) => (
+            "typecast",
+            vec![("type", type_value(ty)), ("expr", ast_value(expr))],
+        ),
+        ASTKind::Load(ref expr) => ("load", vec![("expr", ast_value(expr))]),
+        ASTKind::Variable(ref ty, ref name) => (
+            "variable",
+            vec![("name", Value::Str(name.to_string())), ("type", type_value(ty))],
+        ),
+        ASTKind::VariableDecl(ref ty, ref name, ref sclass, ref init) => (
+            "var-decl",
+            vec![
+                ("name", Value::Str(name.to_string())),
+                ("storage", sclass_value(sclass)),
+                ("type", type_value(ty)),
+                ("init", opt_ast_value(init)),
+            ],
+        ),
+        ASTKind::ConstArray(ref elems) => ("const-array", vec![("elems", asts_value(elems))]),
+        ASTKind::ConstStruct(ref elems) => ("const-struct", vec![("elems", asts_value(elems))]),
+        ASTKind::UnaryOp(ref expr, ref op) => (
+            "unary-op",
+            vec![("op", Value::Str(format!("{:?}", op))), ("expr", ast_value(expr))],
+        ),
+        ASTKind::BinaryOp(ref lhs, ref rhs, ref op) => (
+            "binary-op",
+            vec![
+                ("op", Value::Str(format!("{:?}", op))),
+                ("lhs", ast_value(lhs)),
+                ("rhs", ast_value(rhs)),
+            ],
+        ),
+        ASTKind::TernaryOp(ref cond, ref then_e, ref else_e) => (
+            "ternary-op",
+            vec![
+                ("cond", ast_value(cond)),
+                ("then", ast_value(then_e)),
+                ("else", ast_value(else_e)),
+            ],
+        ),
+        ASTKind::FuncDef(ref functy, ref param_names, ref name, ref sclass, ref body) => (
+            "func-def",
+            vec![
+                ("name", Value::Str(name.to_string())),
+                ("storage", sclass_value(sclass)),
+                ("type", type_value(functy)),
+                (
+                    "params",
+                    Value::List(
+                        param_names
+                            .iter()
+                            .map(|name| Value::Str(name.to_string()))
+                            .collect(),
+                    ),
+                ),
+                ("body", ast_value(body)),
+            ],
+        ),
+        ASTKind::Block(ref stmts) => ("block", vec![("stmts", asts_value(stmts))]),
+        ASTKind::Compound(ref stmts) => ("compound", vec![("stmts", asts_value(stmts))]),
+        ASTKind::If(ref cond, ref then_s, ref else_s) => (
+            "if",
+            vec![
+                ("cond", ast_value(cond)),
+                ("then", ast_value(then_s)),
+                ("else", ast_value(else_s)),
+            ],
+        ),
+        ASTKind::For(ref init, ref cond, ref step, ref body) => (
+            "for",
+            vec![
+                ("init", ast_value(init)),
+                ("cond", ast_value(cond)),
+                ("step", ast_value(step)),
+                ("body", ast_value(body)),
+            ],
+        ),
+        ASTKind::While(ref cond, ref body) => (
+            "while",
+            vec![("cond", ast_value(cond)), ("body", ast_value(body))],
+        ),
+        ASTKind::DoWhile(ref cond, ref body) => (
+            "do-while",
+            vec![("cond", ast_value(cond)), ("body", ast_value(body))],
+        ),
+        ASTKind::Switch(ref cond, ref body) => (
+            "switch",
+            vec![("cond", ast_value(cond)), ("body", ast_value(body))],
+        ),
+        ASTKind::Case(ref expr) => ("case", vec![("expr", ast_value(expr))]),
+        ASTKind::DefaultL => ("default", vec![]),
+        ASTKind::Goto(ref label) => ("goto", vec![("label", Value::Str(label.to_string()))]),
+        ASTKind::Label(ref name) => ("label", vec![("name", Value::Str(name.to_string()))]),
+        ASTKind::FuncCall(ref func, ref args) => (
+            "func-call",
+            vec![("func", ast_value(func)), ("args", asts_value(args))],
+        ),
+        ASTKind::StructRef(ref expr, ref field) => (
+            "struct-ref",
+            vec![("expr", ast_value(expr)), ("field", Value::Str(field.to_string()))],
+        ),
+        ASTKind::Break => ("break", vec![]),
+        ASTKind::Continue => ("continue", vec![]),
+        ASTKind::Return(ref retval) => ("return", vec![("value", opt_ast_value(retval))]),
+    };
+    Node {
+        kind: kind,
+        // line 0 is a node the parser made up (e.g. an empty else)
+        pos: if ast.pos.line == 0 {
+            None
+        } else {
+            Some(ast.pos.clone())
+        },
+        fields: fields,
+    }
+}
+
+fn bits_value(bits: &Bits) -> i64 {
+    match *bits {
+        Bits::Bits8 => 8,
+        Bits::Bits16 => 16,
+        Bits::Bits32 => 32,
+        Bits::Bits64 => 64,
+    }
+}
+
+fn sclass_value(sclass: &StorageClass) -> Value {
+    Value::Str(
+        match *sclass {
+            StorageClass::Typedef => "typedef",
+            StorageClass::Extern => "extern",
+            StorageClass::Static => "static",
+            StorageClass::Auto => "auto",
+            StorageClass::Register => "register",
+        }.to_string(),
+    )
+}
+
+fn type_value(ty: &Type) -> Value {
+    let int_ty = |kind: &'static str, sign: &Sign| {
+        let sign = match *sign {
+            Sign::Signed => "signed",
+            Sign::Unsigned => "unsigned",
+        };
+        (kind, vec![("sign", Value::Str(sign.to_string()))])
+    };
+    let (kind, fields) = match *ty {
+        Type::Void => ("void", vec![]),
+        Type::Char(ref sign) => int_ty("char", sign),
+        Type::Short(ref sign) => int_ty("short", sign),
+        Type::Int(ref sign) => int_ty("int", sign),
+        Type::Long(ref sign) => int_ty("long", sign),
+        Type::LLong(ref sign) => int_ty("long-long", sign),
+        Type::Float => ("float", vec![]),
+        Type::Double => ("double", vec![]),
+        Type::Ptr(ref elem_ty) => ("pointer", vec![("elem", type_value(elem_ty))]),
+        Type::Array(ref elem_ty, size) => (
+            "array",
+            vec![("size", Value::Int(size as i64)), ("elem", type_value(elem_ty))],
+        ),
+        Type::Func(ref ret_ty, ref param_tys, vararg) => (
+            "function",
+            vec![
+                ("vararg", Value::Bool(vararg)),
+                ("ret", type_value(ret_ty)),
+                ("params", Value::List(param_tys.iter().map(type_value).collect())),
+            ],
+        ),
+        Type::Struct(ref name, ref fields) => (
+            "struct",
+            vec![("name", Value::Str(name.to_string())), ("fields", asts_value(fields))],
+        ),
+        Type::Union(ref name, ref fields, _) => (
+            "union",
+            vec![("name", Value::Str(name.to_string())), ("fields", asts_value(fields))],
+        ),
+        Type::Enum => ("enum", vec![]),
+    };
+    Value::Node(Node {
+        kind: kind,
+        pos: None,
+        fields: fields,
+    })
+}
+
+fn indent(level: usize) -> String {
+    "  ".repeat(level)
+}
+
+// (kind :pos LINE:COLUMN :scalar-field value
+//   :node-field (...))
+fn sexpr_node(node: &Node, level: usize, out: &mut String) {
+    out.push('(');
+    out.push_str(node.kind);
+    if let Some(ref pos) = node.pos {
+        out.push_str(format!(" :pos {}:{}", pos.line, pos.column).as_str());
+    }
+    for &(name, ref val) in &node.fields {
+        if val.is_scalar() {
+            out.push_str(format!(" :{} ", name).as_str());
+            sexpr_value(val, level + 1, out);
+        }
+    }
+    for &(name, ref val) in &node.fields {
+        if !val.is_scalar() {
+            out.push_str(format!("\n{}:{} ", indent(level + 1), name).as_str());
+            sexpr_value(val, level + 1, out);
+        }
+    }
+    out.push(')');
+}
+
+fn sexpr_value(val: &Value, level: usize, out: &mut String) {
+    match *val {
+        Value::Null => out.push_str("nil"),
+        Value::Bool(b) => out.push_str(if b { "t" } else { "nil" }),
+        Value::Int(i) => out.push_str(format!("{}", i).as_str()),
+        Value::Float(f) => out.push_str(format!("{:?}", f).as_str()),
+        Value::Str(ref s) => out.push_str(c_str(s).as_str()),
+        Value::Node(ref node) => sexpr_node(node, level, out),
+        Value::List(ref elems) => {
+            out.push('(');
+            for (i, elem) in elems.iter().enumerate() {
+                if val.is_scalar() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                } else {
+                    out.push_str(format!("\n{}", indent(level + 1)).as_str());
+                }
+                sexpr_value(elem, level + 1, out);
+            }
+            out.push(')');
+        }
+    }
+}
+
+// a C string literal (\n, \t, \r, \\, \" and octal escapes), which emacs lisp reads as well
+fn c_str(s: &str) -> String {
+    format!("\"{}\"", escape_c_chars(s, '"'))
+}
+
+fn json_node(node: &Node, level: usize, out: &mut String) {
+    out.push_str("{\n");
+    out.push_str(format!("{}\"kind\": \"{}\"", indent(level + 1), node.kind).as_str());
+    if let Some(ref pos) = node.pos {
+        out.push_str(
+            format!(
+                ",\n{}\"pos\": {{\"line\": {}, \"column\": {}}}",
+                indent(level + 1),
+                pos.line,
+                pos.column
+            ).as_str(),
+        );
+    }
+    for &(name, ref val) in &node.fields {
+        out.push_str(format!(",\n{}\"{}\": ", indent(level + 1), name).as_str());
+        json_value(val, level + 1, out);
+    }
+    out.push_str(format!("\n{}}}", indent(level)).as_str());
+}
+
+fn json_value(val: &Value, level: usize, out: &mut String) {
+    match *val {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if b { "true" } else { "false" }),
+        Value::Int(i) => out.push_str(format!("{}", i).as_str()),
+        // NaN and infinity can't be represented in JSON
+        Value::Float(f) if !f.is_finite() => out.push_str("null"),
+        Value::Float(f) => out.push_str(format!("{:?}", f).as_str()),
+        Value::Str(ref s) => out.push_str(json_str(s).as_str()),
+        Value::Node(ref node) => json_node(node, level, out),
+        Value::List(ref elems) => {
+            if elems.is_empty() {
+                out.push_str("[]");
+                return;
+            }
+            out.push('[');
+            for (i, elem) in elems.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(format!("\n{}", indent(level + 1)).as_str());
+                json_value(elem, level + 1, out);
+            }
+            out.push_str(format!("\n{}]", indent(level)).as_str());
+        }
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut escaped = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 => escaped.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// scalar fields go into the label of the graph node, and nodes become edges.
+// returns the id of 'node'.
+fn dot_node(node: &Node, id: &mut usize, out: &mut String) -> usize {
+    let my_id = *id;
+    *id += 1;
+
+    let mut label = node.kind.to_string();
+    if let Some(ref pos) = node.pos {
+        label.push_str(format!("\n{}:{}", pos.line, pos.column).as_str());
+    }
+    for &(name, ref val) in &node.fields {
+        if val.is_scalar() {
+            label.push_str(format!("\n{}: {}", name, dot_scalar(val)).as_str());
+        }
+    }
+    // '\l' left-justifies each line
+    label.push('\n');
+    out.push_str(format!("    n{} [label={}];\n", my_id, dot_str(label.as_str())).as_str());
+
+    for &(name, ref val) in &node.fields {
+        match *val {
+            Value::Node(ref child) => {
+                let child_id = dot_node(child, id, out);
+                out.push_str(
+                    format!("    n{} -> n{} [label={}];\n", my_id, child_id, dot_str(name)).as_str(),
+                );
+            }
+            Value::List(ref elems) if !val.is_scalar() => for (i, elem) in elems.iter().enumerate() {
+                if let Value::Node(ref child) = *elem {
+                    let child_id = dot_node(child, id, out);
+                    out.push_str(
+                        format!(
+                            "    n{} -> n{} [label={}];\n",
+                            my_id,
+                            child_id,
+                            dot_str(format!("{}[{}]", name, i).as_str())
+                        ).as_str(),
+                    );
+                }
+            },
+            _ => {}
+        }
+    }
+    my_id
+}
+
+fn dot_scalar(val: &Value) -> String {
+    match *val {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => format!("{}", b),
+        Value::Int(i) => format!("{}", i),
+        Value::Float(f) => format!("{:?}", f),
+        Value::Str(ref s) => c_str(s),
+        Value::List(ref elems) => format!(
+            "[{}]",
+            elems
+                .iter()
+                .map(dot_scalar)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Value::Node(_) => "".to_string(),
+    }
+}
+
+fn dot_str(s: &str) -> String {
+    let mut escaped = "\"".to_string();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\l"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::Options;
+    use session::Session;
+    use target::Target;
+
+    const SOURCE: &'static str = "int main() {\n\
+                                  \x20 char *s = \"a\\n\";\n\
+                                  \x20 if (s)\n\
+                                  \x20   return 1;\n\
+                                  \x20 return 0;\n\
+                                  }\n";
+
+    fn dump_source(format: Format) -> String {
+        let options = Options::with_target(Target::from_triple("x86_64-unknown-linux-gnu").unwrap());
+        let asts = Session::from_source("test.c", SOURCE, &options).parse().unwrap();
+        dump(&asts, "test.c", format)
+    }
+
+    #[test]
+    fn sexpr() {
+        let out = dump_source(Format::Sexpr);
+        assert!(out.starts_with("(translation-unit :file \"test.c\""));
+        assert!(out.contains("(func-def :pos 1:1 :name \"main\""));
+        assert!(out.contains(":body (block :pos 1:12"));
+        assert!(out.contains("(var-decl :pos 2:8 :name \"s\""));
+        assert!(out.contains("(string :pos 2:13 :value \"a\\n\")"));
+        assert!(out.contains("(if :pos 3:3"));
+        assert!(out.contains("(return :pos 5:3"));
+        // the empty else made by the parser has no position
+        assert!(out.contains(":else (block :stmts ())"));
+    }
+
+    #[test]
+    fn json() {
+        let out = dump_source(Format::Json);
+        assert!(out.contains("\"kind\": \"func-def\",\n      \"pos\": {\"line\": 1, \"column\": 1}"));
+        assert!(out.contains("\"kind\": \"if\",\n"));
+        assert!(out.contains("\"pos\": {\"line\": 3, \"column\": 3}"));
+        assert!(out.contains("\"value\": \"a\\n\""));
+    }
+
+    #[test]
+    fn dot() {
+        let out = dump_source(Format::Dot);
+        assert!(out.starts_with("digraph ast {\n"));
+        assert!(out.trim_right().ends_with('}'));
+        assert!(out.contains("[label=\"func-def\\l1:1\\lname: \\\"main\\\"\\l"));
+        assert!(out.contains("[label=\"if\\l3:3\\l\"];"));
+        assert!(out.contains(" -> n"));
+    }
+}
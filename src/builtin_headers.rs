@@ -0,0 +1,54 @@
+use file_provider::FileProvider;
+use std::io;
+use std::sync::Arc;
+
+// the directory where the headers embedded in the binary are found.
+// it doesn't exist on the file system.
+pub const BUILTIN_INCLUDE_DIR: &'static str = "<built-in>/include/";
+
+// the headers in include/ (rucc.h is read before every source file)
+const HEADERS: &'static [(&'static str, &'static str)] = &[
+    ("float.h", include_str!("../include/float.h")),
+    ("iso646.h", include_str!("../include/iso646.h")),
+    ("rucc.h", include_str!("../include/rucc.h")),
+    ("stdalign.h", include_str!("../include/stdalign.h")),
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+    ("stdnoreturn.h", include_str!("../include/stdnoreturn.h")),
+];
+
+fn find(filename: &str) -> Option<&'static str> {
+    if !filename.starts_with(BUILTIN_INCLUDE_DIR) {
+        return None;
+    }
+    let name = &filename[BUILTIN_INCLUDE_DIR.len()..];
+    HEADERS
+        .iter()
+        .find(|&&(header, _)| header == name)
+        .map(|&(_, body)| body)
+}
+
+// serves the built-in headers, and reads the other files from 'files'
+#[derive(Debug)]
+pub struct BuiltinHeaders {
+    files: Arc<FileProvider>,
+}
+
+impl BuiltinHeaders {
+    pub fn new(files: Arc<FileProvider>) -> BuiltinHeaders {
+        BuiltinHeaders { files: files }
+    }
+}
+
+impl FileProvider for BuiltinHeaders {
+    fn is_file(&self, filename: &str) -> bool {
+        find(filename).is_some() || self.files.is_file(filename)
+    }
+    fn read(&self, filename: &str) -> io::Result<String> {
+        match find(filename) {
+            Some(body) => Ok(body.to_string()),
+            None => self.files.read(filename),
+        }
+    }
+}
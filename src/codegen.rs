@@ -5,18 +5,24 @@ extern crate libc;
 extern crate rand;
 use self::rand::Rng;
 
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::ptr;
+use std::mem;
 use std::boxed::Box;
 use std::collections::{hash_map, HashMap, VecDeque};
+use std::sync::{Once, ONCE_INIT};
 
 use self::llvm::core::*;
 use self::llvm::prelude::*;
+use self::llvm::target::*;
+use self::llvm::target_machine::*;
+use self::llvm::transforms::pass_manager_builder::*;
 
 use node;
 use node::Bits;
 use lexer::Pos;
 use types::{RectypeName, Sign, StorageClass, Type};
+use target::{Arch, Target};
 
 macro_rules! matches {
     ($e:expr, $p:pat) => {
@@ -85,12 +91,182 @@ pub enum Error {
     Msg(String),
 }
 
+// the type of a value used as an operand. a void expression has no value
+fn value_ty(ty: Option<Type>) -> CodegenR<Type> {
+    match ty {
+        Some(ty) => Ok(ty),
+        None => Err(Error::Msg("void value not ignored as it ought to be".to_string())),
+    }
+}
+
 unsafe fn cur_bb_has_no_terminator(builder: LLVMBuilderRef) -> bool {
     LLVMIsATerminatorInst(LLVMGetLastInstruction(LLVMGetInsertBlock(builder))) == ptr::null_mut()
 }
 
 type CodegenR<T> = Result<T, Error>;
 
+// -O0, -O1, -O2, -O3, -Os
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    Os,
+}
+
+impl OptLevel {
+    // (optimization level, size level) for the pass manager builder
+    fn levels(&self) -> (u32, u32) {
+        match *self {
+            OptLevel::O0 => (0, 0),
+            OptLevel::O1 => (1, 0),
+            OptLevel::O2 => (2, 0),
+            OptLevel::O3 => (3, 0),
+            OptLevel::Os => (2, 1),
+        }
+    }
+    // the same thresholds as clang
+    fn inline_threshold(&self) -> Option<u32> {
+        match *self {
+            OptLevel::O0 | OptLevel::O1 => None,
+            OptLevel::O2 => Some(225),
+            OptLevel::O3 => Some(250),
+            OptLevel::Os => Some(75),
+        }
+    }
+    fn codegen_opt_level(&self) -> LLVMCodeGenOptLevel {
+        match *self {
+            OptLevel::O0 => LLVMCodeGenOptLevel::LLVMCodeGenLevelNone,
+            OptLevel::O1 => LLVMCodeGenOptLevel::LLVMCodeGenLevelLess,
+            OptLevel::O2 | OptLevel::Os => LLVMCodeGenOptLevel::LLVMCodeGenLevelDefault,
+            OptLevel::O3 => LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
+        }
+    }
+}
+
+const LINE_MD_KIND: &'static str = "rucc.line";
+
+// turn 'rucc.line' metadata attached to instructions into '; line N' comments
+fn add_line_comments(ir: &str) -> String {
+    let attachment = format!(", !{} !", LINE_MD_KIND);
+    let mut md_lines = HashMap::new();
+    let mut md_ids = Vec::new();
+    let mut lines = Vec::new();
+    for line in ir.lines() {
+        match line.find(attachment.as_str()) {
+            Some(start) => {
+                let id_start = start + attachment.len();
+                let id_end = line[id_start..]
+                    .find(|c: char| !c.is_digit(10))
+                    .map_or(line.len(), |end| id_start + end);
+                md_ids.push(Some(line[id_start..id_end].to_string()));
+                lines.push(format!("{}{}", &line[..start], &line[id_end..]));
+            }
+            None => {
+                md_ids.push(None);
+                lines.push(line.to_string());
+            }
+        }
+        // '!N = !{i32 LINE}'
+        if line.starts_with('!') && line.ends_with('}') {
+            if let Some(eq) = line.find(" = !{i32 ") {
+                let src_line = &line[eq + " = !{i32 ".len()..line.len() - 1];
+                if src_line.chars().all(|c| c.is_digit(10)) {
+                    md_lines.insert(line[1..eq].to_string(), src_line.to_string());
+                }
+            }
+        }
+    }
+
+    let mut output = "".to_string();
+    let mut last_src_line = None;
+    for (line, md_id) in lines.iter().zip(md_ids.iter()) {
+        // metadata only used by the line comments
+        if line.starts_with('!') {
+            let id = line[1..].split(' ').next().unwrap().to_string();
+            if md_lines.contains_key(&id) && md_ids.contains(&Some(id)) {
+                continue;
+            }
+        }
+        if line.starts_with("define ") {
+            last_src_line = None;
+        }
+        if let Some(src_line) = md_id.as_ref().and_then(|id| md_lines.get(id)) {
+            if last_src_line != Some(src_line) {
+                let indent = line.len() - line.trim_left().len();
+                output.push_str(format!("{}; line {}\n", &line[..indent], src_line).as_str());
+                last_src_line = Some(src_line);
+            }
+        }
+        output.push_str(line.as_str());
+        output.push('\n');
+    }
+    output
+}
+
+// llvm's target registry is global. sessions on several threads may create codegens at once,
+// so each backend is initialized only once
+static NATIVE_INIT: Once = ONCE_INIT;
+static X86_INIT: Once = ONCE_INIT;
+static AARCH64_INIT: Once = ONCE_INIT;
+static RISCV_INIT: Once = ONCE_INIT;
+
+unsafe fn initialize_native_backend() {
+    NATIVE_INIT.call_once(|| {
+        llvm::execution_engine::LLVMLinkInMCJIT();
+        llvm::target::LLVM_InitializeAllTargetMCs();
+        llvm::target::LLVM_InitializeNativeTarget();
+        llvm::target::LLVM_InitializeNativeAsmPrinter();
+        llvm::target::LLVM_InitializeNativeAsmParser();
+    });
+}
+
+// the native backend and the one for the target are initialized in Codegen::new
+unsafe fn initialize_backend(arch: Arch) {
+    let once = match arch {
+        Arch::X86 | Arch::X86_64 => &X86_INIT,
+        Arch::AArch64 => &AARCH64_INIT,
+        Arch::RiscV64 => &RISCV_INIT,
+    };
+    once.call_once(|| match arch {
+        Arch::X86 | Arch::X86_64 => {
+            LLVMInitializeX86TargetInfo();
+            LLVMInitializeX86Target();
+            LLVMInitializeX86TargetMC();
+            LLVMInitializeX86AsmPrinter();
+        }
+        Arch::AArch64 => {
+            LLVMInitializeAArch64TargetInfo();
+            LLVMInitializeAArch64Target();
+            LLVMInitializeAArch64TargetMC();
+            LLVMInitializeAArch64AsmPrinter();
+        }
+        Arch::RiscV64 => {
+            LLVMInitializeRISCVTargetInfo();
+            LLVMInitializeRISCVTarget();
+            LLVMInitializeRISCVTargetMC();
+            LLVMInitializeRISCVAsmPrinter();
+        }
+    });
+}
+
+unsafe fn take_memory_buffer(buf: LLVMMemoryBufferRef) -> Vec<u8> {
+    let start = LLVMGetBufferStart(buf) as *const u8;
+    let data = ::std::slice::from_raw_parts(start, LLVMGetBufferSize(buf)).to_vec();
+    LLVMDisposeMemoryBuffer(buf);
+    data
+}
+
+unsafe fn take_llvm_message(msg: *mut libc::c_char) -> String {
+    if msg == ptr::null_mut() {
+        return "unknown llvm error".to_string();
+    }
+    let s = CStr::from_ptr(msg).to_string_lossy().into_owned();
+    LLVMDisposeMessage(msg);
+    s
+}
+
 pub struct Codegen {
     context: LLVMContextRef,
     module: LLVMModuleRef,
@@ -103,15 +279,21 @@ pub struct Codegen {
     break_labels: VecDeque<LLVMBasicBlockRef>,
     continue_labels: VecDeque<LLVMBasicBlockRef>,
     cur_func: Option<LLVMValueRef>,
+    opt_level: OptLevel,
+    // instructions are tagged with 'rucc.line' metadata to comment the llvm ir with source lines
+    line_comments: bool,
+    // the lines of the nodes being generated (the innermost last), and the block and its last
+    // instruction when the instructions were tagged last. see Codegen::tag_new_insts
+    line_stack: Vec<usize>,
+    tagged_until: Option<(LLVMBasicBlockRef, LLVMValueRef)>,
+    target: Target,
 }
 
 impl Codegen {
-    pub unsafe fn new(mod_name: &str) -> Codegen {
-        llvm::execution_engine::LLVMLinkInInterpreter();
-        llvm::target::LLVM_InitializeAllTargetMCs();
-        llvm::target::LLVM_InitializeNativeTarget();
-        llvm::target::LLVM_InitializeNativeAsmPrinter();
-        llvm::target::LLVM_InitializeNativeAsmParser();
+    // init_target() must be called before generating code for 'target'
+    pub unsafe fn new(mod_name: &str, target: Target) -> Codegen {
+        initialize_native_backend();
+        initialize_backend(target.arch);
 
         let context = LLVMContextCreate();
 
@@ -119,14 +301,6 @@ impl Codegen {
         let module = LLVMModuleCreateWithNameInContext(c_mod_name.as_ptr(), context);
         let mut global_varmap = HashMap::new();
 
-        let mut ee = 0 as llvm::execution_engine::LLVMExecutionEngineRef;
-        let mut error = 0 as *mut i8;
-        if llvm::execution_engine::LLVMCreateExecutionEngineForModule(&mut ee, module, &mut error)
-            != 0
-        {
-            println!("err");
-        }
-
         let llvm_memcpy_ty = Type::Func(
             Box::new(Type::Void),
             vec![
@@ -139,13 +313,13 @@ impl Codegen {
             false,
         );
         let llvm_memcpy_llvm_ty = LLVMFunctionType(
-            LLVMVoidType(),
+            LLVMVoidTypeInContext(context),
             vec![
-                LLVMPointerType(LLVMInt8Type(), 0),
-                LLVMPointerType(LLVMInt8Type(), 0),
-                LLVMInt32Type(),
-                LLVMInt32Type(),
-                LLVMInt1Type(),
+                LLVMPointerType(LLVMInt8TypeInContext(context), 0),
+                LLVMPointerType(LLVMInt8TypeInContext(context), 0),
+                LLVMInt32TypeInContext(context),
+                LLVMInt32TypeInContext(context),
+                LLVMInt1TypeInContext(context),
             ].as_mut_slice()
                 .as_mut_ptr(),
             5,
@@ -173,13 +347,13 @@ impl Codegen {
             false,
         );
         let llvm_memset_llvm_ty = LLVMFunctionType(
-            LLVMVoidType(),
+            LLVMVoidTypeInContext(context),
             vec![
-                LLVMPointerType(LLVMInt8Type(), 0),
-                LLVMInt8Type(),
-                LLVMInt32Type(),
-                LLVMInt32Type(),
-                LLVMInt1Type(),
+                LLVMPointerType(LLVMInt8TypeInContext(context), 0),
+                LLVMInt8TypeInContext(context),
+                LLVMInt32TypeInContext(context),
+                LLVMInt32TypeInContext(context),
+                LLVMInt1TypeInContext(context),
             ].as_mut_slice()
                 .as_mut_ptr(),
             5,
@@ -195,6 +369,26 @@ impl Codegen {
             VarInfo::new(llvm_memset_ty, llvm_memset_llvm_ty, llvm_memset),
         );
 
+        // for __builtin_va_start, __builtin_va_end and __builtin_va_copy
+        let va_intrinsics = [("llvm.va_start", 1), ("llvm.va_end", 1), ("llvm.va_copy", 2)];
+        for &(name, params_count) in &va_intrinsics {
+            let ty = Type::Func(
+                Box::new(Type::Void),
+                vec![Type::Ptr(Box::new(Type::Char(Sign::Signed))); params_count],
+                false,
+            );
+            let llvm_ty = LLVMFunctionType(
+                LLVMVoidTypeInContext(context),
+                vec![LLVMPointerType(LLVMInt8TypeInContext(context), 0); params_count]
+                    .as_mut_slice()
+                    .as_mut_ptr(),
+                params_count as u32,
+                0,
+            );
+            let func = LLVMAddFunction(module, CString::new(name).unwrap().as_ptr(), llvm_ty);
+            global_varmap.insert(name.to_string(), VarInfo::new(ty, llvm_ty, func));
+        }
+
         Codegen {
             context: context,
             module: module,
@@ -207,6 +401,11 @@ impl Codegen {
             continue_labels: VecDeque::new(),
             break_labels: VecDeque::new(),
             cur_func: None,
+            opt_level: OptLevel::O0,
+            line_comments: false,
+            line_stack: Vec::new(),
+            tagged_until: None,
+            target: target,
         }
     }
 
@@ -222,6 +421,190 @@ impl Codegen {
         Ok(())
     }
 
+    // set the target triple and the data layout of the module
+    pub unsafe fn init_target(&mut self) -> CodegenR<()> {
+        self.set_target_data_layout()
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+    }
+
+    // must be called before generating code
+    pub fn enable_line_comments(&mut self) {
+        self.line_comments = true;
+    }
+
+    // run the optimization passes for 'opt_level' on the whole module.
+    // must be called after all the toplevels are generated.
+    pub unsafe fn optimize(&mut self) -> CodegenR<()> {
+        if self.opt_level == OptLevel::O0 {
+            return Ok(());
+        }
+
+        let (opt_level, size_level) = self.opt_level.levels();
+        let builder = LLVMPassManagerBuilderCreate();
+        LLVMPassManagerBuilderSetOptLevel(builder, opt_level);
+        LLVMPassManagerBuilderSetSizeLevel(builder, size_level);
+        if let Some(threshold) = self.opt_level.inline_threshold() {
+            LLVMPassManagerBuilderUseInlinerWithThreshold(builder, threshold);
+        }
+
+        let func_passes = LLVMCreateFunctionPassManagerForModule(self.module);
+        LLVMPassManagerBuilderPopulateFunctionPassManager(builder, func_passes);
+        let module_passes = LLVMCreatePassManager();
+        LLVMPassManagerBuilderPopulateModulePassManager(builder, module_passes);
+        LLVMPassManagerBuilderDispose(builder);
+
+        LLVMInitializeFunctionPassManager(func_passes);
+        let mut func = LLVMGetFirstFunction(self.module);
+        while func != ptr::null_mut() {
+            LLVMRunFunctionPassManager(func_passes, func);
+            func = LLVMGetNextFunction(func);
+        }
+        LLVMFinalizeFunctionPassManager(func_passes);
+        LLVMDisposePassManager(func_passes);
+
+        LLVMRunPassManager(module_passes, self.module);
+        LLVMDisposePassManager(module_passes);
+        Ok(())
+    }
+
+    unsafe fn set_target_data_layout(&mut self) -> CodegenR<()> {
+        let machine = try!(self.create_target_machine());
+        let data_layout = LLVMCreateTargetDataLayout(machine);
+        LLVMSetModuleDataLayout(self.module, data_layout);
+        let checked = self.check_type_sizes(data_layout);
+        LLVMDisposeTargetData(data_layout);
+        LLVMDisposeTargetMachine(machine);
+        checked
+    }
+
+    // sizeof and the struct layout are made from the target description, while llvm lays out
+    // memory by the data layout. they must agree on the size and alignment of every scalar type
+    unsafe fn check_type_sizes(&mut self, data_layout: LLVMTargetDataRef) -> CodegenR<()> {
+        let types = [
+            ("short", Type::Short(Sign::Signed)),
+            ("int", Type::Int(Sign::Signed)),
+            ("long", Type::Long(Sign::Signed)),
+            ("long long", Type::LLong(Sign::Signed)),
+            ("float", Type::Float),
+            ("double", Type::Double),
+            ("pointers", Type::Ptr(Box::new(Type::Void))),
+        ];
+        for &(name, ref ty) in &types {
+            let llvm_ty = self.type_to_llvmty(ty);
+            let llvm_size = LLVMABISizeOfType(data_layout, llvm_ty) as usize;
+            let llvm_align = LLVMABIAlignmentOfType(data_layout, llvm_ty) as usize;
+            let (size, align) = (ty.calc_size(&self.target), ty.calc_align(&self.target));
+            if (size, align) != (llvm_size, llvm_align) {
+                return Err(Error::Msg(format!(
+                    "the size and alignment of {} are {} and {} on '{}' but llvm says {} and {}",
+                    name, size, align, self.target.triple, llvm_size, llvm_align
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // --run: jit-compile the module and call 'main' with 'args' (args[0] is the program name).
+    // 'libraries' are the paths of the shared objects to load first.
+    // returns the value returned from 'main'.
+    pub unsafe fn run_main(&mut self, args: &Vec<String>, libraries: &Vec<String>) -> CodegenR<i32> {
+        use self::llvm::execution_engine::*;
+        use self::llvm::support::LLVMLoadLibraryPermanently;
+
+        if !self.target.is_native() {
+            return Err(Error::Msg(format!(
+                "cannot run code for '{}' on this machine",
+                self.target.triple
+            )));
+        }
+
+        // make the symbols of the host process (libc and so on) visible from the jit code
+        LLVMLoadLibraryPermanently(ptr::null());
+        for lib in libraries {
+            let c_lib = CString::new(lib.as_str()).unwrap();
+            if LLVMLoadLibraryPermanently(c_lib.as_ptr()) != 0 {
+                return Err(Error::Msg(format!("cannot load library '{}'", lib)));
+            }
+        }
+
+        let c_main = CString::new("main").unwrap();
+        let main = LLVMGetNamedFunction(self.module, c_main.as_ptr());
+        if main == ptr::null_mut() || LLVMCountBasicBlocks(main) == 0 {
+            return Err(Error::Msg("undefined reference to 'main'".to_string()));
+        }
+
+        try!(self.set_target_data_layout());
+
+        let mut options: LLVMMCJITCompilerOptions = mem::zeroed();
+        LLVMInitializeMCJITCompilerOptions(&mut options, mem::size_of_val(&options));
+        options.OptLevel = self.opt_level.levels().0;
+        let mut ee = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMCreateMCJITCompilerForModule(
+            &mut ee,
+            self.module,
+            &mut options,
+            mem::size_of_val(&options),
+            &mut error,
+        ) != 0
+        {
+            return Err(Error::Msg(take_llvm_message(error)));
+        }
+
+        let c_args: Vec<CString> = args.iter()
+            .map(|arg| CString::new(arg.as_str()).unwrap())
+            .collect();
+        let mut argv: Vec<*const libc::c_char> = c_args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(ptr::null());
+        let c_envs: Vec<CString> = ::std::env::vars()
+            .map(|(key, val)| CString::new(format!("{}={}", key, val)).unwrap())
+            .collect();
+        let mut envp: Vec<*const libc::c_char> = c_envs.iter().map(|env| env.as_ptr()).collect();
+        envp.push(ptr::null());
+
+        let ret = LLVMRunFunctionAsMain(
+            ee,
+            main,
+            args.len() as libc::c_uint,
+            argv.as_ptr(),
+            envp.as_ptr(),
+        );
+
+        // the execution engine owns the module. take it back before disposing the engine
+        let mut module = ptr::null_mut();
+        LLVMRemoveModule(ee, self.module, &mut module, &mut error);
+        LLVMDisposeExecutionEngine(ee);
+        Ok(ret as i32)
+    }
+
+    // textual llvm ir. with line comments enabled, each group of instructions
+    // generated from the same source line is preceded by '; line N'
+    pub unsafe fn llvm_ir(&mut self) -> String {
+        let ir_ptr = LLVMPrintModuleToString(self.module);
+        let ir = CStr::from_ptr(ir_ptr).to_string_lossy().into_owned();
+        LLVMDisposeMessage(ir_ptr);
+        if self.line_comments {
+            add_line_comments(ir.as_str())
+        } else {
+            ir
+        }
+    }
+
+    pub unsafe fn llvm_bitcode(&mut self) -> Vec<u8> {
+        take_memory_buffer(llvm::bit_writer::LLVMWriteBitcodeToMemoryBuffer(self.module))
+    }
+
+    pub unsafe fn object_code(&mut self) -> CodegenR<Vec<u8>> {
+        self.emit_to_memory(LLVMCodeGenFileType::LLVMObjectFile)
+    }
+
+    pub unsafe fn assembly(&mut self) -> CodegenR<Vec<u8>> {
+        self.emit_to_memory(LLVMCodeGenFileType::LLVMAssemblyFile)
+    }
+
     pub unsafe fn write_llvm_bitcode_to_file(&mut self, filename: &str) {
         llvm::bit_writer::LLVMWriteBitcodeToFile(
             self.module,
@@ -229,17 +612,82 @@ impl Codegen {
         );
     }
 
+    unsafe fn emit_to_memory(&mut self, file_ty: LLVMCodeGenFileType) -> CodegenR<Vec<u8>> {
+        let machine = try!(self.create_target_machine());
+
+        let data_layout = LLVMCreateTargetDataLayout(machine);
+        LLVMSetModuleDataLayout(self.module, data_layout);
+        LLVMDisposeTargetData(data_layout);
+
+        let mut buf = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        let failed = LLVMTargetMachineEmitToMemoryBuffer(
+            machine,
+            self.module,
+            file_ty,
+            &mut error,
+            &mut buf,
+        ) != 0;
+        LLVMDisposeTargetMachine(machine);
+
+        if failed {
+            return Err(Error::Msg(take_llvm_message(error)));
+        }
+        Ok(take_memory_buffer(buf))
+    }
+
+    unsafe fn create_target_machine(&mut self) -> CodegenR<LLVMTargetMachineRef> {
+        // e.g. x86_64-linux-gnux32 -> x86_64-unknown-linux-gnux32
+        let triple = CString::new(self.target.triple.as_str()).unwrap();
+        let triple = CString::new(take_llvm_message(LLVMNormalizeTargetTriple(triple.as_ptr())))
+            .unwrap();
+        LLVMSetTarget(self.module, triple.as_ptr());
+
+        let mut target = ptr::null_mut();
+        let mut error = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut target, &mut error) != 0 {
+            return Err(Error::Msg(take_llvm_message(error)));
+        }
+
+        // tune for the host cpu unless a cpu or features are specified
+        let (cpu, features) = if self.target.is_native() && self.target.cpu.is_none()
+            && self.target.features.is_empty()
+        {
+            (
+                take_llvm_message(LLVMGetHostCPUName()),
+                take_llvm_message(LLVMGetHostCPUFeatures()),
+            )
+        } else {
+            (
+                self.target.cpu.clone().unwrap_or("".to_string()),
+                self.target.features.join(","),
+            )
+        };
+        let cpu = CString::new(cpu).unwrap();
+        let features = CString::new(features).unwrap();
+        let machine = LLVMCreateTargetMachine(
+            target,
+            triple.as_ptr(),
+            cpu.as_ptr(),
+            features.as_ptr(),
+            self.opt_level.codegen_opt_level(),
+            // executables are position independent by default on most systems
+            LLVMRelocMode::LLVMRelocPIC,
+            LLVMCodeModel::LLVMCodeModelDefault,
+        );
+        Ok(machine)
+    }
+
     unsafe fn gen_toplevel(&mut self, ast: &node::AST) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let result = match ast.kind {
-            node::ASTKind::FuncDef(ref functy, ref param_names, ref name, ref body) => {
-                self.gen_func_def(functy, param_names, name, body)
+            node::ASTKind::FuncDef(ref functy, ref param_names, ref name, ref sclass, ref body) => {
+                self.gen_func_def(functy, param_names, name, sclass, body)
             }
             node::ASTKind::VariableDecl(ref ty, ref name, ref sclass, ref init) => {
                 self.gen_global_var_decl(ty, name, sclass, init)
             }
-            _ => panic!(format!(
-                "given ast which shouldn't be contained in toplevels: {:?}",
-                ast
+            _ => Err(Error::Msg(
+                "expected a function definition or a declaration".to_string(),
             )),
         };
 
@@ -250,6 +698,7 @@ impl Codegen {
     }
 
     unsafe fn gen(&mut self, ast: &node::AST) -> CodegenR<(LLVMValueRef, Option<Type>)> {
+        let has_line = self.enter_line(ast);
         let result = match ast.kind {
             node::ASTKind::VariableDecl(ref ty, ref name, ref sclass, ref init) => {
                 self.gen_local_var_decl(ty, name, sclass, init)
@@ -292,13 +741,96 @@ impl Codegen {
             node::ASTKind::Float(ref f) => self.make_double(*f),
             node::ASTKind::Char(ref c) => self.make_char(*c),
             node::ASTKind::String(ref s) => self.make_const_str(s),
-            _ => panic!(format!("codegen: unknown ast (given {:?})", ast)),
+            _ => Err(Error::Msg(
+                "this expression or statement is not supported".to_string(),
+            )),
         };
+        if has_line {
+            self.leave_line();
+        }
         result.or_else(|cr: Error| match cr {
             Error::Msg(msg) => Err(Error::MsgWithPos(msg, ast.pos.clone())),
             Error::MsgWithPos(msg, pos) => Err(Error::MsgWithPos(msg, pos)),
         })
     }
+    // the llvm type and fields of the struct or union 'ty'
+    fn get_rectype(&self, ty: &Type) -> CodegenR<RectypeInfo> {
+        let name = match ty.get_name() {
+            Some(name) => name,
+            None => return Err(Error::Msg("expected a struct or union type".to_string())),
+        };
+        match self.llvm_struct_map.get(name.as_str()) {
+            Some(rectype) => Ok(rectype.clone()),
+            None => Err(Error::Msg(format!("incomplete type '{}'", name))),
+        }
+    }
+    // the function being generated. there's none while evaluating a global initializer
+    fn get_cur_func(&self) -> CodegenR<LLVMValueRef> {
+        match self.cur_func {
+            Some(func) => Ok(func),
+            None => Err(Error::Msg("initializer element is not constant".to_string())),
+        }
+    }
+    // the instructions generated from here belong to the line of 'ast' until its inner nodes
+    // or leave_line(). returns false if 'ast' has no line or no instruction is generated for it
+    unsafe fn enter_line(&mut self, ast: &node::AST) -> bool {
+        if !self.line_comments || ast.pos.line == 0 || self.cur_func.is_none() {
+            return false;
+        }
+        self.tag_new_insts();
+        self.line_stack.push(ast.pos.line);
+        true
+    }
+    unsafe fn leave_line(&mut self) {
+        self.tag_new_insts();
+        self.line_stack.pop();
+    }
+
+    // tag the instructions built since the last call with the line of the innermost node.
+    // the builder appends them to the block that was current then, and to the current block
+    // if it has moved to another one since. so each instruction is visited about once.
+    unsafe fn tag_new_insts(&mut self) {
+        let bb = LLVMGetInsertBlock(self.builder);
+        if let Some(&line) = self.line_stack.last() {
+            let kind = LLVMGetMDKindIDInContext(
+                self.context,
+                LINE_MD_KIND.as_ptr() as *const _,
+                LINE_MD_KIND.len() as u32,
+            );
+            let i32_ty = LLVMInt32TypeInContext(self.context);
+            let mut line_val = [LLVMConstInt(i32_ty, line as u64, 0)];
+            let md = LLVMMDNodeInContext(self.context, line_val.as_mut_ptr(), 1);
+
+            let mut last_bb = ptr::null_mut();
+            if let Some((tagged_bb, tagged_inst)) = self.tagged_until {
+                last_bb = tagged_bb;
+                let mut inst = if tagged_inst == ptr::null_mut() {
+                    LLVMGetFirstInstruction(tagged_bb)
+                } else {
+                    LLVMGetNextInstruction(tagged_inst)
+                };
+                while inst != ptr::null_mut() {
+                    if LLVMGetMetadata(inst, kind) == ptr::null_mut() {
+                        LLVMSetMetadata(inst, kind, md);
+                    }
+                    inst = LLVMGetNextInstruction(inst);
+                }
+            }
+            if bb != ptr::null_mut() && bb != last_bb {
+                let mut inst = LLVMGetLastInstruction(bb);
+                while inst != ptr::null_mut() && LLVMGetMetadata(inst, kind) == ptr::null_mut() {
+                    LLVMSetMetadata(inst, kind, md);
+                    inst = LLVMGetPreviousInstruction(inst);
+                }
+            }
+        }
+        self.tagged_until = if bb == ptr::null_mut() {
+            None
+        } else {
+            Some((bb, LLVMGetLastInstruction(bb)))
+        };
+    }
+
     unsafe fn gen_init_global(
         &mut self,
         ast: &node::AST,
@@ -344,6 +876,7 @@ impl Codegen {
         functy: &Type,
         param_names: &Vec<String>,
         name: &String,
+        sclass: &StorageClass,
         body: &Box<node::AST>,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let func_ty = self.type_to_llvmty(functy);
@@ -351,7 +884,7 @@ impl Codegen {
             &Type::Func(ref ret_ty, ref args_types, ref is_vararg) => {
                 (ret_ty, args_types, is_vararg)
             }
-            _ => panic!("never reach"),
+            _ => return Err(Error::Msg(format!("'{}' is not a function", name))),
         };
         let func = match self.global_varmap.entry(name.to_string()) {
             hash_map::Entry::Occupied(o) => o.into_mut().llvm_val,
@@ -365,11 +898,15 @@ impl Codegen {
                 func
             }
         };
+        // static functions must not be visible from other translation units
+        if *sclass == StorageClass::Static {
+            LLVMSetLinkage(func, llvm::LLVMLinkage::LLVMInternalLinkage);
+        }
 
         self.cur_func = Some(func);
         self.local_varmap.push(HashMap::new());
 
-        let bb_entry = LLVMAppendBasicBlock(func, CString::new("entry").unwrap().as_ptr());
+        let bb_entry = LLVMAppendBasicBlockInContext(self.context, func, CString::new("entry").unwrap().as_ptr());
         LLVMPositionBuilderAtEnd(self.builder, bb_entry);
 
         for (i, (arg_ty, arg_name)) in func_args_types.iter().zip(param_names.iter()).enumerate() {
@@ -393,6 +930,7 @@ impl Codegen {
                         LLVMConstNull(self.type_to_llvmty(func_ret_ty)),
                     ),
                 };
+                LLVMDisposeBuilder(terminator_builder);
             }
             iter_bb = LLVMGetNextBasicBlock(iter_bb);
         }
@@ -411,8 +949,7 @@ impl Codegen {
         sclass: &StorageClass,
         init: &Option<Box<node::AST>>,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let (gvar, llvm_gvar_ty) = if self.global_varmap.contains_key(name) {
-            let ref v = self.global_varmap.get(name).unwrap();
+        let (gvar, llvm_gvar_ty) = if let Some(v) = self.global_varmap.get(name) {
             (v.llvm_val, v.llvm_ty)
         } else {
             match *ty {
@@ -445,20 +982,24 @@ impl Codegen {
             VarInfo::new(ty.clone(), llvm_gvar_ty, gvar),
         );
 
-        if init.is_some() {
-            self.const_init_global_var(ty, gvar, &*init.clone().unwrap())
+        if let Some(ref init) = *init {
+            self.const_init_global_var(ty, gvar, &*init)
         } else {
             // default initialization
 
             if let Type::Func(_, _, _) = *ty {
                 // function must not be initialized
+                if *sclass == StorageClass::Static {
+                    LLVMSetLinkage(gvar, llvm::LLVMLinkage::LLVMInternalLinkage);
+                }
                 return Ok((gvar, Some(ty.clone())));
             }
 
             LLVMSetLinkage(
                 gvar,
                 match *sclass {
-                    StorageClass::Typedef => panic!(),
+                    // typedefs don't reach codegen
+                    StorageClass::Typedef => return Ok((gvar, Some(ty.clone()))),
                     StorageClass::Extern => llvm::LLVMLinkage::LLVMExternalLinkage,
                     StorageClass::Static => llvm::LLVMLinkage::LLVMInternalLinkage, // TODO: think handling of static
                     StorageClass::Register => llvm::LLVMLinkage::LLVMCommonLinkage,
@@ -498,7 +1039,11 @@ impl Codegen {
         elems_ast: &Vec<node::AST>,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let mut elems = Vec::new();
-        let (elem_val, elem_ty) = try!(self.gen(&elems_ast[0]));
+        let first = match elems_ast.first() {
+            Some(first) => first,
+            None => return Err(Error::Msg("empty initializer list".to_string())),
+        };
+        let (elem_val, elem_ty) = try!(self.gen(first));
         elems.push(elem_val);
         let llvm_elem_ty = LLVMTypeOf(elems[0]);
         for e in elems_ast[1..].iter() {
@@ -512,7 +1057,7 @@ impl Codegen {
                 elems.as_mut_slice().as_mut_ptr(),
                 elems_len as u32,
             ),
-            Some(Type::Array(Box::new(elem_ty.unwrap()), elems_len as i32)),
+            Some(Type::Array(Box::new(try!(value_ty(elem_ty))), elems_len as i32)),
         ))
     }
     unsafe fn gen_const_array_for_global_init(
@@ -523,7 +1068,7 @@ impl Codegen {
         let (elem_ty, len) = if let &Type::Array(ref elem_ty, len) = ty {
             (&**elem_ty, len)
         } else {
-            panic!("never reach");
+            return Err(Error::Msg("array initializer for a non-array type".to_string()));
         };
 
         let llvm_elem_ty = self.type_to_llvmty(elem_ty);
@@ -545,8 +1090,7 @@ impl Codegen {
         elems_ast: &Vec<node::AST>,
         ty: &Type,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let struct_name = ty.get_name().unwrap();
-        let rectype = (*self.llvm_struct_map.get(struct_name.as_str()).unwrap()).clone();
+        let rectype = try!(self.get_rectype(ty));
 
         let mut elems = Vec::new();
         for ((elem_ast, field_ty), field_llvm_ty) in elems_ast
@@ -571,19 +1115,17 @@ impl Codegen {
         var: LLVMValueRef,
         ty: &Type,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let llvm_memset = self.global_varmap
-            .get("llvm.memset.p0i8.i32")
-            .unwrap()
-            .clone();
+        let llvm_memset = self.global_varmap["llvm.memset.p0i8.i32"].clone();
+        let size = ty.calc_size(&self.target);
         LLVMBuildCall(
             self.builder,
             llvm_memset.llvm_val,
             vec![
-                self.typecast(var, LLVMPointerType(LLVMInt8Type(), 0)),
+                self.typecast(var, LLVMPointerType(LLVMInt8TypeInContext(self.context), 0)),
                 try!(self.make_int(0, &Bits::Bits8, false)).0,
-                LLVMConstInt(LLVMInt32Type(), ty.calc_size() as u64, 0),
-                LLVMConstInt(LLVMInt32Type(), 4, 0),
-                LLVMConstInt(LLVMInt1Type(), 0, 0),
+                LLVMConstInt(LLVMInt32TypeInContext(self.context), size as u64, 0),
+                LLVMConstInt(LLVMInt32TypeInContext(self.context), 4, 0),
+                LLVMConstInt(LLVMInt1TypeInContext(self.context), 0, 0),
             ].as_mut_slice()
                 .as_mut_ptr(),
             5,
@@ -597,7 +1139,10 @@ impl Codegen {
         elems_ast: &Vec<node::AST>,
         ty: &Type,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let elem_ty = ty.get_elem_ty().unwrap();
+        let elem_ty = match ty.get_elem_ty() {
+            Some(elem_ty) => elem_ty,
+            None => return Err(Error::Msg("array initializer for a non-array type".to_string())),
+        };
 
         try!(self.fill_with_0(var, ty));
 
@@ -633,8 +1178,7 @@ impl Codegen {
         elems_ast: &Vec<node::AST>,
         ty: &Type,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let struct_name = ty.get_name().unwrap();
-        let rectype = (*self.llvm_struct_map.get(struct_name.as_str()).unwrap()).clone();
+        let rectype = try!(self.get_rectype(ty));
 
         try!(self.fill_with_0(var, ty));
 
@@ -677,7 +1221,7 @@ impl Codegen {
         }
 
         // Allocate a varaible, always at the first of the entry block
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
         let builder = LLVMCreateBuilderInContext(self.context);
         let entry_bb = LLVMGetEntryBasicBlock(func);
         let first_inst = LLVMGetFirstInstruction(entry_bb);
@@ -692,13 +1236,14 @@ impl Codegen {
             llvm_var_ty,
             CString::new(name.as_str()).unwrap().as_ptr(),
         );
+        LLVMDisposeBuilder(builder);
         self.local_varmap.last_mut().unwrap().insert(
             name.as_str().to_string(),
             VarInfo::new(ty.clone(), llvm_var_ty, var),
         );
 
-        if init.is_some() {
-            try!(self.set_local_var_initializer(var, ty, &*init.clone().unwrap(),));
+        if let Some(ref init) = *init {
+            try!(self.set_local_var_initializer(var, ty, &*init));
         }
         Ok((var, Some(ty.clone())))
     }
@@ -783,11 +1328,11 @@ impl Codegen {
         let cond_val_tmp = try!(self.gen(cond)).0;
         let cond_val = self.val_to_bool(cond_val_tmp);
 
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
-        let bb_then = LLVMAppendBasicBlock(func, CString::new("then").unwrap().as_ptr());
-        let bb_else = LLVMAppendBasicBlock(func, CString::new("else").unwrap().as_ptr());
-        let bb_merge = LLVMAppendBasicBlock(func, CString::new("merge").unwrap().as_ptr());
+        let bb_then = LLVMAppendBasicBlockInContext(self.context, func, CString::new("then").unwrap().as_ptr());
+        let bb_else = LLVMAppendBasicBlockInContext(self.context, func, CString::new("else").unwrap().as_ptr());
+        let bb_merge = LLVMAppendBasicBlockInContext(self.context, func, CString::new("merge").unwrap().as_ptr());
 
         LLVMBuildCondBr(self.builder, cond_val, bb_then, bb_else);
 
@@ -815,13 +1360,13 @@ impl Codegen {
         cond: &node::AST,
         body: &node::AST,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
         let bb_before_loop =
-            LLVMAppendBasicBlock(func, CString::new("before_loop").unwrap().as_ptr());
-        let bb_loop = LLVMAppendBasicBlock(func, CString::new("loop").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("before_loop").unwrap().as_ptr());
+        let bb_loop = LLVMAppendBasicBlockInContext(self.context, func, CString::new("loop").unwrap().as_ptr());
         let bb_after_loop =
-            LLVMAppendBasicBlock(func, CString::new("after_loop").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("after_loop").unwrap().as_ptr());
         self.continue_labels.push_back(bb_loop);
         self.break_labels.push_back(bb_after_loop);
 
@@ -851,13 +1396,13 @@ impl Codegen {
         cond: &node::AST,
         body: &node::AST,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
         let bb_before_loop =
-            LLVMAppendBasicBlock(func, CString::new("before_loop").unwrap().as_ptr());
-        let bb_loop = LLVMAppendBasicBlock(func, CString::new("loop").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("before_loop").unwrap().as_ptr());
+        let bb_loop = LLVMAppendBasicBlockInContext(self.context, func, CString::new("loop").unwrap().as_ptr());
         let bb_after_loop =
-            LLVMAppendBasicBlock(func, CString::new("after_loop").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("after_loop").unwrap().as_ptr());
         self.continue_labels.push_back(bb_loop);
         self.break_labels.push_back(bb_after_loop);
 
@@ -887,11 +1432,11 @@ impl Codegen {
         cond: &node::AST,
         body: &node::AST,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
         let cond_val = try!(self.gen(cond)).0;
         let bb_after_switch =
-            LLVMAppendBasicBlock(func, CString::new("after_switch").unwrap().as_ptr());
-        let default = LLVMAppendBasicBlock(func, CString::new("default").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("after_switch").unwrap().as_ptr());
+        let default = LLVMAppendBasicBlockInContext(self.context, func, CString::new("default").unwrap().as_ptr());
         let switch = LLVMBuildSwitch(self.builder, cond_val, default, 10);
         self.break_labels.push_back(bb_after_switch);
         self.switch_list
@@ -922,10 +1467,13 @@ impl Codegen {
         Ok((ptr::null_mut(), None))
     }
     unsafe fn gen_case(&mut self, expr: &node::AST) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
         let expr_val = try!(self.gen(expr)).0;
-        let (switch, _, ty) = *self.switch_list.back().unwrap();
-        let label = LLVMAppendBasicBlock(func, CString::new("label").unwrap().as_ptr());
+        let (switch, _, ty) = match self.switch_list.back() {
+            Some(switch) => *switch,
+            None => return Err(Error::Msg("'case' statement not in switch statement".to_string())),
+        };
+        let label = LLVMAppendBasicBlockInContext(self.context, func, CString::new("label").unwrap().as_ptr());
 
         // if the above case doesn't have 'break'
         // switch(X) {
@@ -940,7 +1488,14 @@ impl Codegen {
         Ok((ptr::null_mut(), None))
     }
     unsafe fn gen_default(&mut self) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let default = self.switch_list.back_mut().unwrap();
+        let default = match self.switch_list.back_mut() {
+            Some(switch) => switch,
+            None => {
+                return Err(Error::Msg(
+                    "'default' statement not in switch statement".to_string(),
+                ))
+            }
+        };
 
         // if the above case doesn't have 'break'
         // switch(X) {
@@ -964,14 +1519,14 @@ impl Codegen {
         step: &node::AST,
         body: &node::AST,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
         let bb_before_loop =
-            LLVMAppendBasicBlock(func, CString::new("before_loop").unwrap().as_ptr());
-        let bb_loop = LLVMAppendBasicBlock(func, CString::new("loop").unwrap().as_ptr());
-        let bb_step = LLVMAppendBasicBlock(func, CString::new("step").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("before_loop").unwrap().as_ptr());
+        let bb_loop = LLVMAppendBasicBlockInContext(self.context, func, CString::new("loop").unwrap().as_ptr());
+        let bb_step = LLVMAppendBasicBlockInContext(self.context, func, CString::new("step").unwrap().as_ptr());
         let bb_after_loop =
-            LLVMAppendBasicBlock(func, CString::new("after_loop").unwrap().as_ptr());
+            LLVMAppendBasicBlockInContext(self.context, func, CString::new("after_loop").unwrap().as_ptr());
         self.continue_labels.push_back(bb_step);
         self.break_labels.push_back(bb_after_loop);
         try!(self.gen(init));
@@ -1012,33 +1567,33 @@ impl Codegen {
     }
 
     unsafe fn gen_goto(&mut self, label_name: &String) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
         match self.label_map.entry(label_name.to_string()) {
             hash_map::Entry::Occupied(o) => {
                 let label = o.into_mut();
                 LLVMBuildBr(self.builder, *label);
             }
             hash_map::Entry::Vacant(v) => {
-                let label = LLVMAppendBasicBlock(func, CString::new("label").unwrap().as_ptr());
+                let label = LLVMAppendBasicBlockInContext(self.context, func, CString::new("label").unwrap().as_ptr());
                 LLVMBuildBr(self.builder, label);
                 v.insert(label);
             }
         };
-        let tmp_label = LLVMAppendBasicBlock(func, CString::new("tmp_label").unwrap().as_ptr());
+        let tmp_label = LLVMAppendBasicBlockInContext(self.context, func, CString::new("tmp_label").unwrap().as_ptr());
         LLVMPositionBuilderAtEnd(self.builder, tmp_label);
 
         Ok((ptr::null_mut(), None))
     }
 
     unsafe fn gen_label(&mut self, label_name: &String) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
         match self.label_map.entry(label_name.to_string()) {
             hash_map::Entry::Occupied(o) => {
                 let label = o.into_mut();
                 LLVMPositionBuilderAtEnd(self.builder, *label);
             }
             hash_map::Entry::Vacant(v) => {
-                let label = LLVMAppendBasicBlock(func, CString::new("label").unwrap().as_ptr());
+                let label = LLVMAppendBasicBlockInContext(self.context, func, CString::new("label").unwrap().as_ptr());
                 v.insert(label);
                 LLVMBuildBr(self.builder, label);
                 LLVMPositionBuilderAtEnd(self.builder, label);
@@ -1146,15 +1701,17 @@ impl Codegen {
         let (lhs, lhsty_w) = try!(self.gen(lhsast));
         let (rhs, rhsty_w) = try!(self.gen(rhsast));
 
-        let lhsty = lhsty_w.unwrap().conversion();
-        let rhsty = rhsty_w.unwrap().conversion();
+        let lhsty = try!(value_ty(lhsty_w)).conversion();
+        let rhsty = try!(value_ty(rhsty_w)).conversion();
 
         if matches!(lhsty, Type::Ptr(_)) && matches!(rhsty, Type::Ptr(_)) {
-            let castlhs = self.typecast(lhs, LLVMInt64Type());
-            let castrhs = self.typecast(rhs, LLVMInt64Type());
+            let ptrdiff_ty = Type::ptrdiff(&self.target);
+            let llvm_ptrdiff_ty = self.type_to_llvmty(&ptrdiff_ty);
+            let castlhs = self.typecast(lhs, llvm_ptrdiff_ty);
+            let castrhs = self.typecast(rhs, llvm_ptrdiff_ty);
             return Ok((
-                self.gen_int_binary_op(castlhs, castrhs, op),
-                Some(Type::LLong(Sign::Signed)),
+                try!(self.gen_int_binary_op(castlhs, castrhs, op)),
+                Some(ptrdiff_ty),
             ));
         }
 
@@ -1175,7 +1732,7 @@ impl Codegen {
             let castrhs = self.typecast(rhs, conv_llvm_ty);
             let castlhs = self.typecast(lhs, conv_llvm_ty);
             return Ok((
-                self.gen_double_binary_op(castlhs, castrhs, op),
+                try!(self.gen_double_binary_op(castlhs, castrhs, op)),
                 Some(conv_ty),
             ));
         }
@@ -1183,7 +1740,7 @@ impl Codegen {
         if conv_ty.is_int_ty() {
             let castrhs = self.typecast(rhs, conv_llvm_ty);
             let castlhs = self.typecast(lhs, conv_llvm_ty);
-            return Ok((self.gen_int_binary_op(castlhs, castrhs, op), Some(conv_ty)));
+            return Ok((try!(self.gen_int_binary_op(castlhs, castrhs, op)), Some(conv_ty)));
         }
 
         Err(Error::MsgWithPos(
@@ -1209,10 +1766,10 @@ impl Codegen {
             )
         };
 
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
-        let bb_then = LLVMAppendBasicBlock(func, CString::new("then").unwrap().as_ptr());
-        let bb_merge = LLVMAppendBasicBlock(func, CString::new("merge").unwrap().as_ptr());
+        let bb_then = LLVMAppendBasicBlockInContext(self.context, func, CString::new("then").unwrap().as_ptr());
+        let bb_merge = LLVMAppendBasicBlockInContext(self.context, func, CString::new("merge").unwrap().as_ptr());
         let x = LLVMGetInsertBlock(self.builder);
 
         LLVMBuildCondBr(self.builder, lhs_val, bb_then, bb_merge);
@@ -1241,7 +1798,7 @@ impl Codegen {
         );
         LLVMAddIncoming(
             phi,
-            vec![LLVMConstInt(LLVMInt1Type(), 0, 0)]
+            vec![LLVMConstInt(LLVMInt1TypeInContext(self.context), 0, 0)]
                 .as_mut_slice()
                 .as_mut_ptr(),
             vec![x].as_mut_slice().as_mut_ptr(),
@@ -1272,10 +1829,10 @@ impl Codegen {
             )
         };
 
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
-        let bb_then = LLVMAppendBasicBlock(func, CString::new("then").unwrap().as_ptr());
-        let bb_merge = LLVMAppendBasicBlock(func, CString::new("merge").unwrap().as_ptr());
+        let bb_then = LLVMAppendBasicBlockInContext(self.context, func, CString::new("then").unwrap().as_ptr());
+        let bb_merge = LLVMAppendBasicBlockInContext(self.context, func, CString::new("merge").unwrap().as_ptr());
         let x = LLVMGetInsertBlock(self.builder);
 
         LLVMBuildCondBr(self.builder, lhs_val, bb_merge, bb_then);
@@ -1303,7 +1860,7 @@ impl Codegen {
         );
         LLVMAddIncoming(
             phi,
-            vec![LLVMConstInt(LLVMInt1Type(), 1, 0)]
+            vec![LLVMConstInt(LLVMInt1TypeInContext(self.context), 1, 0)]
                 .as_mut_slice()
                 .as_mut_ptr(),
             vec![x].as_mut_slice().as_mut_ptr(),
@@ -1325,7 +1882,7 @@ impl Codegen {
         rhsast: &node::AST,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let (dst, ptr_dst_ty_w) = try!(self.gen(lhsast));
-        let ptr_dst_ty = ptr_dst_ty_w.unwrap();
+        let ptr_dst_ty = try!(value_ty(ptr_dst_ty_w));
         // self.gen returns Ptr(real_type)
         let dst_ty = match ptr_dst_ty.get_elem_ty() {
             Some(ok) => ok,
@@ -1351,8 +1908,8 @@ impl Codegen {
         lhs: LLVMValueRef,
         rhs: LLVMValueRef,
         op: &node::CBinOps,
-    ) -> LLVMValueRef {
-        match *op {
+    ) -> CodegenR<LLVMValueRef> {
+        Ok(match *op {
             node::CBinOps::Add => LLVMBuildAdd(
                 self.builder,
                 lhs,
@@ -1453,8 +2010,12 @@ impl Codegen {
                 CString::new("xor").unwrap().as_ptr(),
             ),
             node::CBinOps::Comma => rhs,
-            _ => panic!(),
-        }
+            _ => {
+                return Err(Error::Msg(
+                    "invalid operands to binary expression".to_string(),
+                ))
+            }
+        })
     }
 
     unsafe fn gen_ptr_binary_op(
@@ -1493,8 +2054,8 @@ impl Codegen {
         lhs: LLVMValueRef,
         rhs: LLVMValueRef,
         op: &node::CBinOps,
-    ) -> LLVMValueRef {
-        match *op {
+    ) -> CodegenR<LLVMValueRef> {
+        Ok(match *op {
             node::CBinOps::Add => LLVMBuildFAdd(
                 self.builder,
                 lhs,
@@ -1561,8 +2122,12 @@ impl Codegen {
                 rhs,
                 CString::new("fge").unwrap().as_ptr(),
             ),
-            _ => ptr::null_mut(),
-        }
+            _ => {
+                return Err(Error::Msg(
+                    "invalid operands to binary expression".to_string(),
+                ))
+            }
+        })
     }
 
     unsafe fn gen_ternary_op(
@@ -1582,11 +2147,11 @@ impl Codegen {
             )
         };
 
-        let func = self.cur_func.unwrap();
+        let func = try!(self.get_cur_func());
 
-        let bb_then = LLVMAppendBasicBlock(func, CString::new("then").unwrap().as_ptr());
-        let bb_else = LLVMAppendBasicBlock(func, CString::new("else").unwrap().as_ptr());
-        let bb_merge = LLVMAppendBasicBlock(func, CString::new("merge").unwrap().as_ptr());
+        let bb_then = LLVMAppendBasicBlockInContext(self.context, func, CString::new("then").unwrap().as_ptr());
+        let bb_else = LLVMAppendBasicBlockInContext(self.context, func, CString::new("else").unwrap().as_ptr());
+        let bb_merge = LLVMAppendBasicBlockInContext(self.context, func, CString::new("merge").unwrap().as_ptr());
 
         LLVMBuildCondBr(self.builder, cond_val, bb_then, bb_else);
 
@@ -1602,8 +2167,8 @@ impl Codegen {
 
         LLVMPositionBuilderAtEnd(self.builder, bb_merge);
 
-        if matches!(then_ty.clone().unwrap(), Type::Void) || matches!(else_ty.unwrap(), Type::Void)
-        {
+        let is_void = |ty: &Option<Type>| ty.as_ref().map_or(true, |ty| matches!(*ty, Type::Void));
+        if is_void(&then_ty) || is_void(&else_ty) {
             return Ok((ptr::null_mut(), None));
         }
 
@@ -1642,18 +2207,18 @@ impl Codegen {
         field_name: String,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let (strct, ptr_ty) = try!(self.gen(expr));
-        let ptr_ty = ptr_ty.unwrap();
-        let ty = ptr_ty
-            .get_elem_ty()
-            .or_else(|| panic!("gen_assign: must be a pointer to the value's type"))
-            .unwrap();
-        let strct_name = ty.get_name();
-        assert!(strct_name.is_some());
-
-        let ref rectype = self.llvm_struct_map
-            .get(strct_name.unwrap().as_str())
-            .unwrap();
-        let idx = *rectype.field_pos.get(field_name.as_str()).unwrap();
+        let rectype = match try!(value_ty(ptr_ty)).get_elem_ty() {
+            Some(ty) => try!(self.get_rectype(ty)),
+            None => {
+                return Err(Error::Msg(
+                    "member reference base type is not a structure or union".to_string(),
+                ))
+            }
+        };
+        let idx = match rectype.field_pos.get(field_name.as_str()) {
+            Some(idx) => *idx,
+            None => return Err(Error::Msg(format!("no member named '{}'", field_name))),
+        };
         if rectype.is_struct {
             Ok((
                 LLVMBuildStructGEP(
@@ -1693,8 +2258,7 @@ impl Codegen {
         }
         let mut n = (self.local_varmap.len() - 1) as i32;
         while n >= 0 {
-            if self.local_varmap[n as usize].contains_key(name) {
-                let varinfo = self.local_varmap[n as usize].get(name).unwrap();
+            if let Some(varinfo) = self.local_varmap[n as usize].get(name) {
                 return Some(varinfo);
             }
             n -= 1;
@@ -1706,7 +2270,7 @@ impl Codegen {
         let varinfo_w = self.lookup_var(name.as_str());
         match varinfo_w {
             Some(varinfo) => Ok((varinfo.llvm_val, Some(Type::Ptr(Box::new(varinfo.ty))))),
-            None => panic!("undeclared variable"),
+            None => Err(Error::Msg(format!("use of undeclared identifier '{}'", name))),
         }
     }
 
@@ -1750,7 +2314,7 @@ impl Codegen {
                 }
             }
         } else {
-            panic!();
+            Err(Error::Msg("cannot load from a non-pointer value".to_string()))
         }
     }
 
@@ -1759,6 +2323,12 @@ impl Codegen {
         ast: &node::AST,
         args: &Vec<node::AST>,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
+        if let node::ASTKind::Variable(_, ref name) = retrieve_from_load(ast).kind {
+            if name.starts_with("__builtin_") {
+                return self.gen_builtin_call(name.as_str(), args, &ast.pos);
+            }
+        }
+
         // there's a possibility that the types of args are not the same as the types of params.
         // so the args before implicit type casting are called 'maybe correct args'.
         let mut maybe_correct_args_val = vec![];
@@ -1782,7 +2352,7 @@ impl Codegen {
             }
             x => {
                 let (val, ty) = try!(self.gen(&x));
-                VarInfo::new(ty.unwrap(), LLVMTypeOf(val), val)
+                VarInfo::new(try!(value_ty(ty)), LLVMTypeOf(val), val)
             }
         };
 
@@ -1860,6 +2430,67 @@ impl Codegen {
             Some((*func_ret_ty).clone()),
         ))
     }
+    // the functions in parser::BUILTIN_FUNCS
+    unsafe fn gen_builtin_call(
+        &mut self,
+        name: &str,
+        args: &Vec<node::AST>,
+        pos: &Pos,
+    ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
+        if name == "__builtin_reg_class" {
+            let class = match args.get(0).map(|arg| &arg.kind) {
+                Some(&node::ASTKind::TypeCast(_, Type::Ptr(ref ty))) => match **ty {
+                    Type::Float | Type::Double => 1,
+                    Type::Struct(_, _) | Type::Union(_, _, _) | Type::Array(_, _) => 2,
+                    _ => 0,
+                },
+                _ => {
+                    return Err(Error::MsgWithPos(
+                        "__builtin_reg_class requires a cast to a pointer type".to_string(),
+                        pos.clone(),
+                    ))
+                }
+            };
+            return Ok((
+                LLVMConstInt(LLVMInt32TypeInContext(self.context), class, 0),
+                Some(Type::Int(Sign::Signed)),
+            ));
+        }
+
+        // __builtin_va_start(ap) -> llvm.va_start(ap) and so on.
+        // 'ap' points to the va_list
+        let intrinsic = format!("llvm.{}", &name["__builtin_".len()..]);
+        let func = match self.global_varmap.get(intrinsic.as_str()) {
+            Some(func) => func.llvm_val,
+            None => {
+                return Err(Error::MsgWithPos(
+                    format!("unknown builtin function '{}'", name),
+                    pos.clone(),
+                ))
+            }
+        };
+        let params_count = if name == "__builtin_va_copy" { 2 } else { 1 };
+        if args.len() != params_count {
+            return Err(Error::MsgWithPos(
+                format!("'{}' takes {} arguments", name, params_count),
+                pos.clone(),
+            ));
+        }
+        let i8ptr_ty = LLVMPointerType(LLVMInt8TypeInContext(self.context), 0);
+        let mut args_val = Vec::new();
+        for arg in args {
+            let val = try!(self.gen(arg)).0;
+            args_val.push(self.typecast(val, i8ptr_ty));
+        }
+        LLVMBuildCall(
+            self.builder,
+            func,
+            args_val.as_mut_slice().as_mut_ptr(),
+            params_count as u32,
+            CString::new("").unwrap().as_ptr(),
+        );
+        Ok((ptr::null_mut(), None))
+    }
     unsafe fn gen_continue(&mut self) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let continue_bb = if let Some(l) = self.continue_labels.back() {
             *l
@@ -1886,12 +2517,13 @@ impl Codegen {
 
     unsafe fn gen_return(&mut self, ret_ast: &node::AST) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let (ret_val, _) = try!(self.gen(ret_ast));
+        let func = try!(self.get_cur_func());
         Ok((
             LLVMBuildRet(
                 self.builder,
                 self.typecast(
                     ret_val,
-                    LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(self.cur_func.unwrap()))),
+                    LLVMGetReturnType(LLVMGetElementType(LLVMTypeOf(func))),
                 ),
             ),
             None,
@@ -1905,10 +2537,10 @@ impl Codegen {
         is_unsigned: bool,
     ) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         let ty = match *bits {
-            Bits::Bits8 => LLVMInt8Type(),
-            Bits::Bits16 => LLVMInt16Type(),
-            Bits::Bits32 => LLVMInt32Type(),
-            Bits::Bits64 => LLVMInt64Type(),
+            Bits::Bits8 => LLVMInt8TypeInContext(self.context),
+            Bits::Bits16 => LLVMInt16TypeInContext(self.context),
+            Bits::Bits32 => LLVMInt32TypeInContext(self.context),
+            Bits::Bits64 => LLVMInt64TypeInContext(self.context),
         };
         Ok((
             LLVMConstInt(ty, n, if is_unsigned { 1 } else { 0 }),
@@ -1917,15 +2549,15 @@ impl Codegen {
     }
     pub unsafe fn make_char(&mut self, n: i32) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         Ok((
-            LLVMConstInt(LLVMInt8Type(), n as u64, 0),
-            Some(Type::Char(Sign::Signed)),
+            LLVMConstInt(LLVMInt8TypeInContext(self.context), n as u64, 0),
+            Some(Type::plain_char(&self.target)),
         ))
     }
     pub unsafe fn make_float(&mut self, f: f64) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        Ok((LLVMConstReal(LLVMFloatType(), f), Some(Type::Float)))
+        Ok((LLVMConstReal(LLVMFloatTypeInContext(self.context), f), Some(Type::Float)))
     }
     pub unsafe fn make_double(&mut self, f: f64) -> CodegenR<(LLVMValueRef, Option<Type>)> {
-        Ok((LLVMConstReal(LLVMDoubleType(), f), Some(Type::Double)))
+        Ok((LLVMConstReal(LLVMDoubleTypeInContext(self.context), f), Some(Type::Double)))
     }
     pub unsafe fn make_const_str(&mut self, s: &String) -> CodegenR<(LLVMValueRef, Option<Type>)> {
         Ok((
@@ -1934,7 +2566,7 @@ impl Codegen {
                 CString::new(s.as_str()).unwrap().as_ptr(),
                 CString::new("str").unwrap().as_ptr(),
             ),
-            Some(Type::Ptr(Box::new(Type::Char(Sign::Signed)))),
+            Some(Type::Ptr(Box::new(Type::plain_char(&self.target)))),
         ))
     }
 
@@ -1978,19 +2610,18 @@ impl Codegen {
 
     pub unsafe fn type_to_llvmty(&mut self, ty: &Type) -> LLVMTypeRef {
         match ty {
-            &Type::Void => LLVMVoidType(),
-            &Type::Char(_) => LLVMInt8Type(),
-            &Type::Short(_) => LLVMInt16Type(),
-            &Type::Int(_) => LLVMInt32Type(),
-            &Type::Long(_) => LLVMInt64Type(),
-            &Type::LLong(_) => LLVMInt64Type(),
-            &Type::Float => LLVMFloatType(),
-            &Type::Double => LLVMDoubleType(),
+            &Type::Void => LLVMVoidTypeInContext(self.context),
+            &Type::Char(_) => LLVMInt8TypeInContext(self.context),
+            &Type::Short(_) | &Type::Int(_) | &Type::Long(_) | &Type::LLong(_) => {
+                LLVMIntTypeInContext(self.context, ty.calc_size(&self.target) as u32 * 8)
+            }
+            &Type::Float => LLVMFloatTypeInContext(self.context),
+            &Type::Double => LLVMDoubleTypeInContext(self.context),
             &Type::Ptr(ref elemty) => LLVMPointerType(
                 || -> LLVMTypeRef {
                     let elemty = self.type_to_llvmty(&**elemty);
                     match LLVMGetTypeKind(elemty) {
-                        llvm::LLVMTypeKind::LLVMVoidTypeKind => LLVMInt8Type(),
+                        llvm::LLVMTypeKind::LLVMVoidTypeKind => LLVMInt8TypeInContext(self.context),
                         _ => elemty,
                     }
                 }(),
@@ -1999,6 +2630,8 @@ impl Codegen {
             &Type::Array(ref elemty, ref size) => {
                 LLVMArrayType(self.type_to_llvmty(&**elemty), *size as u32)
             }
+            // structs and unions are passed as llvm aggregates, which is not the C abi
+            // of any target (see README)
             &Type::Func(ref ret_type, ref param_types, ref is_vararg) => LLVMFunctionType(
                 self.type_to_llvmty(&**ret_type),
                 || -> *mut LLVMTypeRef {
@@ -2015,7 +2648,7 @@ impl Codegen {
             &Type::Union(ref name, ref fields, ref max_size_field_pos) => {
                 self.make_union(name, fields, *max_size_field_pos)
             }
-            &Type::Enum => LLVMInt32Type(),
+            &Type::Enum => LLVMInt32TypeInContext(self.context),
         }
     }
     unsafe fn make_rectype_base(
@@ -2119,15 +2752,23 @@ impl Codegen {
         if exist {
             return new_struct;
         }
-        // size of an union is the same as the biggest type in the union
-        LLVMStructSetBody(
-            new_struct,
-            vec![fields_llvm_types[max_size_field_pos]]
-                .as_mut_slice()
-                .as_mut_ptr(),
-            1,
-            0,
-        );
+        // the most aligned field gives the alignment of the union, and the padding after it
+        // makes the size the same as calc_size() (the biggest field rounded up to the alignment)
+        let target = &self.target;
+        let union_ty = Type::Union(name.to_string(), fields.clone(), max_size_field_pos);
+        let union_size = union_ty.calc_size(target);
+        let aligned_pos = (0..fields_types.len())
+            .max_by_key(|&i| {
+                let ty = &fields_types[i];
+                (ty.calc_align(target), ty.calc_size(target))
+            })
+            .unwrap_or(max_size_field_pos);
+        let mut body = vec![fields_llvm_types[aligned_pos]];
+        let padding = union_size - fields_types[aligned_pos].calc_size(target);
+        if padding > 0 {
+            body.push(LLVMArrayType(LLVMInt8TypeInContext(self.context), padding as u32));
+        }
+        LLVMStructSetBody(new_struct, body.as_mut_slice().as_mut_ptr(), body.len() as u32, 0);
         self.llvm_struct_map.insert(
             name.to_string(),
             RectypeInfo::new(
@@ -2141,3 +2782,71 @@ impl Codegen {
         new_struct
     }
 }
+
+// everything llvm made for this codegen (types, constants, the module) lives in its own context,
+// so dropping a codegen doesn't affect the others
+impl Drop for Codegen {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMDisposeBuilder(self.builder);
+            LLVMDisposeModule(self.module);
+            LLVMContextDispose(self.context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_line_comments, Codegen};
+    use target::Target;
+
+    // each codegen has its own context, so one can live and be dropped while another does
+    #[test]
+    fn two_codegens_at_once() {
+        let target = Target::from_triple("x86_64-unknown-linux-gnu").unwrap();
+        let a = unsafe { Codegen::new("a.c", target.clone()) };
+        let b = unsafe { Codegen::new("b.c", target) };
+        assert!(a.context != b.context);
+        assert!(a.module != b.module);
+        drop(a);
+        drop(b);
+    }
+
+    #[test]
+    fn line_comments_replace_line_metadata() {
+        let ir = "define i32 @main() {\n\
+                  entry:\n  \
+                  %a = alloca i32, !rucc.line !0\n  \
+                  store i32 1, i32* %a, !rucc.line !0\n  \
+                  %0 = load i32, i32* %a, !rucc.line !1\n  \
+                  ret i32 %0, !rucc.line !1\n\
+                  }\n\
+                  \n\
+                  define void @f() {\n\
+                  entry:\n  \
+                  ret void, !rucc.line !1\n\
+                  }\n\
+                  \n\
+                  !0 = !{i32 2}\n\
+                  !1 = !{i32 3}\n\
+                  !2 = !{i32 4}\n";
+        let expected = "define i32 @main() {\n\
+                        entry:\n  \
+                        ; line 2\n  \
+                        %a = alloca i32\n  \
+                        store i32 1, i32* %a\n  \
+                        ; line 3\n  \
+                        %0 = load i32, i32* %a\n  \
+                        ret i32 %0\n\
+                        }\n\
+                        \n\
+                        define void @f() {\n\
+                        entry:\n  \
+                        ; line 3\n  \
+                        ret void\n\
+                        }\n\
+                        \n\
+                        !2 = !{i32 4}\n";
+        assert_eq!(add_line_comments(ir), expected);
+    }
+}
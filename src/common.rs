@@ -1,7 +1,15 @@
-use lexer;
-use parser;
 use codegen;
-use std::io::{stderr, Write};
+use ast_dump;
+use include_path::IncludePath;
+use target::{Arch, Target};
+use error::{Diagnostic, Diagnostics, Severity};
+use file_provider::FileProvider;
+use session::{codegen_error, internal_error, Session, STDIN};
+use std::fs;
+use std::panic;
+use std::io::{stderr, stdout, Read, Write};
+use std::sync::Arc;
+use std::process::Command;
 
 extern crate regex;
 use self::regex::Regex;
@@ -9,62 +17,557 @@ use self::regex::Regex;
 extern crate ansi_term;
 use self::ansi_term::Colour;
 
-use CODEGEN;
-
-// parse -> codegen -> write llvm bitcode to output file
-pub fn run_file<'a>(filename: &'a str) {
-    // parser::Parser::new(&mut lexer).run(&mut nodes);
-
-    // DEBUG: for node in &ast {
-    // DEBUG:     node.show();
-    // DEBUG: }
-
-    // DEBUG: println!("\nllvm-ir test output:");
-    unsafe {
-        let mut nodes = Vec::new();
-        let mut lexer = lexer::Lexer::new(filename.to_string());
-        let mut parser = parser::Parser::new(&mut lexer);
-
-        loop {
-            match parser.read_toplevel(&mut nodes) {
-                Err(parser::Error::EOF) => break,
-                Err(_) => continue,
-                _ => {}
-            }
-            match CODEGEN.lock().unwrap().run(&nodes) {
-                Ok(_) => {}
-                // TODO: implement err handler for codegen
-                Err(codegen::Error::MsgWithPos(msg, pos)) => {
-                    writeln!(
-                        &mut stderr(),
-                        "{}: {} {}: {}",
-                        parser.lexer.get_filename(),
-                        Colour::Red.bold().paint("error:"),
-                        pos.line,
-                        msg
-                    ).unwrap();
-                    writeln!(
-                        &mut stderr(),
-                        "{}",
-                        parser.lexer.get_surrounding_code_with_err_point(pos.pos)
-                    ).unwrap();
-                    println!(
-                        "{} error{} generated.",
-                        parser.err_counts + 1,
-                        if parser.err_counts + 1 > 1 { "s" } else { "" }
-                    );
-                    ::std::process::exit(-1);
-                }
-                _ => panic!("this is a bug. fix soon"),
-            }
-            nodes.clear();
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputKind {
+    Preprocessed,
+    AstDump(ast_dump::Format),
+    Bitcode,
+    LLVMIR,
+    Assembly,
+    Object,
+    Executable,
+    // -M, -MM: make rules listing the headers (like -E, nothing is compiled)
+    Dependencies,
+}
+
+impl OutputKind {
+    // preprocessed source, AST dumps and dependencies are printed to stdout when '-o' is not given
+    pub fn is_text(&self) -> bool {
+        match *self {
+            OutputKind::Preprocessed | OutputKind::AstDump(_) | OutputKind::Dependencies => true,
+            _ => false,
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputKind::Preprocessed => "i",
+            OutputKind::AstDump(ref format) => format.extension(),
+            OutputKind::Bitcode => "bc",
+            OutputKind::LLVMIR => "ll",
+            OutputKind::Assembly => "s",
+            OutputKind::Object => "o",
+            OutputKind::Executable => "out",
+            OutputKind::Dependencies => "d",
+        }
+    }
+}
+
+// -D and -U
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroOption {
+    Define(String),
+    Undef(String),
+}
+
+// -M, -MM, -MD, -MMD
+#[derive(Debug, Clone)]
+pub struct DependencyOptions {
+    // false for -MM and -MMD
+    pub system_headers: bool,
+    // -MF
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub output_kind: OutputKind,
+    // if None, the output file name is made from the input file name
+    pub output_filename: Option<String>,
+    // -L
+    pub library_paths: Vec<String>,
+    // -l
+    pub libraries: Vec<String>,
+    // -D and -U in the order given (the last one for a name wins)
+    pub macros: Vec<MacroOption>,
+    // -I, -iquote, -isystem
+    pub include_path: IncludePath,
+    // -O0, -O1, -O2, -O3, -Os
+    pub opt_level: codegen::OptLevel,
+    // comment the llvm ir (-S --emit-llvm) with source lines
+    pub llvm_line_comments: bool,
+    // where source files and headers are read from. None means the real file system
+    pub file_provider: Option<Arc<FileProvider>>,
+    // type sizes and predefined macros are made from this
+    pub target: Target,
+    // -MD and -MMD write dependencies besides the output. -M and -MM use this as well
+    pub dependency: Option<DependencyOptions>,
+}
+
+impl Options {
+    // for the host. fails if rucc doesn't support the host
+    pub fn new() -> Result<Options, String> {
+        Target::host().map(Options::with_target)
+    }
+    pub fn with_target(target: Target) -> Options {
+        Options {
+            output_kind: OutputKind::Executable,
+            output_filename: None,
+            library_paths: Vec::new(),
+            libraries: Vec::new(),
+            macros: Vec::new(),
+            include_path: IncludePath::new(),
+            opt_level: codegen::OptLevel::O0,
+            llvm_line_comments: false,
+            file_provider: None,
+            target: target,
+            dependency: None,
+        }
+    }
+}
+
+// the result of compile()
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub kind: OutputKind,
+    pub data: Vec<u8>,
+    // the source file and the headers it includes (without system headers for -MM and -MMD)
+    pub dependencies: Vec<String>,
+    // e.g. #warning
+    pub warnings: Vec<Diagnostic>,
+}
+
+// foo/bar.c -> foo/bar.{ext}
+pub fn replace_extension(filename: &str, ext: &str) -> String {
+    Regex::new(r"\.[^./]*$")
+        .unwrap()
+        .replace_all(filename, "")
+        .to_string() + "." + ext
+}
+
+pub fn show_error(msg: &str) {
+    writeln!(
+        &mut stderr(),
+        "rucc: {} {}",
+        Colour::Red.bold().paint("error:"),
+        msg
+    ).unwrap();
+}
+
+pub fn show_warning(msg: &str) {
+    writeln!(
+        &mut stderr(),
+        "rucc: {} {}",
+        Colour::Purple.bold().paint("warning:"),
+        msg
+    ).unwrap();
+}
+
+pub fn show_diagnostics(diagnostics: &Diagnostics) {
+    for diag in &diagnostics.errors {
+        let severity = match diag.severity {
+            Severity::Error => Colour::Red.bold().paint("error:"),
+            Severity::Warning => Colour::Purple.bold().paint("warning:"),
+        };
+        if diag.line > 0 {
+            writeln!(
+                &mut stderr(),
+                "{}: {} {}: {}",
+                diag.filename,
+                severity,
+                diag.line,
+                diag.msg
+            ).unwrap();
+        } else {
+            writeln!(&mut stderr(), "{}: {} {}", diag.filename, severity, diag.msg).unwrap();
+        }
+        if let Some(ref code) = diag.code {
+            writeln!(&mut stderr(), "{}", code).unwrap();
+        }
+    }
+    // stderr: the output of -E may be written to stdout
+    writeln!(&mut stderr(), "{}", diagnostics.summary()).unwrap();
+}
+
+// compile C sources, then link them with the other inputs (object files, archives).
+// warnings are shown as they are found. the errors are returned to the caller
+pub fn run(inputs: &Vec<String>, options: &Options) -> Result<(), Diagnostics> {
+    let (sources, others): (Vec<&String>, Vec<&String>) =
+        inputs.iter().partition(|input| is_source(input));
+
+    if options.output_kind != OutputKind::Executable {
+        if !others.is_empty() {
+            show_warning(format!("'{}': linker input unused", others[0]).as_str());
+        }
+        if sources.len() > 1 && options.output_filename.is_some() {
+            return Err(driver_error(
+                "cannot specify '-o' with '-c', '-S' or '-E' with multiple files".to_string(),
+            ));
+        }
+        for source in sources {
+            let output = try!(compile_and_warn(source, options));
+            try!(write_dependency_file(source, &output, options));
+            try!(match options.output_filename {
+                _ if output.kind == OutputKind::Dependencies => Ok(()),
+                Some(ref name) => write_output(name.as_str(), &output),
+                None if output.kind.is_text() => write_output(STDIN, &output),
+                None => write_output(
+                    replace_extension(stdin_as_file(source), output.kind.extension()).as_str(),
+                    &output,
+                ),
+            });
+        }
+        return Ok(());
+    }
+
+    // before compiling anything
+    try!(host_cc_flags(&options.target).map_err(driver_error));
+
+    // every translation unit is compiled into its own object file
+    let mut tmp_objects = Vec::new();
+    let result = compile_objects(&sources, options, &mut tmp_objects).and_then(|_| {
+        let mut objects = tmp_objects.clone();
+        objects.extend(others.into_iter().cloned());
+        let output_file_name = match options.output_filename {
+            Some(ref name) => name.to_string(),
+            None => "a.out".to_string(),
+        };
+        link(&objects, output_file_name.as_str(), options).map_err(driver_error)
+    });
+
+    for name in tmp_objects {
+        let _ = fs::remove_file(name);
+    }
+    result
+}
+
+// the object files written are added to 'objects' even if a later source fails,
+// so that the caller can remove them
+fn compile_objects(
+    sources: &Vec<&String>,
+    options: &Options,
+    objects: &mut Vec<String>,
+) -> Result<(), Diagnostics> {
+    for (i, source) in sources.iter().enumerate() {
+        let output = try!(compile_and_warn(source, options));
+        try!(write_dependency_file(source, &output, options));
+        let name = ::std::env::temp_dir()
+            .join(format!("rucc-{}-{}.o", ::std::process::id(), i))
+            .to_string_lossy()
+            .into_owned();
+        try!(write_output(name.as_str(), &output));
+        objects.push(name);
+    }
+    Ok(())
+}
+
+// an error of the driver (not related to any source file)
+fn driver_error(msg: String) -> Diagnostics {
+    Diagnostics::new(vec![Diagnostic::without_pos(msg.as_str())])
+}
+
+// -M, -MM: to -MF, -o or stdout.
+// -MD, -MMD: to -MF, or foo.d for 'foo.o' (-o) or 'foo.c'
+fn write_dependency_file(
+    source: &str,
+    output: &Output,
+    options: &Options,
+) -> Result<(), Diagnostics> {
+    let dependency = match options.dependency {
+        Some(ref dependency) => dependency,
+        None => return Ok(()),
+    };
+    // the object file that depends on the headers
+    let object = match options.output_filename {
+        Some(ref name) if options.output_kind == OutputKind::Object => name.clone(),
+        _ => replace_extension(basename(stdin_as_file(source)), "o"),
+    };
+    let rule = Output {
+        kind: OutputKind::Dependencies,
+        data: make_rule(object.as_str(), &output.dependencies).into_bytes(),
+        dependencies: Vec::new(),
+        warnings: Vec::new(),
+    };
+    let filename = match (&dependency.filename, output.kind) {
+        (&Some(ref name), _) => name.clone(),
+        (&None, OutputKind::Dependencies) => match options.output_filename {
+            Some(ref name) => name.clone(),
+            None => STDIN.to_string(),
+        },
+        (&None, _) => replace_extension(
+            match options.output_filename {
+                Some(ref name) if options.output_kind == OutputKind::Object => name.as_str(),
+                _ => basename(stdin_as_file(source)),
+            },
+            "d",
+        ),
+    };
+    write_output(filename.as_str(), &rule)
+}
+
+// 'target: deps...' wrapped like GCC does
+fn make_rule(target: &str, dependencies: &Vec<String>) -> String {
+    let mut rule = format!("{}:", escape_make(target));
+    let mut column = rule.len();
+    for dep in dependencies {
+        let dep = escape_make(dep.as_str());
+        if column + 1 + dep.len() > 76 && column > target.len() + 1 {
+            rule.push_str(" \\\n");
+            column = 0;
+        }
+        rule.push(' ');
+        rule.push_str(dep.as_str());
+        column += 1 + dep.len();
+    }
+    rule.push('\n');
+    rule
+}
+
+fn escape_make(filename: &str) -> String {
+    filename.replace("$", "$$").replace(" ", "\\ ").replace("#", "\\#")
+}
+
+fn basename(filename: &str) -> &str {
+    filename.rsplit('/').next().unwrap()
+}
+
+fn stdin_as_file(source: &str) -> &str {
+    if source == STDIN {
+        "stdin"
+    } else {
+        source
+    }
+}
+
+// '-' is stdin
+fn is_source(input: &str) -> bool {
+    input.ends_with(".c") || input == STDIN
+}
+
+// the warnings are shown here, even if the compilation fails
+fn compile_and_warn(source: &str, options: &Options) -> Result<Output, Diagnostics> {
+    let output = try!(compile(source, options));
+    if !output.warnings.is_empty() {
+        show_diagnostics(&Diagnostics::new(output.warnings.clone()));
+    }
+    Ok(output)
+}
+
+// '-' means stdout
+fn write_output(output_file_name: &str, output: &Output) -> Result<(), Diagnostics> {
+    let result = if output_file_name == STDIN {
+        stdout().write_all(&output.data)
+    } else {
+        fs::write(output_file_name, &output.data)
+    };
+    result.map_err(|e| driver_error(format!("{}: {}", output_file_name, e)))
+}
+
+// use 'cc' as a linker driver so that crt objects and libc are linked properly
+fn link(objects: &Vec<String>, output_filename: &str, options: &Options) -> Result<(), String> {
+    let mut cmd = Command::new("cc");
+    cmd.arg("-o").arg(output_filename).args(objects);
+    cmd.args(try!(host_cc_flags(&options.target)));
+    for path in &options.library_paths {
+        cmd.arg(format!("-L{}", path));
+    }
+    for lib in &options.libraries {
+        cmd.arg(format!("-l{}", lib));
+    }
+
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(e) => return Err(format!("cannot execute linker 'cc': {}", e)),
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let linker_msg = String::from_utf8_lossy(output.stderr.as_slice()).into_owned();
+    Err(format!(
+        "linker command failed {}\n{}",
+        match output.status.code() {
+            Some(code) => format!("with exit code {}", code),
+            None => "by signal".to_string(),
+        },
+        linker_msg.trim_right()
+    ))
+}
+
+// the flags that make the host's 'cc' link for 'target'
+fn host_cc_flags(target: &Target) -> Result<Vec<&'static str>, String> {
+    if target.is_native() {
+        return Ok(Vec::new());
+    }
+    match target.arch {
+        // i386 on x86_64 hosts
+        Arch::X86 if target.can_link_on_host() => Ok(vec!["-m32"]),
+        _ => Err(format!(
+            "cannot link for '{}'. use '-c' or '-S' and link with the target's toolchain",
+            target.triple
+        )),
+    }
+}
+
+// --run: compile 'filename' in memory and execute its 'main' with 'args'.
+// returns the exit code of the program. the warnings are shown before running it
+pub fn run_jit(filename: &str, args: &Vec<String>, options: &Options) -> Result<i32, Diagnostics> {
+    let mut session = try!(Session::new(filename, options));
+    let mut codegen = try!(session.codegen());
+    if !session.warnings().is_empty() {
+        show_diagnostics(&Diagnostics::new(session.warnings().clone()));
+    }
+    let mut argv = vec![filename.to_string()];
+    argv.extend(args.iter().cloned());
+    let dirs = library_dirs(options);
+    let mut libraries = Vec::new();
+    for name in &options.libraries {
+        match find_shared_library(name, &dirs) {
+            Some(path) => libraries.push(path),
+            None => return Err(driver_error(format!("cannot find -l{}", name))),
+        }
+    }
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe {
+        codegen.run_main(&argv, &libraries)
+    })) {
+        Ok(Ok(ret)) => Ok(ret),
+        Ok(Err(err)) => Err(codegen_error(err)),
+        Err(cause) => Err(internal_error(cause)),
+    }
+}
+
+// -L and then the directories the system's linker searches
+fn library_dirs(options: &Options) -> Vec<String> {
+    let mut dirs = options.library_paths.clone();
+    if let Some(multiarch) = options.target.multiarch() {
+        dirs.push(format!("/lib/{}", multiarch));
+        dirs.push(format!("/usr/lib/{}", multiarch));
+    }
+    for dir in &["/usr/local/lib", "/lib64", "/usr/lib64", "/lib", "/usr/lib"] {
+        dirs.push(dir.to_string());
+    }
+    dirs
+}
+
+// the shared object of '-lname' that can be loaded into the process.
+// 'libname.so' is often a linker script (e.g. libm.so) or missing without the -dev package,
+// so the versioned soname (e.g. libm.so.6) in the same directory is taken then
+fn find_shared_library(name: &str, dirs: &Vec<String>) -> Option<String> {
+    let unversioned = format!("lib{}.so", name);
+    let is_elf = |path: &str| {
+        let mut magic = [0u8; 4];
+        match fs::File::open(path) {
+            Ok(mut file) => file.read_exact(&mut magic).is_ok() && &magic == b"\x7fELF",
+            Err(_) => false,
+        }
+    };
+    for dir in dirs {
+        let path = format!("{}/{}", dir.trim_right_matches('/'), unversioned);
+        if is_elf(path.as_str()) {
+            return Some(path);
         }
-        parser.show_total_errors();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        // 'libname.so.N' (not 'libname.so.N.M', which the soname links to)
+        let mut versioned = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path().to_string_lossy().into_owned())
+            .filter(|path| {
+                let file_name = path.rsplit('/').next().unwrap();
+                file_name.starts_with(unversioned.as_str())
+                    && file_name[unversioned.len()..].starts_with('.')
+                    && file_name[unversioned.len() + 1..].chars().all(|c| c.is_digit(10))
+                    && is_elf(path.as_str())
+            })
+            .collect::<Vec<String>>();
+        versioned.sort();
+        if let Some(path) = versioned.pop() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+// compile the C source file 'source' ('-' means stdin) into 'options.output_kind' in memory.
+// each call has its own Session, so this can be called from several threads at once.
+pub fn compile(source: &str, options: &Options) -> Result<Output, Diagnostics> {
+    try!(Session::new(source, options)).compile()
+}
+
+// compile 'text' as if it were the content of the file 'filename' (which doesn't have to exist)
+pub fn compile_source(filename: &str, text: &str, options: &Options) -> Result<Output, Diagnostics> {
+    Session::from_source(filename, text, options).compile()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_shared_library, make_rule};
+    use std::env;
+    use std::fs;
+
+    fn deps(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn rule_on_one_line() {
+        assert_eq!(make_rule("foo.o", &deps(&[])), "foo.o:\n");
+        assert_eq!(
+            make_rule("foo.o", &deps(&["foo.c", "foo.h"])),
+            "foo.o: foo.c foo.h\n"
+        );
+    }
+
+    #[test]
+    fn long_rule_is_wrapped() {
+        let rule = make_rule(
+            "main.o",
+            &deps(&[
+                "main.c",
+                "/usr/include/stdio.h",
+                "/usr/include/x86_64-linux-gnu/bits/libc-header-start.h",
+                "/usr/include/features.h",
+                "inc/a.h",
+            ]),
+        );
+        assert_eq!(
+            rule,
+            "main.o: main.c /usr/include/stdio.h \\\n \
+             /usr/include/x86_64-linux-gnu/bits/libc-header-start.h \\\n \
+             /usr/include/features.h inc/a.h\n"
+        );
+        assert!(rule.lines().all(|line| line.len() <= 78));
+    }
+
+    #[test]
+    fn long_name_is_not_wrapped_after_target() {
+        let long = "a/very/long/path/to/some/header/file/that/does/not/fit/on/one/line/at/all.h";
+        assert_eq!(
+            make_rule("x.o", &deps(&[long, "b.h"])),
+            format!("x.o: {} \\\n b.h\n", long)
+        );
+    }
+
+    #[test]
+    fn special_chars_are_escaped() {
+        assert_eq!(
+            make_rule("my obj.o", &deps(&["my file.c", "$dir/a.h", "#b.h"])),
+            "my\\ obj.o: my\\ file.c $$dir/a.h \\#b.h\n"
+        );
+    }
 
-        let output_file_name = Regex::new(r"\..*$").unwrap().replace_all(filename, ".bc");
-        CODEGEN
-            .lock()
-            .unwrap()
-            .write_llvm_bitcode_to_file(output_file_name.to_string().as_str());
+    #[test]
+    fn shared_library_search() {
+        let root = env::temp_dir().join(format!("rucc-libs-{}", ::std::process::id()));
+        let (dir1, dir2) = (root.join("a"), root.join("b"));
+        fs::create_dir_all(&dir1).unwrap();
+        fs::create_dir_all(&dir2).unwrap();
+        let elf = b"\x7fELF...";
+        // libm.so is a linker script in dir1
+        fs::write(dir1.join("libm.so"), "GROUP ( libm.so.6 )").unwrap();
+        fs::write(dir1.join("libm.so.6"), elf).unwrap();
+        fs::write(dir1.join("libm.so.6.1"), elf).unwrap();
+        fs::write(dir1.join("libz.so.1"), "not an object").unwrap();
+        fs::write(dir2.join("libz.so"), elf).unwrap();
+        let dirs = vec![
+            dir1.to_string_lossy().into_owned(),
+            dir2.to_string_lossy().into_owned(),
+        ];
+        let found = |name: &str| find_shared_library(name, &dirs);
+        assert_eq!(found("m"), Some(format!("{}/libm.so.6", dirs[0])));
+        assert_eq!(found("z"), Some(format!("{}/libz.so", dirs[1])));
+        assert_eq!(found("none"), None);
+        fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -0,0 +1,449 @@
+use lexer::{Pos, Symbol, Token, TokenKind};
+use types::Sign;
+use std::cmp::Ordering;
+
+// the evaluator of #if expressions.
+// integers are intmax_t or uintmax_t (64 bits) and follow the C rules for them:
+// the usual arithmetic conversions, wrapping around, and the short circuit evaluation
+// of '&&', '||' and '?:' (e.g. '0 && 1 / 0' is not an error).
+// the lexer has replaced 'defined X', '__has_include(...)' and identifiers with numbers.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    pub fn is_true(&self) -> bool {
+        self.bits() != 0
+    }
+    fn from_bool(b: bool) -> Value {
+        Value::Signed(b as i64)
+    }
+    fn is_unsigned(&self) -> bool {
+        match *self {
+            Value::Signed(_) => false,
+            Value::Unsigned(_) => true,
+        }
+    }
+    fn bits(&self) -> u64 {
+        match *self {
+            Value::Signed(n) => n as u64,
+            Value::Unsigned(n) => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub msg: String,
+    pub pos: Pos,
+}
+
+impl Error {
+    fn new(msg: &str, pos: Pos) -> Error {
+        Error {
+            msg: msg.to_string(),
+            pos: pos,
+        }
+    }
+}
+
+// 'end' is the position of the end of the line, where missing operands are reported.
+// character constants are sign extended unless 'char_unsigned' (plain char of the target)
+pub fn eval(tokens: &Vec<Token>, end: Pos, char_unsigned: bool) -> Result<Value, Error> {
+    if tokens.is_empty() {
+        return Err(Error::new("#if with no expression", end));
+    }
+    let mut evaluator = Evaluator {
+        tokens: tokens,
+        next: 0,
+        end: end,
+        char_unsigned: char_unsigned,
+    };
+    let value = try!(evaluator.read_expr(true));
+    if let Some(tok) = evaluator.peek() {
+        return Err(Error::new(
+            format!("missing binary operator before token '{}'", tok.to_c_text()).as_str(),
+            tok.pos.clone(),
+        ));
+    }
+    Ok(value)
+}
+
+struct Evaluator<'a> {
+    tokens: &'a Vec<Token>,
+    next: usize,
+    end: Pos,
+    char_unsigned: bool,
+}
+
+// the binary operators from the lowest precedence ('?:' and ',' are read separately)
+fn precedence(sym: &Symbol) -> Option<usize> {
+    Some(match *sym {
+        Symbol::LOr => 1,
+        Symbol::LAnd => 2,
+        Symbol::Or => 3,
+        Symbol::Xor => 4,
+        Symbol::Ampersand => 5,
+        Symbol::Eq | Symbol::Ne => 6,
+        Symbol::Lt | Symbol::Le | Symbol::Gt | Symbol::Ge => 7,
+        Symbol::Shl | Symbol::Shr => 8,
+        Symbol::Add | Symbol::Sub => 9,
+        Symbol::Asterisk | Symbol::Div | Symbol::Mod => 10,
+        _ => return None,
+    })
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.next)
+    }
+    fn peek_symbol(&self) -> Option<Symbol> {
+        match self.peek() {
+            Some(&Token {
+                kind: TokenKind::Symbol(ref sym),
+                ..
+            }) => Some(sym.clone()),
+            _ => None,
+        }
+    }
+    fn skip_symbol(&mut self, sym: Symbol) -> bool {
+        if self.peek_symbol() == Some(sym) {
+            self.next += 1;
+            return true;
+        }
+        false
+    }
+    // the position of the next token
+    fn cur_pos(&self) -> Pos {
+        self.peek().map_or(self.end.clone(), |tok| tok.pos.clone())
+    }
+
+    // in the operands not evaluated (e.g. '1 / 0' in '0 && 1 / 0'), 'live' is false
+    // and errors like division by zero are not reported
+    fn read_expr(&mut self, live: bool) -> Result<Value, Error> {
+        let mut value = try!(self.read_cond(live));
+        while self.skip_symbol(Symbol::Comma) {
+            value = try!(self.read_cond(live));
+        }
+        Ok(value)
+    }
+    fn read_cond(&mut self, live: bool) -> Result<Value, Error> {
+        let cond = try!(self.read_binary(1, live));
+        if !self.skip_symbol(Symbol::Question) {
+            return Ok(cond);
+        }
+        let then = try!(self.read_expr(live && cond.is_true()));
+        if !self.skip_symbol(Symbol::Colon) {
+            return Err(Error::new("expected ':' in #if expression", self.cur_pos()));
+        }
+        let els = try!(self.read_cond(live && !cond.is_true()));
+        // the result has the common type of both branches
+        let unsigned = then.is_unsigned() || els.is_unsigned();
+        let value = if cond.is_true() { then } else { els };
+        Ok(if unsigned {
+            Value::Unsigned(value.bits())
+        } else {
+            value
+        })
+    }
+    // the binary operators whose precedence is 'min_prec' or higher
+    fn read_binary(&mut self, min_prec: usize, live: bool) -> Result<Value, Error> {
+        let mut lhs = try!(self.read_unary(live));
+        loop {
+            let op = match self.peek_symbol() {
+                Some(sym) => sym,
+                None => return Ok(lhs),
+            };
+            let prec = match precedence(&op) {
+                Some(prec) if prec >= min_prec => prec,
+                _ => return Ok(lhs),
+            };
+            let pos = self.cur_pos();
+            self.next += 1;
+            let rhs_live = match op {
+                Symbol::LAnd => live && lhs.is_true(),
+                Symbol::LOr => live && !lhs.is_true(),
+                _ => live,
+            };
+            let rhs = try!(self.read_binary(prec + 1, rhs_live));
+            lhs = try!(binary_op(&op, lhs, rhs, pos, rhs_live));
+        }
+    }
+    fn read_unary(&mut self, live: bool) -> Result<Value, Error> {
+        let op = match self.peek_symbol() {
+            Some(sym @ Symbol::Add)
+            | Some(sym @ Symbol::Sub)
+            | Some(sym @ Symbol::BitwiseNot)
+            | Some(sym @ Symbol::Not) => sym,
+            _ => return self.read_primary(live),
+        };
+        self.next += 1;
+        let value = try!(self.read_unary(live));
+        Ok(match (op, value) {
+            (Symbol::Add, value) => value,
+            (Symbol::Sub, Value::Signed(n)) => Value::Signed(n.wrapping_neg()),
+            (Symbol::Sub, Value::Unsigned(n)) => Value::Unsigned(n.wrapping_neg()),
+            (Symbol::BitwiseNot, Value::Signed(n)) => Value::Signed(!n),
+            (Symbol::BitwiseNot, Value::Unsigned(n)) => Value::Unsigned(!n),
+            (_, value) => Value::from_bool(!value.is_true()),
+        })
+    }
+    fn read_primary(&mut self, live: bool) -> Result<Value, Error> {
+        let tok = match self.peek() {
+            Some(tok) => tok,
+            None => return Err(Error::new("expected value in #if expression", self.end.clone())),
+        };
+        self.next += 1;
+        match tok.kind {
+            // a constant too large for intmax_t is uintmax_t
+            TokenKind::IntNumber(n, _, Sign::Signed) if n >= 0 => Ok(Value::Signed(n)),
+            TokenKind::IntNumber(n, _, _) => Ok(Value::Unsigned(n as u64)),
+            TokenKind::Char(c) if self.char_unsigned => Ok(Value::Signed(c as u32 as u8 as i64)),
+            TokenKind::Char(c) => Ok(Value::Signed(c as u32 as u8 as i8 as i64)),
+            TokenKind::Symbol(Symbol::OpeningParen) => {
+                let value = try!(self.read_expr(live));
+                if !self.skip_symbol(Symbol::ClosingParen) {
+                    return Err(Error::new("missing ')' in #if expression", self.cur_pos()));
+                }
+                Ok(value)
+            }
+            TokenKind::FloatNumber(_) => Err(Error::new(
+                "floating constant in #if expression",
+                tok.pos.clone(),
+            )),
+            _ => Err(Error::new(
+                format!("invalid token '{}' in #if expression", tok.to_c_text()).as_str(),
+                tok.pos.clone(),
+            )),
+        }
+    }
+}
+
+fn binary_op(op: &Symbol, lhs: Value, rhs: Value, pos: Pos, live: bool) -> Result<Value, Error> {
+    match *op {
+        Symbol::LAnd => return Ok(Value::from_bool(lhs.is_true() && rhs.is_true())),
+        Symbol::LOr => return Ok(Value::from_bool(lhs.is_true() || rhs.is_true())),
+        Symbol::Shl | Symbol::Shr => return Ok(shift(*op == Symbol::Shl, lhs, rhs)),
+        _ => {}
+    }
+
+    // the usual arithmetic conversions: if either is unsigned, both are converted to uintmax_t.
+    // +, -, *, &, |, ^, == and != give the same bits for both signed and unsigned
+    let unsigned = lhs.is_unsigned() || rhs.is_unsigned();
+    let (l, r) = (lhs.bits(), rhs.bits());
+    let make = |n: u64| {
+        if unsigned {
+            Value::Unsigned(n)
+        } else {
+            Value::Signed(n as i64)
+        }
+    };
+    let compare = || {
+        if unsigned {
+            l.cmp(&r)
+        } else {
+            (l as i64).cmp(&(r as i64))
+        }
+    };
+    Ok(match *op {
+        Symbol::Add => make(l.wrapping_add(r)),
+        Symbol::Sub => make(l.wrapping_sub(r)),
+        Symbol::Asterisk => make(l.wrapping_mul(r)),
+        Symbol::Ampersand => make(l & r),
+        Symbol::Or => make(l | r),
+        Symbol::Xor => make(l ^ r),
+        Symbol::Eq => Value::from_bool(l == r),
+        Symbol::Ne => Value::from_bool(l != r),
+        Symbol::Lt => Value::from_bool(compare() == Ordering::Less),
+        Symbol::Le => Value::from_bool(compare() != Ordering::Greater),
+        Symbol::Gt => Value::from_bool(compare() == Ordering::Greater),
+        Symbol::Ge => Value::from_bool(compare() != Ordering::Less),
+        Symbol::Div | Symbol::Mod => {
+            if r == 0 {
+                if !live {
+                    return Ok(make(0));
+                }
+                return Err(Error::new("division by zero in #if", pos));
+            }
+            let is_div = *op == Symbol::Div;
+            make(match (unsigned, is_div) {
+                (true, true) => l / r,
+                (true, false) => l % r,
+                (false, true) => (l as i64).wrapping_div(r as i64) as u64,
+                (false, false) => (l as i64).wrapping_rem(r as i64) as u64,
+            })
+        }
+        _ => unreachable!(),
+    })
+}
+
+// the result has the type of the left operand.
+// a negative count shifts to the other direction, and a count of 64 or more shifts out all bits
+fn shift(is_left: bool, lhs: Value, rhs: Value) -> Value {
+    let count = match rhs {
+        Value::Signed(n) => n,
+        Value::Unsigned(n) => if n > i64::max_value() as u64 {
+            i64::max_value()
+        } else {
+            n as i64
+        },
+    };
+    let is_left = is_left == (count >= 0);
+    let count = count.checked_abs().unwrap_or(i64::max_value());
+    match lhs {
+        Value::Signed(n) if count >= 64 => Value::Signed(if is_left || n >= 0 { 0 } else { -1 }),
+        Value::Signed(n) if is_left => Value::Signed(n.wrapping_shl(count as u32)),
+        Value::Signed(n) => Value::Signed(n >> count),
+        Value::Unsigned(_) if count >= 64 => Value::Unsigned(0),
+        Value::Unsigned(n) if is_left => Value::Unsigned(n << count),
+        Value::Unsigned(n) => Value::Unsigned(n >> count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use file_provider::VirtualFiles;
+    use include_path::IncludePath;
+    use lexer::Lexer;
+    use target::Target;
+    use std::sync::Arc;
+
+    // whether the group of '#if expr' is included, or the first error
+    fn eval_if_on(expr: &str, triple: &str, files: VirtualFiles) -> Result<bool, String> {
+        let source = format!("#if {}\nyes\n#else\nno\n#endif\n", expr);
+        let target = Target::from_triple(triple).unwrap();
+        let mut lexer = Lexer::with_file_provider(
+            "dir/test.c".to_string(),
+            Some(source),
+            Arc::new(files),
+            target,
+        );
+        let mut include_path = IncludePath::new();
+        include_path.add_user_dir("inc");
+        lexer.set_include_path(include_path);
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            if lexer.get_filename() == "dir/test.c" {
+                tokens.push(tok.to_c_text());
+            }
+        }
+        match lexer.take_diagnostics().into_iter().find(|diag| diag.is_error()) {
+            Some(diag) => Err(diag.msg),
+            None => Ok(tokens == vec!["yes".to_string()]),
+        }
+    }
+    fn eval_if(expr: &str) -> Result<bool, String> {
+        eval_if_on(expr, "x86_64-linux-gnu", VirtualFiles::new())
+    }
+
+    #[test]
+    fn unsigned_promotion() {
+        assert_eq!(eval_if("-1 < 0"), Ok(true));
+        assert_eq!(eval_if("-1 < 0u"), Ok(false));
+        assert_eq!(eval_if("-1 > 0U"), Ok(true));
+        assert_eq!(eval_if("-1 / 2u == 9223372036854775807"), Ok(true));
+        // a constant too large for intmax_t is unsigned
+        assert_eq!(eval_if("18446744073709551615 > 0"), Ok(true));
+        assert_eq!(eval_if("-18446744073709551615 == 1"), Ok(true));
+        // '?:' has the common type of both branches
+        assert_eq!(eval_if("(1 ? -1 : 0u) > 0"), Ok(true));
+        assert_eq!(eval_if("(1 ? -1 : 0) > 0"), Ok(false));
+        // the result of comparisons is signed int
+        assert_eq!(eval_if("(0u < 1) - 2 < 0"), Ok(true));
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(eval_if("1 << 63 < 0"), Ok(true));
+        assert_eq!(eval_if("1u << 63 > 0"), Ok(true));
+        assert_eq!(eval_if("-1 >> 1 == -1"), Ok(true));
+        assert_eq!(eval_if("-1u >> 63 == 1"), Ok(true));
+        // a count of 64 or more shifts out all bits
+        assert_eq!(eval_if("(1 << 64) == 0 && (-1 >> 64) == -1 && (-1u >> 100) == 0"), Ok(true));
+        // a negative count shifts to the other direction
+        assert_eq!(eval_if("1 >> -1 == 2 && 4 << -2 == 1"), Ok(true));
+        // the type of the left operand
+        assert_eq!(eval_if("(-2 >> 1u) < 0"), Ok(true));
+    }
+
+    #[test]
+    fn overflow_wraps_around() {
+        assert_eq!(eval_if("9223372036854775807 + 1 < 0"), Ok(true));
+        assert_eq!(eval_if("-9223372036854775807 - 2 > 0"), Ok(true));
+        assert_eq!(eval_if("(-9223372036854775807 - 1) / -1 < 0"), Ok(true));
+        assert_eq!(eval_if("(-9223372036854775807 - 1) % -1 == 0"), Ok(true));
+        assert_eq!(eval_if("0u - 1 == 18446744073709551615"), Ok(true));
+        assert_eq!(eval_if("-(-9223372036854775807 - 1) < 0"), Ok(true));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let msg = Err("division by zero in #if".to_string());
+        assert_eq!(eval_if("1 / 0"), msg);
+        assert_eq!(eval_if("1 % (1 - 1)"), msg);
+        assert_eq!(eval_if("1u / 0"), msg);
+        assert_eq!(eval_if("-7 / 2 == -3 && -7 % 2 == -1"), Ok(true));
+    }
+
+    #[test]
+    fn short_circuit() {
+        assert_eq!(eval_if("0 && 1 / 0"), Ok(false));
+        assert_eq!(eval_if("1 || 1 / 0"), Ok(true));
+        assert_eq!(eval_if("0 ? 1 / 0 : 2"), Ok(true));
+        assert_eq!(eval_if("1 ? 3 : 1 % 0"), Ok(true));
+        assert_eq!(eval_if("0 && (1 || 1 / 0)"), Ok(false));
+        assert_eq!(
+            eval_if("1 && 1 / 0"),
+            Err("division by zero in #if".to_string())
+        );
+    }
+
+    #[test]
+    fn char_constants() {
+        assert_eq!(eval_if("'a' == 97 && '\\n' == 10 && '\\0' == 0"), Ok(true));
+        assert_eq!(eval_if("'\\377' < 0"), Ok(true));
+        assert_eq!(eval_if("'\\xff' == -1"), Ok(true));
+        // plain char is unsigned on aarch64
+        let aarch64 = |expr| eval_if_on(expr, "aarch64-linux-gnu", VirtualFiles::new());
+        assert_eq!(aarch64("'\\xff' == 255"), Ok(true));
+        assert_eq!(aarch64("'\\xff' < 0"), Ok(false));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_if("1.0"), Err("floating constant in #if expression".to_string()));
+        assert_eq!(eval_if("(1"), Err("missing ')' in #if expression".to_string()));
+        assert_eq!(eval_if("1 ? 2"), Err("expected ':' in #if expression".to_string()));
+        assert_eq!(eval_if("1 +"), Err("expected value in #if expression".to_string()));
+        assert_eq!(
+            eval_if("1 2"),
+            Err("missing binary operator before token '2'".to_string())
+        );
+    }
+
+    #[test]
+    fn has_include() {
+        let has_include = |expr| {
+            let mut files = VirtualFiles::new();
+            files.add_file("dir/local.h", "");
+            files.add_file("inc/user.h", "");
+            eval_if_on(expr, "x86_64-linux-gnu", files)
+        };
+        // the directory of the current file is searched only for "..."
+        assert_eq!(has_include("__has_include(\"local.h\")"), Ok(true));
+        assert_eq!(has_include("__has_include(<local.h>)"), Ok(false));
+        // -I
+        assert_eq!(has_include("__has_include(<user.h>) && __has_include(\"user.h\")"), Ok(true));
+        // the headers coming with rucc
+        assert_eq!(has_include("__has_include(<stddef.h>)"), Ok(true));
+        assert_eq!(has_include("__has_include(<none.h>) || __has_include(\"none.h\")"), Ok(false));
+        assert_eq!(has_include("defined(__has_include) && !__has_include(<none.h>)"), Ok(true));
+        assert_eq!(
+            has_include("__has_include \"local.h\""),
+            Err("missing '(' after '__has_include'".to_string())
+        );
+    }
+}
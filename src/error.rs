@@ -1,4 +1,112 @@
-pub fn error_exit(line: i32, msg: &str) -> ! {
-    println!("error: {}: {}", line, msg);
-    panic!();
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    // doesn't make the compilation fail (e.g. #warning)
+    Warning,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+// an error found while compiling, e.g. 'foo.c: error: 3: expected ';''
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub filename: String,
+    pub line: usize,
+    pub msg: String,
+    // the source line with '^' under the error point
+    pub code: Option<String>,
+    pub severity: Severity,
+}
+
+impl Diagnostic {
+    pub fn new(filename: &str, line: usize, msg: &str) -> Diagnostic {
+        Diagnostic {
+            filename: filename.to_string(),
+            line: line,
+            msg: msg.to_string(),
+            code: None,
+            severity: Severity::Error,
+        }
+    }
+    // an error not related to any source file
+    pub fn without_pos(msg: &str) -> Diagnostic {
+        Diagnostic::new("rucc", 0, msg)
+    }
+    pub fn with_code(mut self, code: String) -> Diagnostic {
+        self.code = Some(code);
+        self
+    }
+    pub fn as_warning(mut self) -> Diagnostic {
+        self.severity = Severity::Warning;
+        self
+    }
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = self.severity.as_str();
+        if self.line > 0 {
+            try!(write!(f, "{}: {}: {}: {}", self.filename, severity, self.line, self.msg));
+        } else {
+            try!(write!(f, "{}: {}: {}", self.filename, severity, self.msg));
+        }
+        if let Some(ref code) = self.code {
+            try!(write!(f, "\n{}", code));
+        }
+        Ok(())
+    }
+}
+
+// all the errors of a compilation (and the warnings reported with them)
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new(errors: Vec<Diagnostic>) -> Diagnostics {
+        Diagnostics { errors: errors }
+    }
+    // e.g. '1 warning and 2 errors generated.'
+    pub fn summary(&self) -> String {
+        let num_errors = self.errors.iter().filter(|diag| diag.is_error()).count();
+        let num_warnings = self.errors.len() - num_errors;
+        let mut counts = Vec::new();
+        if num_warnings > 0 {
+            counts.push(format!(
+                "{} warning{}",
+                num_warnings,
+                if num_warnings > 1 { "s" } else { "" }
+            ));
+        }
+        if num_errors > 0 || num_warnings == 0 {
+            counts.push(format!(
+                "{} error{}",
+                num_errors,
+                if num_errors > 1 { "s" } else { "" }
+            ));
+        }
+        format!("{} generated.", counts.join(" and "))
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in &self.errors {
+            try!(writeln!(f, "{}", error));
+        }
+        write!(f, "{}", self.summary())
+    }
 }
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::path;
+
+// where the lexer reads source files and headers from.
+// editors and tests can implement this to compile unsaved buffers or virtual header sets.
+// it's shared by sessions running on several threads, so it must be Send + Sync.
+pub trait FileProvider: fmt::Debug + Send + Sync {
+    fn is_file(&self, filename: &str) -> bool;
+    fn read(&self, filename: &str) -> io::Result<String>;
+}
+
+// the real file system
+#[derive(Debug, Clone)]
+pub struct RealFiles;
+
+impl FileProvider for RealFiles {
+    fn is_file(&self, filename: &str) -> bool {
+        path::Path::new(filename).is_file()
+    }
+    fn read(&self, filename: &str) -> io::Result<String> {
+        let mut body = String::new();
+        try!(try!(OpenOptions::new().read(true).open(filename)).read_to_string(&mut body));
+        Ok(body)
+    }
+}
+
+// files in memory. the files not found here are looked up in 'fallback' if any
+#[derive(Debug)]
+pub struct VirtualFiles {
+    files: HashMap<String, String>,
+    fallback: Option<Box<FileProvider>>,
+}
+
+impl VirtualFiles {
+    pub fn new() -> VirtualFiles {
+        VirtualFiles {
+            files: HashMap::new(),
+            fallback: None,
+        }
+    }
+    // virtual files on top of the real file system
+    pub fn with_real_files() -> VirtualFiles {
+        VirtualFiles {
+            files: HashMap::new(),
+            fallback: Some(Box::new(RealFiles)),
+        }
+    }
+    pub fn add_file(&mut self, filename: &str, body: &str) {
+        self.files.insert(filename.to_string(), body.to_string());
+    }
+}
+
+impl FileProvider for VirtualFiles {
+    fn is_file(&self, filename: &str) -> bool {
+        self.files.contains_key(filename)
+            || self.fallback
+                .as_ref()
+                .map_or(false, |fallback| fallback.is_file(filename))
+    }
+    fn read(&self, filename: &str) -> io::Result<String> {
+        if let Some(body) = self.files.get(filename) {
+            return Ok(body.clone());
+        }
+        match self.fallback {
+            Some(ref fallback) => fallback.read(filename),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no such virtual file",
+            )),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use builtin_headers::BUILTIN_INCLUDE_DIR;
+use file_provider::FileProvider;
+use target::{Arch, Target};
+use std::path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirKind {
+    Quote,  // -iquote
+    User,   // -I
+    System, // -isystem and default directories
+}
+
+// Header search order is the same as GCC:
+//   #include "..." : the directory of the current file, -iquote, -I, -isystem, default dirs
+//   #include <...> : -I, -isystem, default dirs
+// The default dirs start with the directory of the headers coming with rucc (the builtin dir).
+#[derive(Debug, Clone)]
+pub struct IncludePath {
+    dirs: Vec<(String, DirKind)>,
+    // -resource-dir. if None, the headers embedded in the binary are used
+    resource_dir: Option<String>,
+    // the system directories made from the target (see set_target)
+    default_dirs: Vec<String>,
+}
+
+impl IncludePath {
+    pub fn new() -> IncludePath {
+        IncludePath {
+            dirs: Vec::new(),
+            resource_dir: None,
+            default_dirs: Vec::new(),
+        }
+    }
+
+    // the default system directories for 'target'.
+    // the target specific headers are in /usr/include/<multiarch> (multiarch packages)
+    // or /usr/<multiarch>/include (cross toolchains). i386 headers may also be installed
+    // with the x86_64 ones (gcc-multilib). targets other than linux have no default dirs
+    pub fn set_target(&mut self, target: &Target) {
+        self.default_dirs.clear();
+        let multiarch = match target.multiarch() {
+            Some(multiarch) => multiarch,
+            None => return,
+        };
+        self.default_dirs.push("/usr/local/include/".to_string());
+        if !target.is_native() {
+            self.default_dirs.push(format!("/usr/{}/include/", multiarch));
+        }
+        self.default_dirs.push(format!("/usr/include/{}/", multiarch));
+        if target.arch == Arch::X86 && !target.is_native() {
+            self.default_dirs.push("/usr/include/x86_64-linux-gnu/".to_string());
+        }
+        self.default_dirs.push("/usr/include/".to_string());
+        self.default_dirs.push("/usr/include/linux/".to_string());
+    }
+
+    // headers are read from '<dir>/include' instead of the embedded ones
+    pub fn set_resource_dir(&mut self, dir: &str) {
+        self.resource_dir = Some(dir.to_string());
+    }
+    pub fn builtin_dir(&self) -> String {
+        match self.resource_dir {
+            Some(ref dir) => join(dir, "include/"),
+            None => BUILTIN_INCLUDE_DIR.to_string(),
+        }
+    }
+
+    pub fn add_quote_dir(&mut self, dir: &str) {
+        self.insert_dir(dir, DirKind::Quote);
+    }
+    pub fn add_user_dir(&mut self, dir: &str) {
+        self.insert_dir(dir, DirKind::User);
+    }
+    pub fn add_system_dir(&mut self, dir: &str) {
+        self.insert_dir(dir, DirKind::System);
+    }
+    // keep dirs sorted by kind, and in the order of addition in the same kind
+    fn insert_dir(&mut self, dir: &str, kind: DirKind) {
+        let pos = self.dirs
+            .iter()
+            .position(|&(_, k)| kind_order(k) > kind_order(kind))
+            .unwrap_or(self.dirs.len());
+        self.dirs.insert(pos, (dir.to_string(), kind));
+    }
+
+    fn all_dirs(&self) -> Vec<(String, DirKind)> {
+        let mut dirs = self.dirs.clone();
+        dirs.push((self.builtin_dir(), DirKind::System));
+        dirs.extend(
+            self.default_dirs
+                .iter()
+                .map(|dir| (dir.clone(), DirKind::System)),
+        );
+        dirs
+    }
+
+    // whether the 'idx'th directory (returned by search()) is a system directory
+    pub fn is_system_dir(&self, idx: usize) -> bool {
+        self.all_dirs()
+            .get(idx)
+            .map_or(false, |&(_, kind)| kind == DirKind::System)
+    }
+
+    // returns (the path of the found header, index of the directory where the header was found).
+    // 'current_dir' is the directory of the including file and searched first for #include "...".
+    // 'start' is used for #include_next and means the search starts from the 'start'th directory.
+    // headers are looked up in 'files'.
+    pub fn search(
+        &self,
+        name: &str,
+        quoted: bool,
+        current_dir: Option<&str>,
+        start: Option<usize>,
+        files: &FileProvider,
+    ) -> Option<(String, Option<usize>)> {
+        if path::Path::new(name).is_absolute() {
+            return if files.is_file(name) {
+                Some((name.to_string(), None))
+            } else {
+                None
+            };
+        }
+
+        if quoted && start.is_none() {
+            if let Some(dir) = current_dir {
+                let filename = join(dir, name);
+                if files.is_file(filename.as_str()) {
+                    return Some((filename, None));
+                }
+            }
+        }
+
+        let dirs = self.all_dirs();
+        for (i, &(ref dir, kind)) in dirs.iter().enumerate() {
+            if start.map_or(false, |start| i < start) {
+                continue;
+            }
+            if !quoted && kind == DirKind::Quote {
+                continue;
+            }
+            let filename = join(dir, name);
+            if files.is_file(filename.as_str()) {
+                return Some((filename, Some(i)));
+            }
+        }
+        None
+    }
+}
+
+fn kind_order(kind: DirKind) -> usize {
+    match kind {
+        DirKind::Quote => 0,
+        DirKind::User => 1,
+        DirKind::System => 2,
+    }
+}
+
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else if dir.ends_with('/') {
+        format!("{}{}", dir, name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+// foo/bar/baz.h -> foo/bar
+pub fn dirname(filename: &str) -> String {
+    match path::Path::new(filename).parent() {
+        Some(parent) => parent.to_string_lossy().into_owned(),
+        None => "".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_provider::VirtualFiles;
+
+    fn files(names: &[&str]) -> VirtualFiles {
+        let mut files = VirtualFiles::new();
+        for name in names {
+            files.add_file(name, "");
+        }
+        files
+    }
+    // -iquote quote, -I user, -isystem system (added in a mixed order)
+    fn include_path() -> IncludePath {
+        let mut include_path = IncludePath::new();
+        include_path.add_system_dir("system");
+        include_path.add_user_dir("user1");
+        include_path.add_quote_dir("quote");
+        include_path.add_user_dir("user2/");
+        include_path
+    }
+    fn found(path: &str, idx: Option<usize>) -> Option<(String, Option<usize>)> {
+        Some((path.to_string(), idx))
+    }
+
+    #[test]
+    fn search_order() {
+        let include_path = include_path();
+        let all = files(&[
+            "cur/a.h",
+            "quote/a.h",
+            "user1/a.h",
+            "user2/a.h",
+            "system/a.h",
+        ]);
+        assert_eq!(
+            include_path.search("a.h", true, Some("cur"), None, &all),
+            found("cur/a.h", None)
+        );
+        assert_eq!(
+            include_path.search("a.h", true, None, None, &all),
+            found("quote/a.h", Some(0))
+        );
+        // <...> doesn't search the current dir and -iquote
+        assert_eq!(
+            include_path.search("a.h", false, Some("cur"), None, &all),
+            found("user1/a.h", Some(1))
+        );
+        let user2 = files(&["user2/a.h", "system/a.h"]);
+        assert_eq!(
+            include_path.search("a.h", false, Some("cur"), None, &user2),
+            found("user2/a.h", Some(2))
+        );
+        let system = files(&["system/a.h"]);
+        assert_eq!(
+            include_path.search("a.h", true, Some("cur"), None, &system),
+            found("system/a.h", Some(3))
+        );
+        assert_eq!(include_path.search("b.h", true, Some("cur"), None, &all), None);
+    }
+
+    #[test]
+    fn search_next() {
+        let include_path = include_path();
+        let all = files(&["cur/a.h", "quote/a.h", "user1/a.h", "user2/a.h"]);
+        // #include_next in user1/a.h: the current dir is not searched
+        assert_eq!(
+            include_path.search("a.h", true, Some("cur"), Some(2), &all),
+            found("user2/a.h", Some(2))
+        );
+        assert_eq!(include_path.search("a.h", false, Some("cur"), Some(3), &all), None);
+    }
+
+    #[test]
+    fn search_absolute_path() {
+        let include_path = include_path();
+        let all = files(&["/abs/a.h", "user1/abs/a.h"]);
+        assert_eq!(
+            include_path.search("/abs/a.h", false, None, None, &all),
+            found("/abs/a.h", None)
+        );
+        assert_eq!(include_path.search("/abs/b.h", true, Some("cur"), None, &all), None);
+    }
+
+    #[test]
+    fn builtin_and_default_dirs() {
+        let mut include_path = include_path();
+        include_path.set_target(&Target::from_triple("aarch64-linux-gnu").unwrap());
+        let builtin = format!("{}a.h", BUILTIN_INCLUDE_DIR);
+        let builtin_and_default = files(&[builtin.as_str(), "/usr/include/a.h"]);
+        // the builtin dir comes right after -isystem
+        assert_eq!(
+            include_path.search("a.h", false, None, None, &builtin_and_default),
+            found(builtin.as_str(), Some(4))
+        );
+        let default = files(&["/usr/include/a.h"]);
+        let (path, idx) = include_path
+            .search("a.h", false, None, None, &default)
+            .unwrap();
+        assert_eq!(path, "/usr/include/a.h");
+        assert!(idx.unwrap() > 4);
+        assert!(!include_path.is_system_dir(1));
+        assert!(include_path.is_system_dir(3));
+        assert!(include_path.is_system_dir(idx.unwrap()));
+
+        // -resource-dir
+        include_path.set_resource_dir("/res");
+        let resource = files(&["/res/include/a.h", builtin.as_str()]);
+        assert_eq!(
+            include_path.search("a.h", false, None, None, &resource),
+            found("/res/include/a.h", Some(4))
+        );
+
+        // no default dirs for targets other than linux
+        include_path.set_target(&Target::from_triple("x86_64-unknown-none").unwrap());
+        assert_eq!(include_path.search("a.h", false, None, None, &default), None);
+    }
+}
@@ -1,23 +1,49 @@
-use std::fs::OpenOptions;
-use std::io::prelude::*;
 use std::str;
+use std::env;
+use std::fs;
+use std::mem;
+use std::sync::Arc;
 use std::collections::VecDeque;
-use std::path;
-use std::process;
 use std::collections::{HashMap, HashSet};
-use error;
-use parser;
-use parser::{Error, ParseR};
+use error::Diagnostic;
+use file_provider::{FileProvider, RealFiles};
+use parser::{Error, ParseR, BUILTIN_FUNCS};
 use node::Bits;
+use types::Sign;
+use cpp_expr;
+use include_path::{dirname, join, IncludePath};
+use builtin_headers::{BuiltinHeaders, BUILTIN_INCLUDE_DIR};
+use target::Target;
 
-extern crate ansi_term;
-use self::ansi_term::{Colour, Style};
+extern crate libc;
+
+// the macros whose values are computed by the lexer.
+// __has_include and the like can only be used in #if and #elif
+const BUILTIN_MACROS: &'static [&'static str] = &[
+    "__LINE__",
+    "__FILE__",
+    "__BASE_FILE__",
+    "__INCLUDE_LEVEL__",
+    "__COUNTER__",
+    "__DATE__",
+    "__TIME__",
+    "__has_include",
+    "__has_include_next",
+    "__has_builtin",
+    "__has_attribute",
+];
+
+const MONTHS: [&'static str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"
+];
 
 #[derive(Debug, Clone)]
 pub enum Macro {
     // Vec<Token> -> macro body
     Object(Vec<Token>),
-    FuncLike(Vec<Token>),
+    // usize -> the number of parameters
+    // Option<usize> -> the position of the variable arguments ('...') if any
+    FuncLike(Vec<Token>, usize, Option<usize>),
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -59,6 +85,48 @@ pub enum Keyword {
     Return,
 }
 
+impl Keyword {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Keyword::Typedef => "typedef",
+            Keyword::Extern => "extern",
+            Keyword::Static => "static",
+            Keyword::Auto => "auto",
+            Keyword::Restrict => "restrict",
+            Keyword::Register => "register",
+            Keyword::Const => "const",
+            Keyword::ConstExpr => "constexpr",
+            Keyword::Volatile => "volatile",
+            Keyword::Void => "void",
+            Keyword::Signed => "signed",
+            Keyword::Unsigned => "unsigned",
+            Keyword::Char => "char",
+            Keyword::Int => "int",
+            Keyword::Short => "short",
+            Keyword::Long => "long",
+            Keyword::Float => "float",
+            Keyword::Double => "double",
+            Keyword::Struct => "struct",
+            Keyword::Enum => "enum",
+            Keyword::Union => "union",
+            Keyword::Noreturn => "noreturn",
+            Keyword::Inline => "inline",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::For => "for",
+            Keyword::Do => "do",
+            Keyword::While => "while",
+            Keyword::Switch => "switch",
+            Keyword::Case => "case",
+            Keyword::Default => "default",
+            Keyword::Goto => "goto",
+            Keyword::Break => "break",
+            Keyword::Continue => "continue",
+            Keyword::Return => "return",
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub enum Symbol {
     OpeningParen,
@@ -111,10 +179,66 @@ pub enum Symbol {
     Sizeof,
 }
 
+impl Symbol {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Symbol::OpeningParen => "(",
+            Symbol::ClosingParen => ")",
+            Symbol::OpeningBrace => "{",
+            Symbol::ClosingBrace => "}",
+            Symbol::OpeningBoxBracket => "[",
+            Symbol::ClosingBoxBracket => "]",
+            Symbol::Comma => ",",
+            Symbol::Semicolon => ";",
+            Symbol::Colon => ":",
+            Symbol::Point => ".",
+            Symbol::Arrow => "->",
+            Symbol::Inc => "++",
+            Symbol::Dec => "--",
+            Symbol::Add => "+",
+            Symbol::Sub => "-",
+            Symbol::Asterisk => "*",
+            Symbol::Div => "/",
+            Symbol::Mod => "%",
+            Symbol::Not => "!",
+            Symbol::BitwiseNot => "~",
+            Symbol::Ampersand => "&",
+            Symbol::Shl => "<<",
+            Symbol::Shr => ">>",
+            Symbol::Lt => "<",
+            Symbol::Le => "<=",
+            Symbol::Gt => ">",
+            Symbol::Ge => ">=",
+            Symbol::Eq => "==",
+            Symbol::Ne => "!=",
+            Symbol::Xor => "^",
+            Symbol::Or => "|",
+            Symbol::LAnd => "&&",
+            Symbol::LOr => "||",
+            Symbol::Question => "?",
+            Symbol::Assign => "=",
+            Symbol::AssignAdd => "+=",
+            Symbol::AssignSub => "-=",
+            Symbol::AssignMul => "*=",
+            Symbol::AssignDiv => "/=",
+            Symbol::AssignMod => "%=",
+            Symbol::AssignShl => "<<=",
+            Symbol::AssignShr => ">>=",
+            Symbol::AssignAnd => "&=",
+            Symbol::AssignXor => "^=",
+            Symbol::AssignOr => "|=",
+            Symbol::Hash => "#",
+            Symbol::Vararg => "...",
+            Symbol::Sizeof => "sizeof",
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct Pos {
     pub line: usize,
-    pub pos: usize,
+    pub pos: usize,    // byte offset in the file
+    pub column: usize, // 1-based, 0 if unknown
 }
 
 impl Pos {
@@ -122,6 +246,7 @@ impl Pos {
         Pos {
             line: line,
             pos: pos,
+            column: 0,
         }
     }
 }
@@ -131,7 +256,8 @@ pub enum TokenKind {
     MacroParam,
     Keyword(Keyword),
     Identifier(String),
-    IntNumber(i64, Bits),
+    // Sign::Unsigned for the 'u' suffix
+    IntNumber(i64, Bits, Sign),
     FloatNumber(f64),
     String(String),
     Char(char),
@@ -147,14 +273,6 @@ macro_rules! ident_val {
         }
     }
 }
-macro_rules! ident_mut_val {
-    ($e:expr) => {
-        match &mut $e.kind {
-            &mut TokenKind::Identifier(ref mut ident) => ident,
-            _ => panic!()
-        }
-    }
-}
 macro_rules! retrieve_str {
     ($e:expr) => {
         match &$e.kind {
@@ -179,6 +297,7 @@ pub struct Token {
     pub macro_position: usize,
     pub hideset: HashSet<String>,
     pub pos: Pos,
+    pub spelling: Option<String>, // source text of a number literal (used by -E and ##)
 }
 
 impl Token {
@@ -189,11 +308,123 @@ impl Token {
             macro_position: macro_position,
             hideset: HashSet::new(),
             pos: Pos::new(line, pos),
+            spelling: None,
         }
     }
     pub fn add_hideset(&mut self, s: String) {
         self.hideset.insert(s);
     }
+    // the token as C source text (used by -E)
+    pub fn to_c_text(&self) -> String {
+        if let Some(ref spelling) = self.spelling {
+            return spelling.to_string();
+        }
+        match self.kind {
+            TokenKind::Keyword(ref k) => k.as_str().to_string(),
+            TokenKind::Identifier(ref i) => i.to_string(),
+            // a constant too large for long long is unsigned (e.g. 18446744073709551615)
+            TokenKind::IntNumber(ref i, _, Sign::Unsigned) => format!("{}U", *i as u64),
+            TokenKind::IntNumber(ref i, _, _) if *i < 0 => format!("{}U", *i as u64),
+            TokenKind::IntNumber(ref i, _, _) => format!("{}", *i),
+            TokenKind::FloatNumber(ref f) => format!("{:?}", *f),
+            TokenKind::String(ref s) => format!("\"{}\"", escape_c_chars(s.as_str(), '"')),
+            TokenKind::Char(ref c) => {
+                format!("'{}'", escape_c_chars(c.to_string().as_str(), '\''))
+            }
+            TokenKind::Symbol(ref s) => s.as_str().to_string(),
+            TokenKind::MacroParam | TokenKind::Newline => "".to_string(),
+        }
+    }
+}
+
+// escape 's' so that it can be put in a string (quote = '"') or char (quote = '\'') literal
+pub fn escape_c_chars(s: &str, quote: char) -> String {
+    let mut escaped = "".to_string();
+    for c in s.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' => escaped.push_str("\\\\"),
+            c if c == quote => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // bytes >= 0x80 are read as latin-1 chars; write them back as the same bytes
+            c if (c as u32) < 0x20 || (0x7f <= c as u32 && c as u32 <= 0xff) => {
+                escaped.push_str(format!("\\{:03o}", c as u32).as_str())
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// whether '__has_builtin(name)' ('op') is 1
+fn is_builtin_func(op: &str, name: &str) -> bool {
+    op == "__has_builtin" && BUILTIN_FUNCS.contains(&name)
+}
+
+// '#!/path/to/interpreter' in the first line of a source file is ignored.
+// returns the position of the newline ending the line so that line numbers stay correct.
+fn skip_shebang(body: &Vec<u8>) -> usize {
+    if !body.starts_with(b"#!") {
+        return 0;
+    }
+    body.iter().position(|&c| c == b'\n').unwrap_or(body.len())
+}
+
+// the key for '#pragma once'. files not on the disk (e.g. built-in headers) are used as they are
+fn canonical_path(filename: &str) -> String {
+    match fs::canonicalize(filename) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => filename.to_string(),
+    }
+}
+
+// '__VA_OPT__(tokens)' in the body of a variadic macro is replaced with 'tokens'
+// if the variable arguments are given, otherwise it's removed
+fn replace_va_opt(body: &Vec<Token>, has_va_args: bool) -> Vec<Token> {
+    let mut replaced = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        if ident_val!(body[i]) != "__VA_OPT__" || i + 1 >= body.len()
+            || ident_val!(body[i + 1]) != "("
+        {
+            replaced.push(body[i].clone());
+            i += 1;
+            continue;
+        }
+        let mut nest = 0;
+        i += 2; // __VA_OPT__ (
+        while i < body.len() {
+            match ident_val!(body[i]).as_str() {
+                ")" if nest == 0 => break,
+                "(" => nest += 1,
+                ")" => nest -= 1,
+                _ => {}
+            }
+            if has_va_args {
+                replaced.push(body[i].clone());
+            }
+            i += 1;
+        }
+        i += 1; // )
+    }
+    replaced
+}
+
+// an #if (#ifdef, #ifndef) group being read
+#[derive(Clone)]
+struct CondGroup {
+    // whether one of #if, #elif and #else has been included
+    taken: bool,
+    // after #else
+    in_else: bool,
+    // where the #if is
+    line: usize,
+    // the depth of the file stack where the #if is. a group must end in the same file
+    depth: usize,
 }
 
 #[derive(Clone)]
@@ -204,67 +435,108 @@ pub struct Lexer {
     pub peek: VecDeque<Vec<u8>>,
     pub peek_pos: VecDeque<usize>,
     buf: VecDeque<VecDeque<Token>>,
-    cond_stack: Vec<bool>,
+    cond_stack: Vec<CondGroup>,
+    include_path: IncludePath,
+    // the index of the include directory where each file in 'filename' was found (for #include_next)
+    include_dir_idx: VecDeque<Option<usize>>,
+    // the file name given by '#line N "file"' for each file in 'filename'.
+    // only __FILE__ and diagnostics use it. headers are searched for from the real path
+    presumed_filename: VecDeque<Option<String>>,
+    // errors found by the lexer and the parser
+    diagnostics: Vec<Diagnostic>,
+    // true after a fatal error. no more tokens are read
+    aborted: bool,
+    // where the source file and headers are read from
+    files: Arc<FileProvider>,
+    // predefined macros are made from this. the parser uses this for sizeof
+    target: Target,
+    // the headers opened by #include, and whether each is a system header (for -M and -MM)
+    included_files: Vec<(String, bool)>,
+    // the canonical paths of the files with '#pragma once'
+    pragma_once: HashSet<String>,
+    // the source file given (for __BASE_FILE__)
+    base_file: String,
+    // the next value of __COUNTER__
+    counter: i64,
+    // __DATE__ and __TIME__. computed when first used
+    date_time: Option<(String, String)>,
+    // true while the macros in a #if line are expanded
+    in_if_expr: bool,
 }
 
 impl Lexer {
-    pub fn new(filename: String) -> Lexer {
+    pub fn new(filename: String, target: Target) -> Lexer {
+        Lexer::with_file_provider(filename, None, Arc::new(RealFiles), target)
+    }
+    // 'source' is the text of the file named 'filename', which doesn't have to exist
+    pub fn from_source(filename: String, source: String, target: Target) -> Lexer {
+        Lexer::with_file_provider(filename, Some(source), Arc::new(RealFiles), target)
+    }
+    // if 'source' is None, the source file is read from 'files' as well as headers.
+    // predefined macros and type sizes are made from 'target'
+    pub fn with_file_provider(
+        filename: String,
+        source: Option<String>,
+        files: Arc<FileProvider>,
+        target: Target,
+    ) -> Lexer {
+        let files: Arc<FileProvider> = Arc::new(BuiltinHeaders::new(files));
         let mut buf = VecDeque::new();
         buf.push_back(VecDeque::new());
 
-        let mut file = if let Ok(ok) = OpenOptions::new().read(true).open(filename.to_string()) {
-            ok
-        } else {
-            println!(
-                "{} not found such file '{}'",
-                Colour::Red.bold().paint("error:"),
-                Style::new().underline().paint(filename)
-            );
-            ::std::process::exit(0)
-        };
-
-        let mut file_body = String::new();
-        match file.read_to_string(&mut file_body) {
-            Ok(_) => (),
+        let mut diagnostics = Vec::new();
+        let mut file_body = match source.map_or_else(|| files.read(filename.as_str()), Ok) {
+            Ok(body) => body,
             Err(e) => {
-                println!(
-                    "an error occurred while reading file '{}'\n{} {}",
-                    Style::new().underline().paint(filename),
-                    Colour::Red.bold().paint("error:"),
-                    e
-                );
-                ::std::process::exit(0)
+                diagnostics.push(Diagnostic::without_pos(
+                    format!("cannot open '{}': {}", filename, e).as_str(),
+                ));
+                "".to_string()
             }
         };
-
-        let mut rucc_header = OpenOptions::new()
-            .read(true)
-            .open("./include/rucc.h")
-            .unwrap();
-        let mut rucc_header_body = String::new();
-        rucc_header
-            .read_to_string(&mut rucc_header_body)
-            .ok()
-            .expect("cannot read file");
+        // the last one is read first:
+        //   predefined macros -> rucc.h -> command line macros -> source file.
+        // predefined macros and rucc.h are loaded below
         let mut peek = VecDeque::new();
         unsafe {
             peek.push_back(file_body.as_mut_vec().clone());
-            peek.push_back(rucc_header_body.as_mut_vec().clone());
         }
+        peek.push_back(Vec::new());
+        peek.push_back(Vec::new());
+        peek.push_back(Vec::new());
 
         let mut peek_pos = VecDeque::new();
+        peek_pos.push_back(skip_shebang(&peek[0]));
+        peek_pos.push_back(0);
         peek_pos.push_back(0);
         peek_pos.push_back(0);
 
+        let base_file = filename.clone();
         let mut filenames = VecDeque::new();
         filenames.push_back(filename);
+        filenames.push_back("<command line>".to_string());
         filenames.push_back("rucc.h".to_string());
+        filenames.push_back("<built-in>".to_string());
 
         let mut cur_line = VecDeque::new();
         cur_line.push_back(1);
         cur_line.push_back(1);
+        cur_line.push_back(1);
+        cur_line.push_back(1);
+
+        let mut include_dir_idx = VecDeque::new();
+        include_dir_idx.push_back(None);
+        include_dir_idx.push_back(None);
+        include_dir_idx.push_back(None);
+        include_dir_idx.push_back(None);
 
-        Lexer {
+        let mut presumed_filename = VecDeque::new();
+        presumed_filename.push_back(None);
+        presumed_filename.push_back(None);
+        presumed_filename.push_back(None);
+        presumed_filename.push_back(None);
+
+        let mut lexer = Lexer {
             cur_line: cur_line,
             filename: filenames,
             macro_map: HashMap::new(),
@@ -272,13 +544,130 @@ impl Lexer {
             peek_pos: peek_pos,
             buf: buf,
             cond_stack: Vec::new(),
+            include_path: IncludePath::new(),
+            include_dir_idx: include_dir_idx,
+            presumed_filename: presumed_filename,
+            aborted: !diagnostics.is_empty(),
+            diagnostics: diagnostics,
+            files: files,
+            target: target,
+            included_files: Vec::new(),
+            pragma_once: HashSet::new(),
+            base_file: base_file,
+            counter: 0,
+            date_time: None,
+            in_if_expr: false,
+        };
+        lexer.include_path.set_target(&lexer.target);
+        lexer.load_predefined_macros();
+        lexer.load_rucc_header();
+        lexer
+    }
+    // the macros made from the target description. the buffer for them is at the top of the stack
+    fn load_predefined_macros(&mut self) {
+        self.peek[3] = self.target.predefined_macros().into_bytes();
+    }
+    // rucc.h in the builtin include dir. the buffer for it is right below the predefined macros
+    fn load_rucc_header(&mut self) {
+        let filename = join(self.include_path.builtin_dir().as_str(), "rucc.h");
+        match self.files.read(filename.as_str()) {
+            Ok(body) => self.peek[2] = body.into_bytes(),
+            Err(e) => {
+                self.diagnostics.push(Diagnostic::without_pos(
+                    format!("cannot open '{}': {}", filename, e).as_str(),
+                ));
+                self.aborted = true;
+            }
         }
     }
+    // -D: 'NAME' defines NAME as 1, 'NAME=VAL' and 'NAME(a,b)=VAL' define NAME as VAL
+    pub fn define_macro(&mut self, definition: &str) {
+        let directive = match definition.find('=') {
+            Some(i) => format!("#define {} {}\n", &definition[..i], &definition[i + 1..]),
+            None => format!("#define {} 1\n", definition),
+        };
+        self.push_cmdline_directive(directive);
+    }
+    // -U
+    pub fn undef_macro(&mut self, name: &str) {
+        self.push_cmdline_directive(format!("#undef {}\n", name));
+    }
+    fn push_cmdline_directive(&mut self, directive: String) {
+        // the buffer for command line macros is right above the source file.
+        // so this must be called before reading any token.
+        self.peek[1].extend(directive.into_bytes());
+    }
+
+    pub fn get_target(&self) -> &Target {
+        &self.target
+    }
+
+    // this must be called before reading any token, since rucc.h is reloaded
+    // when the builtin include dir is changed (-resource-dir)
+    pub fn set_include_path(&mut self, include_path: IncludePath) {
+        let reload = include_path.builtin_dir() != self.include_path.builtin_dir();
+        self.include_path = include_path;
+        self.include_path.set_target(&self.target);
+        if reload {
+            self.load_rucc_header();
+        }
+    }
+
+    // record an error. 'code' is the source line with the error point
+    pub fn add_error(&mut self, line: usize, msg: &str, code: Option<String>) {
+        let mut diag = Diagnostic::new(self.get_filename().as_str(), line, msg);
+        diag.code = code;
+        self.diagnostics.push(diag);
+    }
+    pub fn add_warning(&mut self, line: usize, msg: &str) {
+        let diag = Diagnostic::new(self.get_filename().as_str(), line, msg).as_warning();
+        self.diagnostics.push(diag);
+    }
+    // record an error that makes it impossible to continue, and stop reading tokens
+    // (every read after this reaches EOF)
+    fn fatal_error(&mut self, msg: &str) -> Error {
+        let line = *self.get_cur_line();
+        self.add_error(line, msg, None);
+        self.aborted = true;
+        Error::Something
+    }
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+    pub fn diagnostics(&self) -> &Vec<Diagnostic> {
+        &self.diagnostics
+    }
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        ::std::mem::replace(&mut self.diagnostics, Vec::new())
+    }
+
+    // the name of the current file changed by #line if any (for __FILE__ and diagnostics)
     pub fn get_filename(&self) -> String {
+        match *self.presumed_filename.back().unwrap() {
+            Some(ref filename) => filename.clone(),
+            None => self.get_real_filename(),
+        }
+    }
+    // the path of the current file as it was opened
+    fn get_real_filename(&self) -> String {
         self.filename.back().unwrap().to_owned()
     }
     pub fn get_cur_pos(&self) -> Pos {
-        Pos::new(*self.get_cur_line(), *self.peek_pos.back().unwrap())
+        let pos = *self.peek_pos.back().unwrap();
+        let mut cur = Pos::new(*self.get_cur_line(), pos);
+        cur.column = self.column_of(pos) + 1;
+        cur
+    }
+    // the column of a token read from the current file. the position of a token
+    // is just after its first character
+    fn column_of(&self, pos: usize) -> usize {
+        let code = self.peek.back().unwrap();
+        let pos = ::std::cmp::min(pos, code.len());
+        let start = code[..pos]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |p| p + 1);
+        pos - start
     }
     pub fn get_cur_line(&self) -> &usize {
         self.cur_line.back().unwrap()
@@ -305,11 +694,20 @@ impl Lexer {
         }
         Ok(c)
     }
+    // put back 'c' read by peek_next
+    fn peek_unget(&mut self, c: char) {
+        *self.peek_pos.back_mut().unwrap() -= 1;
+        if c == '\n' {
+            *self.cur_line.back_mut().unwrap() -= 1;
+        }
+    }
     fn peek_next_char_is(&mut self, ch: char) -> ParseR<bool> {
         let peek = self.peek.back_mut().unwrap();
         let peek_pos = self.peek_pos.back_mut().unwrap();
         if *peek_pos >= peek.len() {
             Err(Error::EOF)
+        } else if *peek_pos + 1 >= peek.len() {
+            Ok(false)
         } else {
             let nextc = peek[*peek_pos + 1] as char;
             Ok(nextc == ch)
@@ -375,7 +773,7 @@ impl Lexer {
             if c.is_alphanumeric() || c == '_' {
                 ident.push(c);
             } else {
-                *self.peek_pos.back_mut().unwrap() -= 1;
+                self.peek_unget(c);
                 break;
             }
         }
@@ -389,44 +787,59 @@ impl Lexer {
     fn read_number_literal(&mut self, c: char) -> ParseR<Token> {
         let mut num = "".to_string();
         num.push(c);
-        let mut is_float = false;
         let mut last = try!(self.peek_get());
         let pos = *self.peek_pos.back().unwrap();
         loop {
             let c = try!(self.peek_next());
             num.push(c);
-            is_float = is_float || c == '.';
             let is_f = "eEpP".contains(last) && "+-".contains(c);
             if !c.is_alphanumeric() && c != '.' && !is_f {
-                is_float = is_float || is_f;
                 num.pop();
-                *self.peek_pos.back_mut().unwrap() -= 1;
+                self.peek_unget(c);
                 break;
             }
             last = c;
         }
 
-        if is_float {
+        Ok(self.number_token(num, pos, *self.get_cur_line()))
+    }
+    // the token of the number literal 'num'
+    fn number_token(&mut self, num: String, pos: usize, line: usize) -> Token {
+        let kind = self.number_kind(num.as_str(), line);
+        let mut token = Token::new(kind, 0, pos, line);
+        token.spelling = Some(num);
+        token
+    }
+    fn number_kind(&mut self, num: &str, line: usize) -> TokenKind {
+        let is_hex = num.starts_with("0x") || num.starts_with("0X");
+        let exponent: &[char] = if is_hex { &['p', 'P'] } else { &['e', 'E'] };
+        if num.contains('.') || num.contains(exponent) {
             // TODO: now rucc ignores suffix
-            num = num.trim_right_matches(|c| match c {
+            let num = num.trim_right_matches(|c| match c {
                 'a'...'z' | 'A'...'Z' | '+' | '-' => true,
                 _ => false,
-            }).to_string();
-            let f: f64 = num.parse().unwrap();
-            Ok(Token::new(
-                TokenKind::FloatNumber(f),
-                0,
-                pos,
-                *self.get_cur_line(),
-            ))
+            });
+            let f: f64 = match num.parse() {
+                Ok(f) => f,
+                Err(_) => {
+                    self.add_error(line, format!("invalid number '{}'", num).as_str(), None);
+                    0.0
+                }
+            };
+            TokenKind::FloatNumber(f)
         } else {
             // TODO: suffix supporting
-            let i = if num.len() > 2 && num.chars().nth(1).unwrap() == 'x' {
-                self.read_hex_num(&num[2..]).0
+            let (i, suffix) = if num.len() > 2 && is_hex {
+                self.read_hex_num(&num[2..])
             } else if num.chars().nth(0).unwrap() == '0' {
-                self.read_oct_num(&num[1..]).0
+                self.read_oct_num(&num[1..])
+            } else {
+                self.read_dec_num(num)
+            };
+            let sign = if suffix.contains('u') || suffix.contains('U') {
+                Sign::Unsigned
             } else {
-                self.read_dec_num(num.as_str()).0
+                Sign::Signed
             };
 
             let max_32bits = 0xffffffff;
@@ -435,18 +848,13 @@ impl Lexer {
             } else {
                 Bits::Bits64
             };
-            Ok(Token::new(
-                TokenKind::IntNumber(i, bits),
-                0,
-                pos,
-                *self.get_cur_line(),
-            ))
+            TokenKind::IntNumber(i, bits, sign)
         }
     }
     fn read_dec_num(&mut self, num_literal: &str) -> (i64, String) {
         let mut suffix = "".to_string();
-        let n = num_literal.chars().fold(0, |n, c| match c {
-            '0'...'9' => n * 10 + c.to_digit(10).unwrap() as u64,
+        let n = num_literal.chars().fold(0u64, |n, c| match c {
+            '0'...'9' => n.wrapping_mul(10).wrapping_add(c.to_digit(10).unwrap() as u64),
             _ => {
                 suffix.push(c);
                 n
@@ -456,8 +864,8 @@ impl Lexer {
     }
     fn read_oct_num(&mut self, num_literal: &str) -> (i64, String) {
         let mut suffix = "".to_string();
-        let n = num_literal.chars().fold(0, |n, c| match c {
-            '0'...'7' => n * 8 + c.to_digit(8).unwrap() as u64,
+        let n = num_literal.chars().fold(0u64, |n, c| match c {
+            '0'...'7' => n.wrapping_mul(8).wrapping_add(c.to_digit(8).unwrap() as u64),
             _ => {
                 suffix.push(c);
                 n
@@ -467,8 +875,10 @@ impl Lexer {
     }
     fn read_hex_num(&mut self, num_literal: &str) -> (i64, String) {
         let mut suffix = "".to_string();
-        let n = num_literal.chars().fold(0, |n, c| match c {
-            '0'...'9' | 'A'...'F' | 'a'...'f' => n * 16 + c.to_digit(16).unwrap() as u64,
+        let n = num_literal.chars().fold(0u64, |n, c| match c {
+            '0'...'9' | 'A'...'F' | 'a'...'f' => {
+                n.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap() as u64)
+            }
             _ => {
                 suffix.push(c);
                 n
@@ -565,8 +975,8 @@ impl Lexer {
                     *self.peek_pos.back_mut().unwrap() -= 1;
                     Ok(self.read_oct_num(oct.as_str()).0 as i32 as u8 as char)
                 } else {
-                    assert!(c == '0');
-                    Ok('\x00')
+                    // one digit (e.g. '\0')
+                    Ok((c as u8 - b'0') as char)
                 }
             }
             _ => Ok(c),
@@ -600,15 +1010,15 @@ impl Lexer {
             }
         };
         if try!(self.peek_next()) != '\'' {
-            error::error_exit(
-                *self.cur_line.back().unwrap() as i32,
-                "missing terminating \' char",
-            );
+            return Err(self.fatal_error("missing terminating \' char"));
         }
         Ok(Token::new(TokenKind::Char(c), 0, pos, *self.get_cur_line()))
     }
 
     pub fn do_read_token(&mut self) -> ParseR<Token> {
+        if self.aborted {
+            return Err(Error::EOF);
+        }
         if let Some(tok) = self.buf.back_mut().unwrap().pop_back() {
             return Ok(tok);
         }
@@ -618,7 +1028,7 @@ impl Lexer {
 
         match self.peek_next() {
             Ok(c) => {
-                match c {
+                let tok = match c {
                     'a'...'z' | 'A'...'Z' | '_' => self.read_identifier(c),
                     ' ' | '\t' => {
                         self.do_read_token()
@@ -658,14 +1068,23 @@ impl Lexer {
                         }
                     }
                     _ => self.read_symbol(c),
-                }
+                };
+                tok.map(|mut tok| {
+                    if tok.pos.column == 0 {
+                        tok.pos.column = self.column_of(tok.pos.pos);
+                    }
+                    tok
+                })
             }
             _ => {
+                self.close_cond_groups();
                 if self.peek.len() > 1 {
                     self.peek.pop_back();
                     self.peek_pos.pop_back();
                     self.filename.pop_back();
                     self.cur_line.pop_back();
+                    self.include_dir_idx.pop_back();
+                    self.presumed_filename.pop_back();
                     self.do_read_token()
                 } else {
                     Err(Error::EOF)
@@ -687,7 +1106,10 @@ impl Lexer {
         let val = ident_val!(token);
 
         if val == "sizeof" {
-            return Token::new(TokenKind::Symbol(Symbol::Sizeof), 0, pos, line);
+            let mut sizeof = Token::new(TokenKind::Symbol(Symbol::Sizeof), 0, pos, line);
+            sizeof.space = token.space;
+            sizeof.pos.column = token.pos.column;
+            return sizeof;
         }
 
         let symbol = match val.as_str() {
@@ -741,7 +1163,10 @@ impl Lexer {
             _ => return token,
         };
 
-        Token::new(symbol, 0, pos, line)
+        let mut sym = Token::new(symbol, 0, pos, line);
+        sym.space = token.space;
+        sym.pos.column = token.pos.column;
+        sym
     }
     fn maybe_convert_to_keyword(&mut self, token: Token) -> Token {
         let pos = token.pos.pos;
@@ -788,7 +1213,10 @@ impl Lexer {
                 "return" => TokenKind::Keyword(Keyword::Return),
                 _ => return token,
             };
-            return Token::new(keyw, 0, pos, line);
+            let mut keyw = Token::new(keyw, 0, pos, line);
+            keyw.space = token.space;
+            keyw.pos.column = token.pos.column;
+            return keyw;
         }
         token
     }
@@ -799,7 +1227,7 @@ impl Lexer {
         name: String,
         macro_body: &Vec<Token>,
     ) -> ParseR<()> {
-        let body = macro_body
+        let mut body: Vec<Token> = macro_body
             .iter()
             .map(|tok| {
                 let mut t = tok.clone();
@@ -808,10 +1236,15 @@ impl Lexer {
                 t
             })
             .collect();
+        // the expansion is separated from the previous token as the macro name is (for -E)
+        if let Some(first) = body.first_mut() {
+            first.space = token.space;
+        }
         self.unget_all(&body);
         Ok(())
     }
-    fn read_one_arg(&mut self, end: &mut bool) -> ParseR<Vec<Token>> {
+    // the variable arguments of a variadic macro are read as one argument including commas
+    fn read_one_arg(&mut self, end: &mut bool, is_va_args: bool) -> ParseR<Vec<Token>> {
         let mut nest = 0;
         let mut arg = Vec::new();
         loop {
@@ -823,7 +1256,7 @@ impl Lexer {
                         *end = true;
                         break;
                     }
-                    "," => break,
+                    "," if !is_va_args => break,
                     _ => {}
                 }
             }
@@ -845,7 +1278,7 @@ impl Lexer {
                     if token.space { " " } else { "" },
                     match token.kind {
                         TokenKind::String(ref s) => format!("\"{}\"", s.as_str()),
-                        TokenKind::IntNumber(ref i, _) => format!("{}", *i),
+                        TokenKind::IntNumber(ref i, _, _) => format!("{}", *i),
                         TokenKind::FloatNumber(ref f) => format!("{}", *f),
                         TokenKind::Identifier(ref i) => format!("{}", *i),
                         TokenKind::Char(ref c) => format!("\'{}\'", *c),
@@ -858,29 +1291,103 @@ impl Lexer {
             .to_string();
         Token::new(TokenKind::String(string), 0, pos.pos, pos.line)
     }
+    // 'lhs ## rhs'. the spellings of the two tokens are joined and lexed again.
+    // if the result isn't a valid preprocessing token, it's an error and the two tokens are left
+    fn paste(&mut self, lhs: Token, rhs: &Token) -> Vec<Token> {
+        let (lhs_text, rhs_text) = (lhs.to_c_text(), rhs.to_c_text());
+        let text = format!("{}{}", lhs_text, rhs_text);
+        let (pos, line) = (lhs.pos.pos, lhs.pos.line);
+        let is_ident_char = |c: char| c.is_alphanumeric() || c == '_';
+        // a pp-number may have a sign after its exponent (e.g. 1e+5)
+        let is_number_char = |(last, c): (char, char)| {
+            is_ident_char(c) || c == '.' || ("eEpP".contains(last) && "+-".contains(c))
+        };
+        let mut pasted = lhs.clone();
+        match text.chars().next() {
+            None => return vec![],
+            Some('0'...'9') if text.chars().zip(text.chars().skip(1)).all(is_number_char) => {
+                let number = self.number_token(text, pos, line);
+                pasted.kind = number.kind;
+                pasted.spelling = number.spelling;
+            }
+            Some(c) if !c.is_digit(10) && text.chars().all(is_ident_char) => {
+                pasted.kind = TokenKind::Identifier(text);
+                pasted.spelling = None;
+            }
+            _ => {
+                let sym = Token::new(TokenKind::Identifier(text.clone()), 0, pos, line);
+                if self.convert_to_symbol(sym).kind == TokenKind::Identifier(text.clone()) {
+                    let msg = format!(
+                        "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                        lhs_text, rhs_text
+                    );
+                    self.add_error(line, msg.as_str(), None);
+                    return vec![lhs, rhs.clone()];
+                }
+                pasted.kind = TokenKind::Identifier(text);
+                pasted.spelling = None;
+            }
+        }
+        vec![pasted]
+    }
     fn expand_func_macro(
         &mut self,
         token: Token,
         name: String,
         macro_body: &Vec<Token>,
+        params: usize,
+        va_position: Option<usize>,
     ) -> ParseR<()> {
         // expect '(', self.skip can't be used because self.skip uses 'self.get' that uses MACRO_MAP with Mutex
         let expect_bracket = try!(self.read_token());
         if expect_bracket.kind != TokenKind::Symbol(Symbol::OpeningParen) {
-            error::error_exit(*self.get_cur_line() as i32, "expected '('");
+            return Err(self.fatal_error("expected '('"));
         }
 
         let mut args = Vec::new();
         let mut end = false;
         while !end {
-            args.push(try!(self.read_one_arg(&mut end)));
+            let is_va_args = va_position == Some(args.len());
+            args.push(try!(self.read_one_arg(&mut end, is_va_args)));
+        }
+
+        // 'F()' is one empty argument, which is fine for a macro without parameters
+        let given = if params == 0 && args.len() == 1 && args[0].is_empty() {
+            0
+        } else {
+            args.len()
+        };
+        // the variable arguments may be omitted
+        let required = if va_position.is_some() { params - 1 } else { params };
+        if given < required || (va_position.is_none() && given > params) {
+            let msg = if given < required {
+                format!(
+                    "macro '{}' requires {} arguments, but only {} given",
+                    name, required, given
+                )
+            } else {
+                format!("macro '{}' passed {} arguments, but takes just {}", name, given, params)
+            };
+            self.add_error(token.pos.line, msg.as_str(), None);
+            return Ok(());
         }
 
+        let macro_body = match va_position {
+            Some(position) => {
+                // the variable arguments may be omitted (e.g. 'LOG("msg")' for 'LOG(fmt, ...)')
+                while args.len() <= position {
+                    args.push(Vec::new());
+                }
+                replace_va_opt(macro_body, !args[position].is_empty())
+            }
+            None => macro_body.clone(),
+        };
+
         let mut expanded = Vec::new();
         let mut is_stringize = false;
         let mut is_combine = false;
         // TODO: refine code
-        for macro_tok in macro_body {
+        for macro_tok in &macro_body {
             if ident_val!(macro_tok) == "#" {
                 if is_stringize {
                     // means ##
@@ -900,11 +1407,31 @@ impl Lexer {
                     expanded.push(stringized);
                     is_stringize = false;
                 } else if is_combine {
-                    let mut last = expanded.pop().unwrap();
-                    for t in &args[position] {
-                        *ident_mut_val!(last) += ident_val!(t).as_str();
+                    match expanded.pop() {
+                        // GNU ', ## __VA_ARGS__': the comma is removed if no variable
+                        // arguments are given. otherwise nothing is pasted
+                        Some(ref comma)
+                            if va_position == Some(position) && ident_val!(comma) == "," =>
+                        {
+                            if !args[position].is_empty() {
+                                expanded.push(comma.clone());
+                                expanded.extend(args[position].iter().cloned());
+                            }
+                        }
+                        Some(last) => {
+                            let mut arg = args[position].iter();
+                            match arg.next() {
+                                Some(first) => {
+                                    let pasted = self.paste(last, first);
+                                    expanded.extend(pasted);
+                                }
+                                None => expanded.push(last),
+                            }
+                            expanded.extend(arg.cloned());
+                        }
+                        // '##' at the beginning of the body
+                        None => expanded.extend(args[position].iter().cloned()),
                     }
-                    expanded.push(last);
                     is_combine = false;
                 } else {
                     self.buf.push_back(VecDeque::new());
@@ -920,9 +1447,14 @@ impl Lexer {
                 }
             } else {
                 if is_combine {
-                    let mut last = expanded.pop().unwrap();
-                    *ident_mut_val!(last) += ident_val!(macro_tok).as_str();
-                    expanded.push(last);
+                    match expanded.pop() {
+                        Some(last) => {
+                            let pasted = self.paste(last, macro_tok);
+                            expanded.extend(pasted);
+                        }
+                        None => expanded.push(macro_tok.clone()),
+                    }
+                    is_combine = false;
                 } else {
                     expanded.push(macro_tok.clone());
                 }
@@ -933,31 +1465,92 @@ impl Lexer {
             tok.add_hideset(name.to_string());
             tok.pos = token.pos.clone();
         }
+        if let Some(first) = expanded.first_mut() {
+            first.space = token.space;
+        }
 
         self.unget_all(&expanded);
         Ok(())
     }
+    // the value of the builtin macro 'tok', or None if it's not a builtin macro
+    fn expand_builtin_macro(&mut self, tok: &Token) -> Option<TokenKind> {
+        let int = |n: i64| TokenKind::IntNumber(n, Bits::Bits32, Sign::Signed);
+        Some(match ident_val!(tok).as_str() {
+            "__LINE__" => int(*self.get_cur_line() as i64),
+            "__FILE__" => TokenKind::String(self.get_filename()),
+            "__BASE_FILE__" => TokenKind::String(self.base_file.clone()),
+            // 0 in the source file
+            "__INCLUDE_LEVEL__" => int(self.peek.len() as i64 - 1),
+            "__COUNTER__" => {
+                self.counter += 1;
+                int(self.counter - 1)
+            }
+            "__DATE__" => TokenKind::String(self.get_date_time().0),
+            "__TIME__" => TokenKind::String(self.get_date_time().1),
+            // left for read_intexpr_line if it comes from a macro in #if (e.g. glibc's
+            // '#define __glibc_has_attribute(attr) __has_attribute (attr)')
+            "__has_include" | "__has_include_next" | "__has_builtin" | "__has_attribute"
+                if self.in_if_expr =>
+            {
+                return None
+            }
+            name @ "__has_include"
+            | name @ "__has_include_next"
+            | name @ "__has_builtin"
+            | name @ "__has_attribute" => {
+                let msg = format!("'{}' can only be used in #if and #elif", name);
+                self.add_error_at(&tok.pos, msg.as_str());
+                int(0)
+            }
+            _ => return None,
+        })
+    }
+    // ("Mmm dd yyyy", "hh:mm:ss") of the time the compilation started.
+    // SOURCE_DATE_EPOCH (seconds since the epoch in UTC) overrides it for reproducible builds
+    fn get_date_time(&mut self) -> (String, String) {
+        if let Some(ref date_time) = self.date_time {
+            return date_time.clone();
+        }
+        let epoch = match env::var("SOURCE_DATE_EPOCH") {
+            Ok(val) => match val.trim().parse::<i64>() {
+                Ok(secs) if 0 <= secs && secs <= 253402300799 => Some(secs),
+                _ => {
+                    self.diagnostics.push(Diagnostic::without_pos(
+                        "environment variable SOURCE_DATE_EPOCH must expand to a non-negative \
+                         integer less than or equal to 253402300799",
+                    ));
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let tm = unsafe {
+            let mut tm: libc::tm = mem::zeroed();
+            match epoch {
+                Some(secs) => libc::gmtime_r(&(secs as libc::time_t), &mut tm),
+                None => libc::localtime_r(&libc::time(::std::ptr::null_mut()), &mut tm),
+            };
+            tm
+        };
+        let date_time = (
+            format!(
+                "{} {:2} {}",
+                MONTHS[tm.tm_mon as usize],
+                tm.tm_mday,
+                tm.tm_year + 1900
+            ),
+            format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec),
+        );
+        self.date_time = Some(date_time.clone());
+        date_time
+    }
     fn expand(&mut self, token: ParseR<Token>) -> ParseR<Token> {
         token.and_then(|tok| {
             let name = ident_val!(tok);
-            match name.as_str() {
-                "__LINE__" => {
-                    return Ok(Token::new(
-                        TokenKind::IntNumber(*self.get_cur_line() as i64, Bits::Bits32),
-                        0,
-                        tok.pos.pos,
-                        tok.pos.line,
-                    ))
-                }
-                "__FILE__" => {
-                    return Ok(Token::new(
-                        TokenKind::String(self.get_filename()),
-                        0,
-                        tok.pos.pos,
-                        tok.pos.line,
-                    ))
-                }
-                _ => {}
+            if let Some(kind) = self.expand_builtin_macro(&tok) {
+                let mut expanded = Token::new(kind, 0, tok.pos.pos, tok.pos.line);
+                expanded.space = tok.space;
+                return Ok(expanded);
             }
             if tok.hideset.contains(name.as_str()) || !self.macro_map.contains_key(name.as_str()) {
                 Ok(tok)
@@ -965,7 +1558,9 @@ impl Lexer {
                 // if cur token is macro:
                 try!(match self.macro_map.get(name.as_str()).unwrap().clone() {
                     Macro::Object(ref body) => self.expand_obj_macro(tok, name, body),
-                    Macro::FuncLike(ref body) => self.expand_func_macro(tok, name, body),
+                    Macro::FuncLike(ref body, params, va_position) => {
+                        self.expand_func_macro(tok, name, body, params, va_position)
+                    }
                 });
                 self.get_token()
             }
@@ -1001,6 +1596,12 @@ impl Lexer {
         })
     }
 
+    // for -E: the same as get() but adjacent string literals are not joined,
+    // since they are concatenated in translation phase 6, after preprocessing
+    pub fn get_preprocessed(&mut self) -> ParseR<Token> {
+        self.get_token()
+    }
+
     pub fn peek(&mut self) -> ParseR<Token> {
         self.get_token().and_then(|tok| {
             let conv = self.maybe_convert_to_keyword(tok);
@@ -1014,7 +1615,8 @@ impl Lexer {
     fn read_cpp_directive(&mut self) -> ParseR<()> {
         let tok = self.do_read_token(); // cpp directive
         tok.and_then(|t| match ident_val!(t).as_str() {
-            "include" => self.read_include(),
+            "include" => self.read_include(false),
+            "include_next" => self.read_include(true),
             "define" => self.read_define(),
             "undef" => self.read_undef(),
             "if" => self.read_if(),
@@ -1022,73 +1624,245 @@ impl Lexer {
             "ifndef" => self.read_ifndef(),
             "elif" => self.read_elif(),
             "else" => self.read_else(),
-            _ => Ok(()),
+            "endif" => self.read_endif(),
+            "error" => self.read_error(),
+            "warning" => self.read_warning(),
+            "pragma" => self.read_pragma(),
+            "line" => self.read_line(false),
+            // the null directive
+            _ if t.kind == TokenKind::Newline => Ok(()),
+            // '# N "file" flags...' written by -E
+            _ if matches!(t.kind, TokenKind::IntNumber(..)) => {
+                self.unget(t);
+                self.read_line(true)
+            }
+            _ => {
+                let line = *self.get_cur_line();
+                let msg = format!("invalid preprocessing directive #{}", t.to_c_text());
+                self.add_error(line, msg.as_str(), None);
+                try!(self.read_rest_of_line());
+                Ok(())
+            }
         })
     }
 
-    fn try_include(&mut self, filename: &str) -> Option<String> {
-        let header_paths = vec![
-            "./include/",
-            "/include/",
-            "/usr/include/",
-            "/usr/include/linux/",
-            "/usr/include/x86_64-linux-gnu/",
-            "./include/",
-            "",
-        ];
-        header_paths
-            .iter()
-            .find(|header_path| {
-                let abs_filename = format!("{}{}", header_path, filename);
-                path::Path::new(abs_filename.as_str()).exists()
-            })
-            .and_then(|a| Some(a.to_string() + filename))
+    // the rest of the directive line as it's written. the newline is left
+    // the text of the line as it is (for #error, #warning and #pragma).
+    // a comment becomes a space and a backslash-newline joins the next line
+    fn read_rest_of_line(&mut self) -> ParseR<String> {
+        let mut text = "".to_string();
+        let mut quote = None;
+        loop {
+            let c = match self.peek_get() {
+                Ok('\n') | Err(_) => break,
+                Ok(c) => c,
+            };
+            try!(self.peek_next());
+            match c {
+                '\\' if self.peek_char_is('\n').unwrap_or(false) => {
+                    try!(self.peek_next());
+                }
+                '\\' if quote.is_some() => {
+                    text.push(c);
+                    match self.peek_get() {
+                        Ok('\n') | Err(_) => {}
+                        Ok(c) => {
+                            try!(self.peek_next());
+                            text.push(c);
+                        }
+                    }
+                }
+                '"' | '\'' if quote.is_none() => {
+                    quote = Some(c);
+                    text.push(c);
+                }
+                c if quote == Some(c) => {
+                    quote = None;
+                    text.push(c);
+                }
+                '/' if quote.is_none() && self.peek_char_is('*').unwrap_or(false) => {
+                    try!(self.peek_next()); // *
+                    let mut last = ' ';
+                    loop {
+                        let c = match self.peek_next() {
+                            Ok(c) => c,
+                            Err(_) => break,
+                        };
+                        if last == '*' && c == '/' {
+                            break;
+                        }
+                        last = c;
+                    }
+                    text.push(' ');
+                }
+                '/' if quote.is_none() && self.peek_char_is('/').unwrap_or(false) => {
+                    while !self.peek_char_is('\n').unwrap_or(true) {
+                        try!(self.peek_next());
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+        Ok(text.trim().to_string())
     }
-    fn read_headerfile_name(&mut self) -> ParseR<String> {
-        let mut name = "".to_string();
-        // Lt = '<'
-        if try!(self.skip_symbol(Symbol::Lt)) {
+    fn read_error(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
+        let msg = format!("#error {}", try!(self.read_rest_of_line()));
+        self.add_error(line, msg.as_str(), None);
+        Ok(())
+    }
+    fn read_warning(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
+        let msg = format!("#warning {}", try!(self.read_rest_of_line()));
+        self.add_warning(line, msg.as_str());
+        Ok(())
+    }
+    // '#pragma once'. other pragmas are ignored
+    fn read_pragma(&mut self) -> ParseR<()> {
+        if try!(self.read_rest_of_line()) == "once" {
+            let filename = canonical_path(self.get_real_filename().as_str());
+            self.pragma_once.insert(filename);
+        }
+        Ok(())
+    }
+    // '#line N' or '#line N "file"'. the line after the directive is reported as line N (of 'file')
+    // a line marker ('# N "file" flags...') may have flags after the file name, which are ignored
+    fn read_line(&mut self, is_marker: bool) -> ParseR<()> {
+        let line = *self.get_cur_line();
+        let mut args = Vec::new();
+        loop {
+            let tok = try!(self.do_read_token());
+            if tok.kind == TokenKind::Newline {
+                break;
+            }
+            let tok = try!(self.expand(Ok(tok)));
+            if tok.kind == TokenKind::Newline {
+                break;
+            }
+            args.push(tok);
+        }
+        if is_marker {
+            args.truncate(2);
+        }
+        let new_line = match args.get(0).map(|tok| &tok.kind) {
+            Some(&TokenKind::IntNumber(n, _, _)) if n > 0 => n as usize,
+            _ => {
+                self.add_error(line, "#line directive requires a positive integer argument", None);
+                return Ok(());
+            }
+        };
+        match args.get(1).map(|tok| &tok.kind) {
+            Some(&TokenKind::String(ref filename)) if args.len() == 2 => {
+                *self.presumed_filename.back_mut().unwrap() = Some(filename.clone());
+            }
+            None => {}
+            _ => {
+                self.add_error(line, "invalid filename for #line directive", None);
+                return Ok(());
+            }
+        }
+        *self.cur_line.back_mut().unwrap() = new_line;
+        Ok(())
+    }
+
+    fn try_include(
+        &mut self,
+        filename: &str,
+        quoted: bool,
+        is_next: bool,
+    ) -> Option<(String, Option<usize>)> {
+        let current_dir = dirname(self.get_real_filename().as_str());
+        // #include_next starts searching from the directory next to where the current file was found
+        let start = if is_next {
+            self.include_dir_idx.back().unwrap().map(|idx| idx + 1)
+        } else {
+            None
+        };
+        self.include_path
+            .search(
+                filename,
+                quoted,
+                Some(current_dir.as_str()),
+                start,
+                &*self.files,
+            )
+    }
+    // returns (header file name, whether the name is quoted by double quotes)
+    fn read_headerfile_name(&mut self) -> ParseR<(String, bool)> {
+        // read a raw token here. Lexer::get() looks ahead of a string literal
+        // (for concatenation) and would leave the next line's token in the buffer.
+        let tok = try!(self.do_read_token());
+        if tok.kind == TokenKind::Identifier("<".to_string()) {
+            let mut name = "".to_string();
             while !try!(self.peek_char_is('>')) {
                 name.push(try!(self.peek_next()));
             }
             try!(self.peek_next()); // >
+            Ok((name, false))
+        } else if let TokenKind::String(name) = tok.kind {
+            Ok((name, true))
         } else {
-            let tok = try!(self.do_read_token());
-            if let TokenKind::String(s) = tok.kind {
-                println!("sorry, using \"double quote\" in #include is currently not supported.");
-                name = s;
-            } else {
-                error::error_exit(*self.get_cur_line() as i32, "expected '<' or '\"'");
-            }
+            Err(self.fatal_error("expected '<' or '\"'"))
         }
-        Ok(name)
     }
-    fn read_include(&mut self) -> ParseR<()> {
+    // a header is a system header if it's found in a system directory,
+    // or found next to a system header
+    fn record_included_file(&mut self, filename: &str, dir_idx: Option<usize>) {
+        // the built-in headers are not real files
+        if filename.starts_with(BUILTIN_INCLUDE_DIR)
+            || self.included_files.iter().any(|&(ref f, _)| f == filename)
+        {
+            return;
+        }
+        let is_system = match dir_idx {
+            Some(idx) => self.include_path.is_system_dir(idx),
+            None => {
+                let cur_filename = self.get_real_filename();
+                self.included_files
+                    .iter()
+                    .any(|&(ref f, is_system)| *f == cur_filename && is_system)
+            }
+        };
+        self.included_files.push((filename.to_string(), is_system));
+    }
+    // the headers opened so far in the order of inclusion. system headers are omitted unless
+    // 'system_headers' is true
+    pub fn get_included_files(&self, system_headers: bool) -> Vec<String> {
+        self.included_files
+            .iter()
+            .filter(|&&(_, is_system)| system_headers || !is_system)
+            .map(|&(ref filename, _)| filename.clone())
+            .collect()
+    }
+
+    fn read_include(&mut self, is_next: bool) -> ParseR<()> {
         // this will be a function
-        let filename = try!(self.read_headerfile_name());
-        let abs_filename = self.try_include(filename.as_str())
-            .or_else(|| {
-                println!("error: {}: not found '{}'", *self.get_cur_line(), filename);
-                process::exit(-1);
-            })
-            .unwrap();
+        let (filename, quoted) = try!(self.read_headerfile_name());
+        let (abs_filename, dir_idx) = match self.try_include(filename.as_str(), quoted, is_next) {
+            Some(found) => found,
+            None => return Err(self.fatal_error(format!("not found '{}'", filename).as_str())),
+        };
         // DEBUG: println!("include filename: {}", abs_filename);
+        if self.pragma_once.contains(&canonical_path(abs_filename.as_str())) {
+            return Ok(());
+        }
 
-        let mut include_file = OpenOptions::new()
-            .read(true)
-            .open(abs_filename.to_string())
-            .unwrap();
-        let mut body = String::with_capacity(512);
-        include_file
-            .read_to_string(&mut body)
-            .ok()
-            .expect("not found file");
+        let mut body = match self.files.read(abs_filename.as_str()) {
+            Ok(body) => body,
+            Err(e) => {
+                let msg = format!("cannot open '{}': {}", abs_filename, e);
+                return Err(self.fatal_error(msg.as_str()));
+            }
+        };
+        self.record_included_file(abs_filename.as_str(), dir_idx);
         self.filename.push_back(abs_filename);
         unsafe {
             self.peek.push_back(body.as_mut_vec().clone());
         }
         self.peek_pos.push_back(0);
         self.cur_line.push_back(1);
+        self.include_dir_idx.push_back(dir_idx);
+        self.presumed_filename.push_back(None);
         Ok(())
     }
 
@@ -1113,17 +1887,34 @@ impl Lexer {
         // read macro arguments
         let mut params = HashMap::new();
         let mut count = 0usize;
+        let mut va_position = None;
         loop {
             let mut arg = ident_val!(try!(self.do_read_token()));
             if arg == ")" {
                 break;
             }
+            if va_position.is_some() {
+                return Err(self.fatal_error("expected ')' after '...'"));
+            }
             if count > 0 {
                 if arg != "," {
-                    error::error_exit(*self.get_cur_line() as i32, "expected comma");
+                    return Err(self.fatal_error("expected comma"));
                 }
                 arg = ident_val!(try!(self.do_read_token()));
             }
+            if arg == "..." {
+                // the variable arguments are referred to as __VA_ARGS__
+                arg = "__VA_ARGS__".to_string();
+                va_position = Some(count);
+            } else {
+                // GNU named variable arguments: 'args...'
+                let next = try!(self.do_read_token());
+                if ident_val!(next) == "..." {
+                    va_position = Some(count);
+                } else {
+                    self.unget(next);
+                }
+            }
             params.insert(arg, count);
             count += 1;
         }
@@ -1141,6 +1932,14 @@ impl Lexer {
             //  and set macro_position
             let maybe_macro_name = ident_val!(tok);
             // print!("{}{}", if tok.space { " " } else { "" }, tok.val);
+            if maybe_macro_name == "__VA_ARGS__" && !params.contains_key("__VA_ARGS__") {
+                let line = tok.pos.line;
+                self.add_error(
+                    line,
+                    "__VA_ARGS__ can only appear in the expansion of a variadic macro",
+                    None,
+                );
+            }
             if params.contains_key(maybe_macro_name.as_str()) {
                 let mut macro_param = tok;
                 macro_param.kind = TokenKind::MacroParam;
@@ -1150,12 +1949,15 @@ impl Lexer {
                 body.push(tok);
             }
         }
-        self.register_funclike_macro(name, body);
+        self.register_funclike_macro(name, body, count, va_position);
         Ok(())
     }
     fn read_define(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
         let mcro = try!(self.do_read_token());
-        assert!(matches!(mcro.kind, TokenKind::Identifier(_)));
+        if !try!(self.check_macro_name(&mcro, line)) {
+            return Ok(());
+        }
         // println!("define: {}", mcro.val);
 
         let t = try!(self.do_read_token());
@@ -1167,181 +1969,515 @@ impl Lexer {
         }
     }
     fn read_undef(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
         let mcro = try!(self.do_read_token());
-        assert!(matches!(mcro.kind, TokenKind::Identifier(_)));
+        if !try!(self.check_macro_name(&mcro, line)) {
+            return Ok(());
+        }
         self.macro_map.remove(ident_val!(mcro).as_str());
         Ok(())
     }
 
+    // builtin macros are always defined
+    // the name of #define and #undef. if it's not an identifier, the line is skipped
+    fn check_macro_name(&mut self, tok: &Token, line: usize) -> ParseR<bool> {
+        let is_ident = match tok.kind {
+            TokenKind::Identifier(ref name) => {
+                name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            }
+            _ => false,
+        };
+        if !is_ident {
+            let msg = if tok.kind == TokenKind::Newline {
+                "no macro name given"
+            } else {
+                "macro names must be identifiers"
+            };
+            self.add_error(line, msg, None);
+            if tok.kind != TokenKind::Newline {
+                try!(self.read_rest_of_line());
+            }
+        }
+        Ok(is_ident)
+    }
+    fn is_defined(&self, name: &str) -> bool {
+        self.macro_map.contains_key(name) || BUILTIN_MACROS.contains(&name)
+    }
+
     fn register_obj_macro(&mut self, name: String, body: Vec<Token>) {
         self.macro_map.insert(name, Macro::Object(body));
     }
-    fn register_funclike_macro(&mut self, name: String, body: Vec<Token>) {
-        self.macro_map.insert(name, Macro::FuncLike(body));
+    fn register_funclike_macro(
+        &mut self,
+        name: String,
+        body: Vec<Token>,
+        params: usize,
+        va_position: Option<usize>,
+    ) {
+        self.macro_map.insert(name, Macro::FuncLike(body, params, va_position));
     }
 
-    fn read_defined_op(&mut self) -> ParseR<Token> {
+    // 1 or 0 at the position of 'op' in a #if line
+    fn bool_token(&self, b: bool, op: &Token) -> Token {
+        Token::new(
+            TokenKind::IntNumber(b as i64, Bits::Bits32, Sign::Signed),
+            0,
+            op.pos.pos,
+            op.pos.line,
+        )
+    }
+    // record an error with the source line pointing at 'pos'
+    fn add_error_at(&mut self, pos: &Pos, msg: &str) {
+        let code = self.get_surrounding_code_with_err_point(pos.pos);
+        self.add_error(pos.line, msg, Some(code));
+    }
+    // 'defined X' or 'defined(X)'
+    fn read_defined_op(&mut self, op: &Token) -> ParseR<Token> {
         let mut tok = try!(self.do_read_token());
-        if ident_val!(tok) == "(" {
+        let has_paren = ident_val!(tok) == "(";
+        if has_paren {
             tok = try!(self.do_read_token());
-            try!(self.expect_skip_symbol(Symbol::ClosingParen));
-        }
-        if self.macro_map.contains_key(ident_val!(tok).as_str()) {
-            Ok(Token::new(
-                TokenKind::IntNumber(1, Bits::Bits32),
-                0,
-                0,
-                *self.get_cur_line(),
-            ))
-        } else {
-            Ok(Token::new(
-                TokenKind::IntNumber(0, Bits::Bits32),
-                0,
-                0,
-                *self.get_cur_line(),
-            ))
         }
-    }
-    fn read_intexpr_line(&mut self) -> ParseR<Vec<Token>> {
-        let mut v = Vec::new();
+        let name = ident_val!(tok);
+        if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            self.add_error_at(&op.pos, "'defined' requires an identifier");
+            // the newline ends the #if line
+            self.unget(tok);
+            return Ok(self.bool_token(false, op));
+        }
+        if has_paren {
+            let closing = try!(self.do_read_token());
+            if ident_val!(closing) != ")" {
+                self.add_error_at(&op.pos, "missing ')' after 'defined'");
+                self.unget(closing);
+            }
+        }
+        let is_defined = self.is_defined(name.as_str());
+        Ok(self.bool_token(is_defined, op))
+    }
+    // '__has_include(<foo.h>)', '__has_include("foo.h")' and __has_include_next
+    fn read_has_include_op(&mut self, op: &Token, is_next: bool) -> ParseR<Token> {
+        let opening = try!(self.do_read_token());
+        if ident_val!(opening) != "(" {
+            let msg = format!("missing '(' after '{}'", ident_val!(op));
+            self.add_error_at(&op.pos, msg.as_str());
+            self.unget(opening);
+            return Ok(self.bool_token(false, op));
+        }
+        let (filename, quoted) = try!(self.read_headerfile_name());
+        let closing = try!(self.do_read_token());
+        if ident_val!(closing) != ")" {
+            let msg = format!("missing ')' after '{}'", ident_val!(op));
+            self.add_error_at(&op.pos, msg.as_str());
+            self.unget(closing);
+        }
+        let found = self.try_include(filename.as_str(), quoted, is_next).is_some();
+        Ok(self.bool_token(found, op))
+    }
+    // '__has_builtin(name)' and '__has_attribute(name)'.
+    // rucc ignores attributes, so '__has_attribute' is always 0
+    fn read_has_feature_op(&mut self, op: &Token) -> ParseR<Token> {
+        let opening = try!(self.do_read_token());
+        if ident_val!(opening) != "(" {
+            let msg = format!("missing '(' after '{}'", ident_val!(op));
+            self.add_error_at(&op.pos, msg.as_str());
+            self.unget(opening);
+            return Ok(self.bool_token(false, op));
+        }
+        // the name may be scoped, e.g. 'gnu::packed'
+        let mut name = "".to_string();
         loop {
-            let mut tok = try!(self.do_read_token());
-            tok = try!(self.expand(Ok(tok)));
-            if tok.kind == TokenKind::Newline {
-                break;
+            let tok = try!(self.do_read_token());
+            match ident_val!(tok).as_str() {
+                ")" => break,
+                _ if tok.kind == TokenKind::Newline => {
+                    let msg = format!("missing ')' after '{}'", ident_val!(op));
+                    self.add_error_at(&op.pos, msg.as_str());
+                    self.unget(tok);
+                    break;
+                }
+                ident => name.push_str(ident),
             }
-
-            tok = self.convert_to_symbol(tok);
-            match tok.kind {
-                TokenKind::Identifier(ident) => {
-                    if ident == "defined" {
-                        v.push(try!(self.read_defined_op()));
-                    } else {
-                        // identifier in expr line is replaced with 0i
-                        v.push(Token::new(
-                            TokenKind::IntNumber(0, Bits::Bits32),
-                            0,
-                            0,
-                            *self.get_cur_line(),
-                        ));
+        }
+        let found = is_builtin_func(ident_val!(op).as_str(), name.as_str());
+        Ok(self.bool_token(found, op))
+    }
+    // '__has_include(...)' and the like that come from macro expansion in #if.
+    // their arguments are already expanded tokens, so they are read from 'tokens'
+    fn eval_expanded_query_ops(&mut self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut result = Vec::new();
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(tok) = tokens.next() {
+            let name = ident_val!(tok);
+            match name.as_str() {
+                "__has_include" | "__has_include_next" | "__has_builtin" | "__has_attribute" => {}
+                _ => {
+                    result.push(tok);
+                    continue;
+                }
+            }
+            let opening = TokenKind::Symbol(Symbol::OpeningParen);
+            if tokens.peek().map_or(true, |next| next.kind != opening) {
+                let msg = format!("missing '(' after '{}'", name);
+                self.add_error_at(&tok.pos, msg.as_str());
+                result.push(self.bool_token(false, &tok));
+                continue;
+            }
+            tokens.next(); // (
+            let mut args = Vec::new();
+            let mut depth = 0;
+            let mut closed = false;
+            while let Some(arg) = tokens.next() {
+                if arg.kind == TokenKind::Symbol(Symbol::OpeningParen) {
+                    depth += 1;
+                } else if arg.kind == TokenKind::Symbol(Symbol::ClosingParen) {
+                    if depth == 0 {
+                        closed = true;
+                        break;
                     }
+                    depth -= 1;
                 }
-                _ => v.push(tok),
+                args.push(arg);
+            }
+            if !closed {
+                let msg = format!("missing ')' after '{}'", name);
+                self.add_error_at(&tok.pos, msg.as_str());
             }
+            let found = match name.as_str() {
+                "__has_include" | "__has_include_next" => {
+                    match self.header_name_of_tokens(&args) {
+                        Some((filename, quoted)) => {
+                            let is_next = name == "__has_include_next";
+                            self.try_include(filename.as_str(), quoted, is_next).is_some()
+                        }
+                        None => {
+                            let msg =
+                                format!("expected \"FILENAME\" or <FILENAME> after '{}'", name);
+                            self.add_error_at(&tok.pos, msg.as_str());
+                            false
+                        }
+                    }
+                }
+                // see read_has_feature_op
+                _ => {
+                    let arg_name = args.iter().map(|arg| ident_val!(arg)).collect::<String>();
+                    is_builtin_func(name.as_str(), arg_name.as_str())
+                }
+            };
+            result.push(self.bool_token(found, &tok));
         }
-        Ok(v)
+        result
     }
-    fn read_constexpr(&mut self) -> ParseR<bool> {
-        let expr_line = try!(self.read_intexpr_line());
-        self.buf.push_back(VecDeque::new());
-
-        self.unget(Token::new(TokenKind::Symbol(Symbol::Semicolon), 0, 0, 0));
-        self.unget_all(&expr_line);
-
-        let node = parser::Parser::new(self).run_as_expr().ok().unwrap();
+    // the header name made of '"name"' or '<' tokens '>'
+    fn header_name_of_tokens(&self, tokens: &Vec<Token>) -> Option<(String, bool)> {
+        match tokens.first().map(|tok| &tok.kind) {
+            Some(&TokenKind::String(ref name)) if tokens.len() == 1 => {
+                return Some((name.clone(), true))
+            }
+            _ => {}
+        }
+        if tokens.len() < 2 || tokens[0].kind != TokenKind::Symbol(Symbol::Lt)
+            || tokens[tokens.len() - 1].kind != TokenKind::Symbol(Symbol::Gt)
+        {
+            return None;
+        }
+        let mut name = "".to_string();
+        for (i, tok) in tokens[1..tokens.len() - 1].iter().enumerate() {
+            if i > 0 && tok.space {
+                name.push(' ');
+            }
+            name.push_str(tok.to_c_text().as_str());
+        }
+        Some((name, false))
+    }
+    // the tokens of a #if line after macro expansion, and the position of the end of the line.
+    // 'defined' and '__has_include' are replaced with 1 or 0 before macros are expanded,
+    // and the identifiers left after the expansion are replaced with 0
+    fn read_intexpr_line(&mut self) -> ParseR<(Vec<Token>, Pos)> {
+        let mut line = Vec::new();
+        let end = loop {
+            let tok = try!(self.do_read_token());
+            if tok.kind == TokenKind::Newline {
+                // the newline token is put at the beginning of the next line
+                break Pos::new(tok.pos.line - 1, tok.pos.pos - 1);
+            }
+            match ident_val!(tok).as_str() {
+                "defined" => line.push(try!(self.read_defined_op(&tok))),
+                "__has_include" => line.push(try!(self.read_has_include_op(&tok, false))),
+                "__has_include_next" => line.push(try!(self.read_has_include_op(&tok, true))),
+                "__has_builtin" | "__has_attribute" => {
+                    line.push(try!(self.read_has_feature_op(&tok)))
+                }
+                "L" | "u" | "U" | "u8" => {
+                    // a wide character constant (e.g. L'\0') has the value of the character
+                    let next = try!(self.do_read_token());
+                    match next.kind {
+                        TokenKind::Char(c) if !next.space => line.push(Token::new(
+                            TokenKind::IntNumber(c as i64, Bits::Bits32, Sign::Signed),
+                            0,
+                            tok.pos.pos,
+                            tok.pos.line,
+                        )),
+                        _ => {
+                            self.unget(next);
+                            line.push(tok);
+                        }
+                    }
+                }
+                _ => line.push(tok),
+            }
+        };
 
+        // macros are expanded in a buffer of their own, so the expansion doesn't go beyond the line
+        self.buf.push_back(VecDeque::new());
+        self.unget_all(&line);
+        self.in_if_expr = true;
+        let mut expanded = Vec::new();
+        loop {
+            match self.get_token() {
+                Ok(tok) => expanded.push(tok),
+                Err(Error::EOF) => break,
+                Err(e) => {
+                    self.in_if_expr = false;
+                    self.buf.pop_back();
+                    return Err(e);
+                }
+            }
+        }
+        self.in_if_expr = false;
         self.buf.pop_back();
 
-        if let Ok(e) = node.eval_constexpr() {
-            Ok(e != 0)
-        } else {
-            println!("error: lexer constexpr");
-            Err(Error::Something)
+        let expanded = self
+            .eval_expanded_query_ops(expanded)
+            .into_iter()
+            .map(|tok| match tok.kind {
+                TokenKind::Identifier(_) => self.bool_token(false, &tok),
+                _ => tok,
+            })
+            .collect();
+        Ok((expanded, end))
+    }
+    // an invalid expression is reported and regarded as false
+    fn read_constexpr(&mut self) -> ParseR<bool> {
+        let (expr_line, end) = try!(self.read_intexpr_line());
+        match cpp_expr::eval(&expr_line, end, self.target.char_unsigned) {
+            Ok(value) => Ok(value.is_true()),
+            Err(err) => {
+                self.add_error_at(&err.pos, err.msg.as_str());
+                Ok(false)
+            }
         }
     }
 
-    fn do_read_if(&mut self, cond: bool) -> ParseR<()> {
-        self.cond_stack.push(cond);
+    fn do_read_if(&mut self, cond: bool, line: usize) -> ParseR<()> {
+        let depth = self.peek.len();
+        self.cond_stack.push(CondGroup {
+            taken: cond,
+            in_else: false,
+            line: line,
+            depth: depth,
+        });
         if !cond {
             try!(self.skip_cond_include());
         }
         Ok(())
     }
     fn read_if(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
         let cond = try!(self.read_constexpr());
-        self.do_read_if(cond)
+        self.do_read_if(cond, line)
     }
     fn read_ifdef(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
         let macro_name = ident_val!(try!(self.do_read_token()));
-        let macro_is_defined = self.macro_map.contains_key(macro_name.as_str());
-        self.do_read_if(macro_is_defined)
+        let macro_is_defined = self.is_defined(macro_name.as_str());
+        self.do_read_if(macro_is_defined, line)
     }
     fn read_ifndef(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
         let macro_name = ident_val!(try!(self.do_read_token()));
-        let macro_is_undefined = !self.macro_map.contains_key(macro_name.as_str());
-        self.do_read_if(macro_is_undefined)
+        let macro_is_undefined = !self.is_defined(macro_name.as_str());
+        self.do_read_if(macro_is_undefined, line)
+    }
+    // the innermost group if it's opened in the current file
+    fn cur_cond_group(&mut self) -> Option<&mut CondGroup> {
+        let depth = self.peek.len();
+        match self.cond_stack.last_mut() {
+            Some(group) if group.depth == depth => Some(group),
+            _ => None,
+        }
     }
     fn read_elif(&mut self) -> ParseR<()> {
-        if *self.cond_stack.last().unwrap() || !try!(self.read_constexpr()) {
+        let line = *self.get_cur_line();
+        let (taken, in_else) = match self.cur_cond_group() {
+            Some(group) => (group.taken, group.in_else),
+            None => {
+                self.add_error(line, "#elif without #if", None);
+                try!(self.read_rest_of_line());
+                return Ok(());
+            }
+        };
+        if in_else {
+            self.add_error(line, "#elif after #else", None);
+            return self.skip_cond_include();
+        }
+        if taken || !try!(self.read_constexpr()) {
             try!(self.skip_cond_include());
         } else {
-            self.cond_stack.pop();
-            self.cond_stack.push(true);
+            self.cur_cond_group().unwrap().taken = true;
         }
         Ok(())
     }
     fn read_else(&mut self) -> ParseR<()> {
-        if *self.cond_stack.last().unwrap() {
+        let line = *self.get_cur_line();
+        try!(self.skip_extra_tokens("else"));
+        let (taken, in_else) = match self.cur_cond_group() {
+            Some(group) => {
+                let state = (group.taken, group.in_else);
+                group.taken = true;
+                group.in_else = true;
+                state
+            }
+            None => {
+                self.add_error(line, "#else without #if", None);
+                return Ok(());
+            }
+        };
+        if in_else {
+            self.add_error(line, "#else after #else", None);
+        }
+        if taken {
             try!(self.skip_cond_include());
         }
         Ok(())
     }
+    fn read_endif(&mut self) -> ParseR<()> {
+        let line = *self.get_cur_line();
+        try!(self.skip_extra_tokens("endif"));
+        if self.cur_cond_group().is_some() {
+            self.cond_stack.pop();
+        } else {
+            self.add_error(line, "#endif without #if", None);
+        }
+        Ok(())
+    }
+    // '#endif FOO': the rest of the line is ignored with a warning as GCC does
+    fn skip_extra_tokens(&mut self, directive: &str) -> ParseR<()> {
+        let line = *self.get_cur_line();
+        if !try!(self.read_rest_of_line()).is_empty() {
+            let msg = format!("extra tokens at end of #{} directive", directive);
+            self.add_warning(line, msg.as_str());
+        }
+        Ok(())
+    }
+    // called at the end of each file. the groups opened in it and not closed are errors
+    fn close_cond_groups(&mut self) {
+        while self.cur_cond_group().is_some() {
+            let group = self.cond_stack.pop().unwrap();
+            self.add_error(group.line, "unterminated conditional directive", None);
+        }
+    }
 
+    // skip lines until #elif, #else or #endif of the current group, which is left to be read.
+    // only '#' at the beginning of a line (after spaces and comments) starts a directive.
+    // '#' in comments and string or char literals is ignored.
+    // at the end of the file, the unterminated group is reported by close_cond_groups
     fn skip_cond_include(&mut self) -> ParseR<()> {
         let mut nest = 0;
+        let mut at_line_start = {
+            let peek_pos = *self.peek_pos.back().unwrap();
+            peek_pos == 0 || self.peek.back().unwrap()[peek_pos - 1] == b'\n'
+        };
         loop {
-            if try!(self.peek_next()) != '#' {
-                continue;
-            }
-
-            let tok = try!(self.do_read_token());
-            let val = ident_val!(tok);
-            if nest == 0 {
-                match val.as_str() {
-                    "else" | "elif" | "endif" => {
-                        let line = *self.get_cur_line();
-                        self.unget(tok);
-                        self.unget(Token::new(
-                            TokenKind::Identifier("#".to_string()),
-                            0,
-                            0,
-                            line,
-                        ));
-                        return Ok(());
+            let c = match self.peek_next() {
+                Ok(c) => c,
+                Err(_) => return Ok(()),
+            };
+            match c {
+                '\n' => at_line_start = true,
+                ' ' | '\t' | '\r' => {}
+                // line continuation
+                '\\' if self.peek_char_is('\n').unwrap_or(false) => {
+                    try!(self.peek_next());
+                }
+                '/' if self.peek_char_is('*').unwrap_or(false) => {
+                    try!(self.peek_next()); // *
+                    let mut last = ' ';
+                    while !(last == '*' && try!(self.peek_char_is('/'))) {
+                        last = try!(self.peek_next());
                     }
-                    _ => {}
+                    try!(self.peek_next()); // /
                 }
-            }
-
-            match val.as_str() {
-                "if" | "ifdef" | "ifndef" => nest += 1,
-                "endif" => nest -= 1,
-                _ => {}
+                '/' if self.peek_char_is('/').unwrap_or(false) => {
+                    while !try!(self.peek_char_is('\n')) {
+                        try!(self.peek_next());
+                    }
+                }
+                '"' | '\'' => {
+                    // an unterminated literal ends at the end of the line
+                    loop {
+                        match try!(self.peek_get()) {
+                            '\n' => break,
+                            '\\' => {
+                                try!(self.peek_next());
+                            }
+                            q if q == c => {
+                                try!(self.peek_next());
+                                break;
+                            }
+                            _ => {}
+                        }
+                        try!(self.peek_next());
+                    }
+                    at_line_start = false;
+                }
+                '#' if at_line_start => {
+                    at_line_start = false;
+                    let line = *self.get_cur_line();
+                    let tok = try!(self.do_read_token());
+                    let val = ident_val!(tok);
+                    if nest == 0 {
+                        match val.as_str() {
+                            "else" | "elif" | "endif" => {
+                                self.unget(tok);
+                                self.unget(Token::new(
+                                    TokenKind::Identifier("#".to_string()),
+                                    0,
+                                    0,
+                                    line,
+                                ));
+                                return Ok(());
+                            }
+                            _ => {}
+                        }
+                    }
+                    match val.as_str() {
+                        "if" | "ifdef" | "ifndef" => nest += 1,
+                        "endif" => nest -= 1,
+                        _ => {}
+                    }
+                    // a directive without name ('#' only)
+                    if tok.kind == TokenKind::Newline {
+                        at_line_start = true;
+                    }
+                }
+                _ => at_line_start = false,
             }
         }
     }
 
     pub fn get_surrounding_code_with_err_point(&mut self, pos: usize) -> String {
         let code = self.peek.back().unwrap();
-        let peek_pos = pos;
-        let start_pos = {
-            let mut p = peek_pos as i32;
-            while p >= 0 && code[p as usize] as char != '\n' {
-                p -= 1;
-            }
-            p += 1; // '\n'
-            p as usize
-        };
-        let end_pos = {
-            let mut p = peek_pos as i32;
-            while p < code.len() as i32 && code[p as usize] as char != '\n' {
-                p += 1;
-            }
-            p as usize
-        };
-        let surrounding_code = String::from_utf8(code[start_pos..end_pos].to_vec())
-            .unwrap()
-            .to_string();
+        let peek_pos = ::std::cmp::min(pos, code.len());
+        let start_pos = code[..peek_pos]
+            .iter()
+            .rposition(|&c| c == b'\n')
+            .map_or(0, |p| p + 1);
+        let end_pos = code[peek_pos..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .map_or(code.len(), |p| peek_pos + p);
+        let surrounding_code = String::from_utf8_lossy(&code[start_pos..end_pos]).into_owned();
         let mut err_point = String::new();
         for _ in 0..(peek_pos - start_pos) {
             err_point.push(' ');
@@ -1350,3 +2486,321 @@ impl Lexer {
         surrounding_code + "\n" + err_point.as_str()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_provider::VirtualFiles;
+
+    // the tokens of the source file as -E prints them, and the diagnostics
+    fn preprocess(source: &str) -> (String, Vec<Diagnostic>) {
+        let target = Target::host().unwrap();
+        let mut lexer = Lexer::from_source("test.c".to_string(), source.to_string(), target);
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            // rucc.h and predefined macros
+            if lexer.get_filename() == "test.c" {
+                tokens.push(tok.to_c_text());
+            }
+        }
+        (tokens.join(" "), lexer.take_diagnostics())
+    }
+
+    fn messages(diagnostics: &Vec<Diagnostic>) -> Vec<(usize, String)> {
+        diagnostics
+            .iter()
+            .map(|diag| (diag.line, format!("{}: {}", diag.severity.as_str(), diag.msg)))
+            .collect()
+    }
+
+    #[test]
+    fn else_and_endif_without_if() {
+        let (output, diagnostics) = preprocess("a\n#else\nb\n#endif\nc\n");
+        assert_eq!(output, "a b c");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (2, "error: #else without #if".to_string()),
+                (4, "error: #endif without #if".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn elif_without_if() {
+        let (output, diagnostics) = preprocess("#elif 1\na\n");
+        assert_eq!(output, "a");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![(1, "error: #elif without #if".to_string())]
+        );
+    }
+
+    #[test]
+    fn elif_after_else() {
+        let (output, diagnostics) = preprocess("#if 0\na\n#else\nb\n#elif 1\nc\n#endif\nd\n");
+        assert_eq!(output, "b d");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![(5, "error: #elif after #else".to_string())]
+        );
+    }
+
+    #[test]
+    fn unterminated_if() {
+        let (output, diagnostics) = preprocess("a\n#if 1\nb\n#ifdef X\nc\n");
+        assert_eq!(output, "a b");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (4, "error: unterminated conditional directive".to_string()),
+                (2, "error: unterminated conditional directive".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_tokens_after_else_and_endif() {
+        let source = "#if 0\na\n#else FOO\nb\n#endif FOO\nc\n#if 1\n#endif /* ok */ // ok\n";
+        let (output, diagnostics) = preprocess(source);
+        assert_eq!(output, "b c");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (3, "warning: extra tokens at end of #else directive".to_string()),
+                (5, "warning: extra tokens at end of #endif directive".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_keep_their_spelling() {
+        let (output, _) = preprocess("18446744073709551615 0xffffffffffffffff 1u 7\n");
+        assert_eq!(output, "18446744073709551615 0xffffffffffffffff 1u 7");
+        let (output, _) = preprocess("#define N 1L<<40\nN 1.5f 0x10 1e+5 010\n");
+        assert_eq!(output, "1L << 40 1.5f 0x10 1e+5 010");
+    }
+
+    #[test]
+    fn high_bytes_are_escaped() {
+        let (output, _) = preprocess("\"\u{e9}\\x80\" '\\377'\n");
+        assert_eq!(output, "\"\\303\\251\\200\" '\\377'");
+    }
+
+    #[test]
+    fn va_opt() {
+        let defs = "#define F(a, ...) f(a __VA_OPT__(,) __VA_ARGS__)\n\
+                    #define G(...) g(0 __VA_OPT__(, __VA_ARGS__) __VA_OPT__())\n";
+        let expand = |uses: &str| preprocess(format!("{}{}", defs, uses).as_str());
+        assert_eq!(expand("F(1)").0, "f ( 1 )");
+        assert_eq!(expand("F(1,)").0, "f ( 1 )");
+        assert_eq!(expand("F(1, 2, 3)").0, "f ( 1 , 2 , 3 )");
+        assert_eq!(expand("G()").0, "g ( 0 )");
+        assert_eq!(expand("G(x, (y, z))").0, "g ( 0 , x , ( y , z ) )");
+        assert!(expand("F(1, 2) G(3)").1.is_empty());
+    }
+
+    #[test]
+    fn gnu_comma_swallowing() {
+        let defs = "#define LOG(fmt, ...) printf(fmt, ## __VA_ARGS__)\n\
+                    #define E(...) e(0, ##__VA_ARGS__)\n";
+        let expand = |uses: &str| preprocess(format!("{}{}", defs, uses).as_str());
+        assert_eq!(expand("LOG(\"a\")").0, "printf ( \"a\" )");
+        assert_eq!(expand("LOG(\"a\",)").0, "printf ( \"a\" )");
+        assert_eq!(expand("LOG(\"%d %d\", 1, 2)").0, "printf ( \"%d %d\" , 1 , 2 )");
+        assert_eq!(expand("E()").0, "e ( 0 )");
+        assert_eq!(expand("E(x)").0, "e ( 0 , x )");
+    }
+
+    #[test]
+    fn argument_count() {
+        let defs = "#define Z() z\n#define F(a, b) f\n#define V(a, b, ...) v\n";
+        let expand = |uses: &str| preprocess(format!("{}{}", defs, uses).as_str());
+        let (output, diagnostics) = expand("Z() F(1, 2) F(, ) V(1, 2) V(1, 2, 3, 4)");
+        assert_eq!(output, "z f f v v");
+        assert!(diagnostics.is_empty());
+        let (output, diagnostics) = expand("F(1) Z(1) F(1, 2, 3) V(1) x\n");
+        assert_eq!(output, "x");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (4, "error: macro 'F' requires 2 arguments, but only 1 given".to_string()),
+                (4, "error: macro 'Z' passed 1 arguments, but takes just 0".to_string()),
+                (4, "error: macro 'F' passed 3 arguments, but takes just 2".to_string()),
+                (4, "error: macro 'V' requires 2 arguments, but only 1 given".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_pasting() {
+        let defs = "#define C(a, b) a ## b\n#define C3(a, b, c) a ## b ## c\n";
+        let expand = |uses: &str| preprocess(format!("{}{}", defs, uses).as_str());
+        assert_eq!(expand("C(x, y) C(x, 1) C(1, 2) C(_, 0)").0, "xy x1 12 _0");
+        assert_eq!(expand("C(<<, =) C(-, >) C(-, -)").0, "<<= -> --");
+        assert_eq!(expand("C3(a, b, c) C(, z) C(z, )").0, "abc z z");
+        assert_eq!(expand("C(x, y z)").0, "xy z");
+        let (output, diagnostics) = expand("C(+, /)");
+        assert_eq!(output, "+ /");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![(
+                3,
+                "error: pasting \"+\" and \"/\" does not give a valid preprocessing token"
+                    .to_string(),
+            )]
+        );
+    }
+
+    #[test]
+    fn pasted_numbers_are_lexed_again() {
+        let source = "#define C(a, b) a ## b\nC(0x, ff) C(1, e5) C(1e+, 5) C(1., 5f) C(1, u)\n";
+        let mut lexer =
+            Lexer::from_source("test.c".to_string(), source.to_string(), Target::host().unwrap());
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            if lexer.get_filename() == "test.c" {
+                tokens.push((tok.to_c_text(), tok.kind));
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec![
+                ("0xff".to_string(), TokenKind::IntNumber(255, Bits::Bits32, Sign::Signed)),
+                ("1e5".to_string(), TokenKind::FloatNumber(1e5)),
+                ("1e+5".to_string(), TokenKind::FloatNumber(1e5)),
+                ("1.5f".to_string(), TokenKind::FloatNumber(1.5)),
+                ("1u".to_string(), TokenKind::IntNumber(1, Bits::Bits32, Sign::Unsigned)),
+            ]
+        );
+        assert!(lexer.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn line_directive() {
+        let mut files = VirtualFiles::new();
+        files.add_file("dir/test.c", "#line 10 \"other.c\"\n__FILE__ __LINE__\n#include \"a.h\"\n");
+        files.add_file("dir/a.h", "__FILE__ __LINE__\n");
+        let target = Target::host().unwrap();
+        let mut lexer =
+            Lexer::with_file_provider("dir/test.c".to_string(), None, Arc::new(files), target);
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            tokens.push(tok.to_c_text());
+        }
+        // "a.h" is searched for in the directory of the real file
+        let n = tokens.len();
+        assert_eq!(
+            tokens[n - 4..].to_vec(),
+            vec!["\"other.c\"", "10", "\"dir/a.h\"", "1"]
+        );
+        assert!(lexer.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn error_and_warning() {
+        let (output, diagnostics) =
+            preprocess("a\n#error \"x\"  /* c */ y\\\n z\n#warning w // c\nb\n");
+        assert_eq!(output, "a b");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![
+                (2, "error: #error \"x\"    y z".to_string()),
+                (4, "warning: #warning w".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pragma_once() {
+        let mut files = VirtualFiles::new();
+        files.add_file(
+            "test.c",
+            "#include \"a.h\"\n#include \"a.h\"\n#include \"b.h\"\n#include \"b.h\"\n",
+        );
+        files.add_file("a.h", "#pragma once\na\n");
+        files.add_file("b.h", "#pragma weak\nb\n");
+        let target = Target::host().unwrap();
+        let mut lexer =
+            Lexer::with_file_provider("test.c".to_string(), None, Arc::new(files), target);
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            let filename = lexer.get_filename();
+            if filename == "a.h" || filename == "b.h" {
+                tokens.push(tok.to_c_text());
+            }
+        }
+        assert_eq!(tokens, vec!["a", "b", "b"]);
+        assert!(lexer.take_diagnostics().is_empty());
+    }
+
+    #[test]
+    fn builtin_macros() {
+        let mut files = VirtualFiles::new();
+        files.add_file(
+            "test.c",
+            "__COUNTER__ __COUNTER__ __INCLUDE_LEVEL__ __BASE_FILE__\n#include \"a.h\"\n",
+        );
+        files.add_file("a.h", "__COUNTER__ __INCLUDE_LEVEL__ __BASE_FILE__ __FILE__\n");
+        let target = Target::host().unwrap();
+        let mut lexer =
+            Lexer::with_file_provider("test.c".to_string(), None, Arc::new(files), target);
+        let mut tokens = Vec::new();
+        while let Ok(tok) = lexer.get_preprocessed() {
+            let filename = lexer.get_filename();
+            if filename == "test.c" || filename == "a.h" {
+                tokens.push(tok.to_c_text());
+            }
+        }
+        assert_eq!(
+            tokens,
+            vec!["0", "1", "0", "\"test.c\"", "2", "1", "\"test.c\"", "\"a.h\""]
+        );
+    }
+
+    #[test]
+    fn date_and_time() {
+        env::set_var("SOURCE_DATE_EPOCH", "1700000000");
+        let (output, diagnostics) = preprocess("__DATE__ __TIME__ __DATE__\n");
+        env::remove_var("SOURCE_DATE_EPOCH");
+        assert_eq!(output, "\"Nov 14 2023\" \"22:13:20\" \"Nov 14 2023\"");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn has_builtin() {
+        let (output, diagnostics) = preprocess(
+            "#if __has_builtin(__builtin_va_start) && __has_builtin(__builtin_reg_class)\n\
+             a\n\
+             #endif\n\
+             #if __has_builtin(__builtin_expect) || __has_attribute(packed)\n\
+             b\n\
+             #endif\n",
+        );
+        assert_eq!(output, "a");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn invalid_directive() {
+        let (output, diagnostics) = preprocess("#foo bar\n#\n# 10 \"test.c\" 1 3\n__LINE__\n");
+        assert_eq!(output, "10");
+        assert_eq!(
+            messages(&diagnostics),
+            vec![(1, "error: invalid preprocessing directive #foo".to_string())]
+        );
+        // directives in skipped groups aren't checked
+        let (output, diagnostics) = preprocess("#if 0\n#foo\n#endif\na\n");
+        assert_eq!(output, "a");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn shebang() {
+        // the first line is skipped but still counted
+        let (output, diagnostics) =
+            preprocess("#!/usr/bin/env rucc --run\nint a = __LINE__;\n#error e\n");
+        assert_eq!(output, "int a = 2 ;");
+        assert_eq!(messages(&diagnostics), vec![(3, "error: #error e".to_string())]);
+    }
+}
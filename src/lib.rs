@@ -1,27 +1,24 @@
 pub mod common;
+pub mod session;
 pub mod error;
 pub mod lexer;
+pub mod cpp_expr;
+pub mod file_provider;
+pub mod include_path;
+pub mod builtin_headers;
 pub mod node;
+pub mod ast_dump;
 pub mod parser;
 pub mod codegen;
 pub mod types;
+pub mod target;
 
-// for LLVMLinkInInterpreter
+pub use common::{compile, compile_source, DependencyOptions, Options, Output, OutputKind};
+pub use session::Session;
+pub use error::{Diagnostic, Diagnostics, Severity};
+pub use file_provider::{FileProvider, RealFiles, VirtualFiles};
+
+// llvm built with LLVM_ENABLE_FFI calls libffi from its interpreter, which is linked in with
+// the other components even though only MCJIT is used, and llvm-config doesn't always list it
 #[link(name = "ffi")]
 extern "C" {}
-
-#[macro_use]
-extern crate lazy_static;
-
-use std::sync::Mutex;
-use std::marker::Send;
-
-unsafe impl Send for codegen::Codegen {}
-
-lazy_static! {
-    static ref CODEGEN: Mutex<codegen::Codegen> = {
-        unsafe {
-            Mutex::new(codegen::Codegen::new("rucc"))
-        }
-    };
-}
@@ -1,5 +1,5 @@
 extern crate rucc;
-use rucc::common;
+use rucc::{ast_dump, codegen, common, target};
 
 extern crate ansi_term;
 use self::ansi_term::Colour;
@@ -7,6 +7,9 @@ use self::ansi_term::Colour;
 extern crate clap;
 use clap::{App, Arg};
 
+use std::env;
+use std::process;
+
 const VERSION_STR: &'static str = env!("CARGO_PKG_VERSION");
 
 fn main() {
@@ -20,18 +23,449 @@ fn main() {
                 .long("version")
                 .help("Show version info"),
         )
-        .arg(Arg::with_name("FILE").help("Input file").index(1));
-    let app_matches = app.clone().get_matches();
+        .arg(
+            Arg::with_name("preprocess")
+                .short("E")
+                .help("Only run the preprocessor"),
+        )
+        .arg(
+            Arg::with_name("M")
+                .long("M")
+                .help("Only output a make rule listing the headers the source depends on"),
+        )
+        .arg(
+            Arg::with_name("MM")
+                .long("MM")
+                .help("Like -M but without system headers"),
+        )
+        .arg(
+            Arg::with_name("MD")
+                .long("MD")
+                .help("Write a make rule (see -M) to a .d file besides compiling"),
+        )
+        .arg(
+            Arg::with_name("MMD")
+                .long("MMD")
+                .help("Like -MD but without system headers"),
+        )
+        .arg(
+            Arg::with_name("MF")
+                .long("MF")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the make rule of -M, -MM, -MD or -MMD to <FILE>"),
+        )
+        .arg(
+            Arg::with_name("dump-ast")
+                .long("dump-ast")
+                .takes_value(true)
+                .possible_values(&["sexpr", "json", "dot"])
+                .value_name("FORMAT")
+                .help("Print the AST instead of compiling"),
+        )
+        .arg(
+            Arg::with_name("object")
+                .short("c")
+                .help("Emit an object file")
+                .conflicts_with("assembly"),
+        )
+        .arg(
+            Arg::with_name("assembly")
+                .short("S")
+                .help("Emit an assembly file"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write output to <FILE>"),
+        )
+        .arg(
+            Arg::with_name("emit-llvm")
+                .long("emit-llvm")
+                .help("Emit LLVM bitcode (or textual LLVM IR with -S)"),
+        )
+        .arg(
+            Arg::with_name("llvm-line-comments")
+                .long("llvm-line-comments")
+                .requires("emit-llvm")
+                .help("Comment the textual LLVM IR with source line numbers"),
+        )
+        .arg(
+            Arg::with_name("opt_level")
+                .short("O")
+                .takes_value(true)
+                .possible_values(&["0", "1", "2", "3", "s"])
+                .value_name("LEVEL")
+                .help("Optimization level ('-O' is the same as '-O1')"),
+        )
+        .arg(
+            Arg::with_name("run")
+                .long("run")
+                .help("Compile <FILE> in memory and run it (arguments after <FILE> are passed to it)"),
+        )
+        .arg(
+            Arg::with_name("library_path")
+                .short("L")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Add <DIR> to the library search path"),
+        )
+        .arg(
+            Arg::with_name("library")
+                .short("l")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("LIB")
+                .help("Link with library <LIB>"),
+        )
+        .arg(
+            Arg::with_name("define")
+                .short("D")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("MACRO[=VAL]")
+                .help("Define <MACRO> to <VAL> (or 1 if <VAL> omitted)"),
+        )
+        .arg(
+            Arg::with_name("undef")
+                .short("U")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("MACRO")
+                .help("Undefine <MACRO>"),
+        )
+        .arg(
+            Arg::with_name("include_dir")
+                .short("I")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Add <DIR> to the include search path"),
+        )
+        .arg(
+            Arg::with_name("iquote")
+                .long("iquote")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Add <DIR> to the include search path for #include \"...\" only"),
+        )
+        .arg(
+            Arg::with_name("isystem")
+                .long("isystem")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("DIR")
+                .help("Add <DIR> to the system include search path"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .value_name("TRIPLE")
+                .help("Generate code for <TRIPLE> (e.g. aarch64-linux-gnu, riscv64-linux-gnu)"),
+        )
+        .arg(
+            Arg::with_name("march")
+                .long("march")
+                .takes_value(true)
+                .value_name("ARCH")
+                .help("Generate code for <ARCH> (e.g. haswell, armv8.2-a+crypto, rv64gc)"),
+        )
+        .arg(
+            Arg::with_name("mcpu")
+                .long("mcpu")
+                .takes_value(true)
+                .value_name("CPU")
+                .help("Generate code for <CPU> (e.g. cortex-a53, sifive-u74)"),
+        )
+        .arg(
+            Arg::with_name("m32")
+                .long("m32")
+                .help("Generate code for i386 (ILP32: 32 bit int, long and pointers)"),
+        )
+        .arg(
+            Arg::with_name("resource-dir")
+                .long("resource-dir")
+                .takes_value(true)
+                .value_name("DIR")
+                .help("Read the headers coming with rucc from <DIR>/include instead of the built-in ones"),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .help("Input files (*.c, *.o, ...). '-' means stdin")
+                .multiple(true)
+                .index(1),
+        );
+    // the arguments of the program run by --run are passed as they are
+    let (args, run_args) = split_run_args(env::args().collect());
+    let args = normalize_args(args);
+    let app_matches = app.clone().get_matches_from(args.clone());
 
-    if let Some(filename) = app_matches.value_of("FILE") {
-        common::run_file(filename);
-        println!("{}", Colour::Green.paint("Compiling exited successfully."));
+    if let Some(inputs) = app_matches.values_of("FILE") {
+        let target = match app_matches.value_of("target") {
+            Some(triple) => target::Target::from_triple(triple),
+            None => target::Target::host(),
+        };
+        let mut options = common::Options::with_target(target.unwrap_or_else(|msg| {
+            common::show_error(msg.as_str());
+            process::exit(-1)
+        }));
+        if app_matches.is_present("M") || app_matches.is_present("MM") {
+            options.output_kind = common::OutputKind::Dependencies;
+        } else if app_matches.is_present("preprocess") {
+            options.output_kind = common::OutputKind::Preprocessed;
+        } else if let Some(format) = app_matches.value_of("dump-ast") {
+            options.output_kind =
+                common::OutputKind::AstDump(ast_dump::Format::from_str(format).unwrap());
+        } else if app_matches.is_present("emit-llvm") {
+            options.output_kind = if app_matches.is_present("assembly") {
+                common::OutputKind::LLVMIR
+            } else {
+                common::OutputKind::Bitcode
+            };
+        } else if app_matches.is_present("object") {
+            options.output_kind = common::OutputKind::Object;
+        } else if app_matches.is_present("assembly") {
+            options.output_kind = common::OutputKind::Assembly;
+        }
+        if ["M", "MM", "MD", "MMD"]
+            .iter()
+            .any(|name| app_matches.is_present(name))
+        {
+            options.dependency = Some(common::DependencyOptions {
+                system_headers: !app_matches.is_present("MM") && !app_matches.is_present("MMD"),
+                filename: app_matches.value_of("MF").map(|s| s.to_string()),
+            });
+        }
+        options.llvm_line_comments = app_matches.is_present("llvm-line-comments");
+        options.opt_level = match app_matches.value_of("opt_level") {
+            Some("1") => codegen::OptLevel::O1,
+            Some("2") => codegen::OptLevel::O2,
+            Some("3") => codegen::OptLevel::O3,
+            Some("s") => codegen::OptLevel::Os,
+            _ => codegen::OptLevel::O0,
+        };
+        options.output_filename = app_matches.value_of("output").map(|s| s.to_string());
+        options.library_paths = values_of(&app_matches, "library_path");
+        options.libraries = values_of(&app_matches, "library");
+        options.macros = macro_options(&args);
+        for dir in values_of(&app_matches, "iquote") {
+            options.include_path.add_quote_dir(dir.as_str());
+        }
+        for dir in values_of(&app_matches, "include_dir") {
+            options.include_path.add_user_dir(dir.as_str());
+        }
+        for dir in values_of(&app_matches, "isystem") {
+            options.include_path.add_system_dir(dir.as_str());
+        }
+        if let Some(dir) = app_matches.value_of("resource-dir") {
+            options.include_path.set_resource_dir(dir);
+        }
+        if app_matches.is_present("m32") {
+            if let Err(msg) = options.target.set_m32() {
+                common::show_error(msg.as_str());
+                process::exit(-1);
+            }
+        }
+        if let Some(march) = app_matches.value_of("march") {
+            if let Err(msg) = options.target.set_march(march) {
+                common::show_error(msg.as_str());
+                process::exit(-1);
+            }
+        }
+        if let Some(cpu) = app_matches.value_of("mcpu") {
+            options.target.set_cpu(cpu);
+        }
+        if app_matches.is_present("run") {
+            let inputs: Vec<&str> = inputs.collect();
+            if inputs.len() > 1 {
+                common::show_error(
+                    format!(
+                        "'--run' takes only one source file, but '{}' is given as well",
+                        inputs[..inputs.len() - 1].join("', '")
+                    ).as_str(),
+                );
+                process::exit(-1);
+            }
+            match common::run_jit(inputs[0], &run_args, &options) {
+                Ok(ret) => process::exit(ret),
+                Err(diagnostics) => {
+                    common::show_diagnostics(&diagnostics);
+                    process::exit(-1);
+                }
+            }
+        }
+        if let Err(diagnostics) = common::run(&inputs.map(|s| s.to_string()).collect(), &options) {
+            common::show_diagnostics(&diagnostics);
+            process::exit(-1);
+        }
+        // the preprocessed source, the AST or '-o -' output may be written to stdout
+        let to_stdout = options.output_kind.is_text()
+            || options.output_filename == Some("-".to_string());
+        if !to_stdout {
+            println!("{}", Colour::Green.paint("Compiling exited successfully."));
+        }
     } else {
         app.print_help().unwrap();
         println!();
     }
 }
 
+// GCC style options that have a long name with a single dash.
+// clap doesn't understand them, so they are rewritten into '--name' (or '--name=value').
+const GCC_STYLE_LONG_OPTIONS: &'static [&'static str] = &[
+    "-iquote",
+    "-isystem",
+    "-resource-dir",
+    "-target",
+    "-march",
+    "-mcpu",
+    "-MF",
+];
+
+// options that take the next argument as their value when it's not attached (e.g. '-o out')
+const SEPARATE_VALUE_OPTIONS: &'static [&'static str] = &[
+    "-o",
+    "-L",
+    "-l",
+    "-D",
+    "-U",
+    "-I",
+    "--dump-ast",
+    "--iquote",
+    "--isystem",
+    "--resource-dir",
+    "--target",
+    "--march",
+    "--mcpu",
+    "--MF",
+];
+
+// GCC style flags (without value). they must match exactly since '-MMD' starts with '-MM'.
+const GCC_STYLE_FLAGS: &'static [&'static str] = &["-m32", "-M", "-MM", "-MD", "-MMD"];
+
+fn normalize_args(args: Vec<String>) -> Vec<String> {
+    args.into_iter()
+        .map(|arg| {
+            // '-O' means '-O1'
+            if arg == "-O" {
+                return "-O1".to_string();
+            }
+            if GCC_STYLE_FLAGS.contains(&arg.as_str()) {
+                return format!("-{}", arg);
+            }
+            for opt in GCC_STYLE_LONG_OPTIONS {
+                if arg == *opt {
+                    return format!("-{}", arg);
+                }
+                if arg.starts_with(opt) {
+                    // both '-isystemdir' and '-march=native' are accepted
+                    let val = &arg[opt.len()..];
+                    return format!("-{}={}", opt, val.trim_left_matches('='));
+                }
+            }
+            arg
+        })
+        .collect()
+}
+
+// rucc --run [options] file.c [args...]
+// the arguments after the source file belong to the program, not to rucc.
+// the source file is the first argument after --run that is neither an option nor its value
+fn split_run_args(args: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let run_pos = match args.iter().position(|arg| arg == "--run") {
+        Some(pos) => pos,
+        None => return (args, Vec::new()),
+    };
+    let mut source_pos = None;
+    let mut rest = args.iter().enumerate().skip(run_pos + 1);
+    while let Some((pos, arg)) = rest.next() {
+        if arg == "-" || !arg.starts_with('-') {
+            source_pos = Some(pos);
+            break;
+        }
+        if SEPARATE_VALUE_OPTIONS.contains(&arg.as_str())
+            || GCC_STYLE_LONG_OPTIONS.contains(&arg.as_str())
+        {
+            rest.next();
+        }
+    }
+    match source_pos {
+        Some(pos) => {
+            let mut args = args;
+            let run_args = args.split_off(pos + 1);
+            (args, run_args)
+        }
+        None => (args, Vec::new()),
+    }
+}
+
+// -D and -U in the order on the command line ('-D NAME' or '-DNAME').
+// clap gives the values of each option separately, so the order is taken from 'args'
+fn macro_options(args: &Vec<String>) -> Vec<common::MacroOption> {
+    let mut opts = Vec::new();
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if !arg.starts_with("-D") && !arg.starts_with("-U") {
+            continue;
+        }
+        let val = if arg.len() == 2 {
+            match args.next() {
+                Some(val) => val.as_str(),
+                None => break,
+            }
+        } else {
+            &arg[2..]
+        };
+        let val = val.to_string();
+        opts.push(if arg.starts_with("-D") {
+            common::MacroOption::Define(val)
+        } else {
+            common::MacroOption::Undef(val)
+        });
+    }
+    opts
+}
+
+fn values_of(matches: &clap::ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|vals| vals.map(|s| s.to_string()).collect())
+        .unwrap_or(Vec::new())
+}
+
+#[test]
+fn run_args_are_not_normalized() {
+    let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let (rucc_args, run_args) =
+        split_run_args(args(&["rucc", "-O", "--run", "foo.c", "-O", "-M", "-march=x"]));
+    assert_eq!(normalize_args(rucc_args), args(&["rucc", "-O1", "--run", "foo.c"]));
+    assert_eq!(run_args, args(&["-O", "-M", "-march=x"]));
+    // the values of options aren't taken for the source file
+    let (rucc_args, run_args) = split_run_args(args(&[
+        "rucc", "--run", "-I", "inc.c", "-isystem", "sys", "-DX", "prog", "a.c", "-",
+    ]));
+    assert_eq!(
+        rucc_args,
+        args(&["rucc", "--run", "-I", "inc.c", "-isystem", "sys", "-DX", "prog"])
+    );
+    assert_eq!(run_args, args(&["a.c", "-"]));
+}
+
 #[test]
 fn compare_with_clang_output() {
     use std::fs;
@@ -69,7 +503,7 @@ fn compare_with_clang_output() {
 #[test]
 fn compile_all_examples() {
     use std::fs;
-    use rucc::{codegen, lexer, parser};
+    use rucc::{codegen, lexer, parser, target};
     use std::io::{stderr, Write};
 
     let examples_paths = match fs::read_dir("example") {
@@ -83,9 +517,10 @@ fn compile_all_examples() {
         // for coverage...
         unsafe {
             let mut nodes = Vec::new();
-            let mut lexer = lexer::Lexer::new(name.to_string());
+            let host = target::Target::host().unwrap();
+            let mut lexer = lexer::Lexer::new(name.to_string(), host.clone());
             let mut parser = parser::Parser::new(&mut lexer);
-            let mut codegen = codegen::Codegen::new("test");
+            let mut codegen = codegen::Codegen::new("test", host);
             loop {
                 match parser.read_toplevel(&mut nodes) {
                     Err(parser::Error::EOF) => break,
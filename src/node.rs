@@ -1,7 +1,6 @@
 use std::boxed::Box;
 use types::{StorageClass, Type};
 use parser::{Error, ParseR};
-use std::marker::Send;
 use lexer::Pos;
 
 #[derive(Debug, Clone)]
@@ -43,7 +42,7 @@ pub enum ASTKind {
     UnaryOp(Box<AST>, CUnaryOps),
     BinaryOp(Box<AST>, Box<AST>, CBinOps),
     TernaryOp(Box<AST>, Box<AST>, Box<AST>), // cond then else
-    FuncDef(Type, Vec<String>, String, Box<AST>), // functype, param names, func name, body
+    FuncDef(Type, Vec<String>, String, StorageClass, Box<AST>), // functype, param names, func name, storage class, body
     Block(Vec<AST>),
     Compound(Vec<AST>),
     If(Box<AST>, Box<AST>, Box<AST>), // cond, then stmt, else stmt
@@ -62,8 +61,6 @@ pub enum ASTKind {
     Return(Option<Box<AST>>),
 }
 
-unsafe impl Send for AST {}
-
 #[derive(Debug, Clone)]
 pub enum CBinOps {
     Add,
@@ -244,8 +241,8 @@ impl AST {
                 rhs.show();
                 print!(")");
             }
-            ASTKind::FuncDef(ref functy, ref param_names, ref name, ref body) => {
-                print!("(def-func {} {:?} {:?}", name, functy, param_names);
+            ASTKind::FuncDef(ref functy, ref param_names, ref name, ref sclass, ref body) => {
+                print!("(def-func {:?} {} {:?} {:?}", sclass, name, functy, param_names);
                 body.show();
                 print!(")");
             }
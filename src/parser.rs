@@ -2,10 +2,10 @@ use lexer::{Keyword, Lexer, Pos, Symbol, Token, TokenKind};
 use node::{ASTKind, Bits, AST};
 use node;
 use types::{Sign, StorageClass, Type};
+use target::Target;
 
 use std::str;
 use std::boxed::Box;
-use std::io::{stderr, Write};
 use std::collections::{hash_map, HashMap, VecDeque};
 
 // use CODEGEN;
@@ -15,9 +15,6 @@ extern crate llvm_sys as llvm;
 extern crate rand;
 use self::rand::Rng;
 
-extern crate ansi_term;
-use self::ansi_term::Colour;
-
 // TODO: add more error kinds
 pub enum Error {
     Something,
@@ -117,47 +114,66 @@ macro_rules! expect_symbol_error {
     }}
 }
 
+// the functions that codegen implements itself. '__has_builtin' is 1 for these
+pub const BUILTIN_FUNCS: [&'static str; 4] = [
+    "__builtin_va_start",
+    "__builtin_va_end",
+    "__builtin_va_copy",
+    // the class of the type of '(type *)0' for va_arg: 0 integer, 1 floating point, 2 memory
+    "__builtin_reg_class",
+];
+
 impl<'a> Parser<'a> {
     pub fn new(lexer: &'a mut Lexer) -> Parser<'a> {
+        let mut env = Env::new();
+        for name in BUILTIN_FUNCS.iter() {
+            let void_ptr = Type::Ptr(Box::new(Type::Void));
+            let functy = match *name {
+                "__builtin_reg_class" => {
+                    Type::Func(Box::new(Type::Int(Sign::Signed)), vec![void_ptr], false)
+                }
+                "__builtin_va_copy" => {
+                    Type::Func(Box::new(Type::Void), vec![void_ptr.clone(), void_ptr], false)
+                }
+                _ => Type::Func(Box::new(Type::Void), vec![void_ptr], false),
+            };
+            env.add(
+                name.to_string(),
+                AST::new(ASTKind::Variable(functy, name.to_string()), Pos::new(0, 0)),
+            );
+        }
         Parser {
             lexer: lexer,
             err_counts: 0,
-            env: Env::new(),
+            env: env,
             tags: Env::new(),
             // constexpr_func_map: HashSet::new(),
         }
     }
+    // errors are recorded in the lexer with the other diagnostics.
+    // nothing is recorded after a fatal error of the lexer, since it only causes more errors.
     fn show_error(&mut self, msg: &str) {
+        if self.lexer.is_aborted() {
+            return;
+        }
         self.err_counts += 1;
-        writeln!(
-            &mut stderr(),
-            "{}: {} {}: {}",
-            self.lexer.get_filename(),
-            Colour::Red.bold().paint("error:"),
-            self.lexer.get_cur_line(),
-            msg
-        ).unwrap();
+        let line = *self.lexer.get_cur_line();
+        self.lexer.add_error(line, msg, None);
     }
     fn show_error_token(&mut self, token: &Token, msg: &str) {
+        self.show_error_at(&token.pos, msg);
+    }
+    fn show_error_at(&mut self, pos: &Pos, msg: &str) {
+        if self.lexer.is_aborted() {
+            return;
+        }
         self.err_counts += 1;
-        writeln!(
-            &mut stderr(),
-            "{}: {} {}: {}",
-            self.lexer.get_filename(),
-            Colour::Red.bold().paint("error:"),
-            token.pos.line,
-            msg
-        ).unwrap();
-        writeln!(
-            &mut stderr(),
-            "{}",
-            self.lexer
-                .get_surrounding_code_with_err_point(token.pos.pos,)
-        ).unwrap();
-    }
-    pub fn run_file(filename: String) -> Vec<AST> {
+        let code = self.lexer.get_surrounding_code_with_err_point(pos.pos);
+        self.lexer.add_error(pos.line, msg, Some(code));
+    }
+    pub fn run_file(filename: String, target: Target) -> Vec<AST> {
         let mut nodes: Vec<AST> = Vec::new();
-        let mut lexer = Lexer::new(filename.to_string());
+        let mut lexer = Lexer::new(filename.to_string(), target);
         // TODO: for debugging
         // loop {
         //     let tok = lexer.get();
@@ -174,23 +190,13 @@ impl<'a> Parser<'a> {
         Parser::new(&mut lexer).run(&mut nodes);
         nodes
     }
+    // errors can be retrieved by Lexer::take_diagnostics()
     pub fn run(&mut self, node: &mut Vec<AST>) {
         while matches!(self.read_toplevel(node), Ok(_)) {}
-        self.show_total_errors();
     }
     pub fn run_as_expr(&mut self) -> ParseR<AST> {
         self.read_expr()
     }
-    pub fn show_total_errors(&mut self) {
-        if self.err_counts > 0 {
-            println!(
-                "{} error{} generated.",
-                self.err_counts,
-                if self.err_counts > 1 { "s" } else { "" }
-            );
-            ::std::process::exit(-1);
-        }
-    }
     pub fn read_toplevel(&mut self, ast: &mut Vec<AST>) -> ParseR<()> {
         // TODO: refine
         if try!(self.is_function_def()) {
@@ -209,10 +215,11 @@ impl<'a> Parser<'a> {
         Ok(())
     }
     fn read_func_def(&mut self) -> ParseR<AST> {
+        let pos = try!(self.lexer.peek()).pos;
         self.env.push();
         self.tags.push();
 
-        let (ret_ty, _, _qualifiers) = try!(self.read_type_spec());
+        let (ret_ty, sclass, _qualifiers) = try!(self.read_type_spec());
         let (functy, name, param_names) = try!(self.read_declarator(ret_ty));
 
         // if qualifiers.q_constexpr {
@@ -231,8 +238,9 @@ impl<'a> Parser<'a> {
             AST::new(ASTKind::String(name.clone()), Pos::new(0, 0)),
         );
 
+        let body_pos = try!(self.lexer.peek()).pos;
         expect_symbol_error!(self, Symbol::OpeningBrace, "expected '('");
-        let body = try!(self.read_func_body(&functy));
+        let body = try!(self.read_func_body(&functy, body_pos));
 
         self.env.pop();
         self.tags.pop();
@@ -246,15 +254,17 @@ impl<'a> Parser<'a> {
                     param_names.unwrap()
                 },
                 name,
+                sclass,
                 Box::new(body),
             ),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_func_body(&mut self, _functy: &Type) -> ParseR<AST> {
-        self.read_compound_stmt()
+    fn read_func_body(&mut self, _functy: &Type, pos: Pos) -> ParseR<AST> {
+        self.read_compound_stmt(pos)
     }
-    fn read_compound_stmt(&mut self) -> ParseR<AST> {
+    // 'pos' is the position of '{'
+    fn read_compound_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         let mut stmts: Vec<AST> = Vec::new();
         loop {
             if try!(self.lexer.skip_symbol(Symbol::ClosingBrace).or_else(|eof| {
@@ -275,27 +285,28 @@ impl<'a> Parser<'a> {
                 }
             }
         }
-        Ok(AST::new(ASTKind::Block(stmts), Pos::new(0, 0)))
+        Ok(AST::new(ASTKind::Block(stmts), pos))
     }
     fn read_stmt(&mut self) -> ParseR<AST> {
         let tok = try!(self.lexer.get());
+        let pos = tok.pos.clone();
         if let &TokenKind::Keyword(ref keyw) = &tok.kind {
             match *keyw {
-                Keyword::If => return self.read_if_stmt(),
-                Keyword::For => return self.read_for_stmt(),
-                Keyword::While => return self.read_while_stmt(),
-                Keyword::Do => return self.read_do_while_stmt(),
-                Keyword::Switch => return self.read_switch_stmt(),
-                Keyword::Case => return self.read_case_label(),
-                Keyword::Default => return self.read_default_label(),
-                Keyword::Goto => return self.read_goto_stmt(),
-                Keyword::Continue => return self.read_continue_stmt(),
-                Keyword::Break => return self.read_break_stmt(),
-                Keyword::Return => return self.read_return_stmt(),
+                Keyword::If => return self.read_if_stmt(pos),
+                Keyword::For => return self.read_for_stmt(pos),
+                Keyword::While => return self.read_while_stmt(pos),
+                Keyword::Do => return self.read_do_while_stmt(pos),
+                Keyword::Switch => return self.read_switch_stmt(pos),
+                Keyword::Case => return self.read_case_label(pos),
+                Keyword::Default => return self.read_default_label(pos),
+                Keyword::Goto => return self.read_goto_stmt(pos),
+                Keyword::Continue => return self.read_continue_stmt(pos),
+                Keyword::Break => return self.read_break_stmt(pos),
+                Keyword::Return => return self.read_return_stmt(pos),
                 _ => {}
             }
         } else if let &TokenKind::Symbol(Symbol::OpeningBrace) = &tok.kind {
-            return self.read_compound_stmt();
+            return self.read_compound_stmt(pos);
         }
 
         if matches!(tok.kind, TokenKind::Identifier(_))
@@ -309,7 +320,7 @@ impl<'a> Parser<'a> {
         expect_symbol_error!(self, Symbol::Semicolon, "expected ';'");
         expr
     }
-    fn read_if_stmt(&mut self) -> ParseR<AST> {
+    fn read_if_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::OpeningParen, "expected '('");
         let cond = try!(self.read_expr());
         expect_symbol_error!(self, Symbol::ClosingParen, "expected ')'");
@@ -321,10 +332,10 @@ impl<'a> Parser<'a> {
         };
         Ok(AST::new(
             ASTKind::If(Box::new(cond), then_stmt, else_stmt),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_for_stmt(&mut self) -> ParseR<AST> {
+    fn read_for_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::OpeningParen, "expected '('");
         let init = try!(self.read_opt_decl_or_stmt());
         // TODO: make read_expr return Option<AST>.
@@ -345,20 +356,20 @@ impl<'a> Parser<'a> {
                 Box::new(step),
                 Box::new(body),
             ),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_while_stmt(&mut self) -> ParseR<AST> {
+    fn read_while_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::OpeningParen, "expected '('");
         let cond = try!(self.read_expr());
         expect_symbol_error!(self, Symbol::ClosingParen, "expected ')'");
         let body = try!(self.read_stmt());
         Ok(AST::new(
             ASTKind::While(Box::new(cond), Box::new(body)),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_do_while_stmt(&mut self) -> ParseR<AST> {
+    fn read_do_while_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         let body = try!(self.read_stmt());
         if !try!(self.lexer.skip_keyword(Keyword::While)) {
             let peek = self.lexer.peek();
@@ -370,52 +381,48 @@ impl<'a> Parser<'a> {
         expect_symbol_error!(self, Symbol::Semicolon, "expected ';'");
         Ok(AST::new(
             ASTKind::DoWhile(Box::new(cond), Box::new(body)),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_switch_stmt(&mut self) -> ParseR<AST> {
+    fn read_switch_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::OpeningParen, "expected '('");
         let cond = try!(self.read_expr());
         expect_symbol_error!(self, Symbol::ClosingParen, "expected ')'");
         let body = Box::new(try!(self.read_stmt()));
         Ok(AST::new(
             ASTKind::Switch(Box::new(cond), body),
-            Pos::new(0, 0),
+            pos,
         ))
     }
-    fn read_case_label(&mut self) -> ParseR<AST> {
+    fn read_case_label(&mut self, pos: Pos) -> ParseR<AST> {
         let expr = try!(self.read_expr());
         expect_symbol_error!(self, Symbol::Colon, "expected ':'");
-        Ok(AST::new(ASTKind::Case(Box::new(expr)), Pos::new(0, 0)))
+        Ok(AST::new(ASTKind::Case(Box::new(expr)), pos))
     }
-    fn read_default_label(&mut self) -> ParseR<AST> {
+    fn read_default_label(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::Colon, "expected ':'");
-        Ok(AST::new(ASTKind::DefaultL, Pos::new(0, 0)))
+        Ok(AST::new(ASTKind::DefaultL, pos))
     }
-    fn read_goto_stmt(&mut self) -> ParseR<AST> {
-        let pos = self.lexer.get_cur_pos();
+    fn read_goto_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         let label_name = ident_val!(try!(self.lexer.get()));
         expect_symbol_error!(self, Symbol::Semicolon, "expected ';'");
         Ok(AST::new(ASTKind::Goto(label_name), pos))
     }
     fn read_label(&mut self, tok: Token) -> ParseR<AST> {
-        let pos = self.lexer.get_cur_pos();
+        let pos = tok.pos.clone();
         let label_name = ident_val!(tok);
         expect_symbol_error!(self, Symbol::Colon, "expected ':'");
         Ok(AST::new(ASTKind::Label(label_name), pos))
     }
-    fn read_continue_stmt(&mut self) -> ParseR<AST> {
-        let pos = self.lexer.get_cur_pos();
+    fn read_continue_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::Semicolon, "expected ';'");
         Ok(AST::new(ASTKind::Continue, pos))
     }
-    fn read_break_stmt(&mut self) -> ParseR<AST> {
-        let pos = self.lexer.get_cur_pos();
+    fn read_break_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         expect_symbol_error!(self, Symbol::Semicolon, "expected ';'");
         Ok(AST::new(ASTKind::Break, pos))
     }
-    fn read_return_stmt(&mut self) -> ParseR<AST> {
-        let pos = self.lexer.get_cur_pos();
+    fn read_return_stmt(&mut self, pos: Pos) -> ParseR<AST> {
         if try!(self.lexer.skip_symbol(Symbol::Semicolon)) {
             Ok(AST::new(ASTKind::Return(None), pos))
         } else {
@@ -553,7 +560,7 @@ impl<'a> Parser<'a> {
     }
     fn is_string(&self, ty: &Type) -> bool {
         if let &Type::Array(ref elem_ty, _) = ty {
-            if matches!(**elem_ty, Type::Char(Sign::Signed)) {
+            if matches!(**elem_ty, Type::Char(_)) {
                 return true;
             }
         }
@@ -693,6 +700,7 @@ impl<'a> Parser<'a> {
         }
 
         loop {
+            let pos = try!(self.lexer.peek()).pos;
             let (mut ty, name, _) = try!(self.read_declarator(basety.clone())); // XXX
 
             if (qualifiers.q_constexpr || qualifiers.q_const)
@@ -721,7 +729,7 @@ impl<'a> Parser<'a> {
                 );
                 ast.push(AST::new(
                     ASTKind::VariableDecl(ty, name, sclass.clone(), init),
-                    self.lexer.get_cur_pos(),
+                    pos,
                 ));
             }
 
@@ -1007,9 +1015,14 @@ impl<'a> Parser<'a> {
         }
 
         // if sign is not expected,
-        //  default is Signed
+        //  default is Signed (but plain char depends on the target)
         if sign.is_none() {
-            sign = Some(Sign::Signed);
+            let is_char = kind == Some(PrimitiveType::Char);
+            sign = Some(if is_char && self.lexer.get_target().char_unsigned {
+                Sign::Unsigned
+            } else {
+                Sign::Signed
+            });
         }
 
         // TODO: add err handler
@@ -1089,8 +1102,9 @@ impl<'a> Parser<'a> {
                 let mut max_sz = 0;
                 for (i, field_decl) in (&fields).iter().enumerate() {
                     if let ASTKind::VariableDecl(ref ty, _, _, _) = field_decl.kind {
-                        if ty.calc_size() > max_sz {
-                            max_sz = ty.calc_size();
+                        let size = ty.calc_size(self.lexer.get_target());
+                        if size > max_sz {
+                            max_sz = size;
                             max_sz_ty_nth = i;
                         }
                     }
@@ -1576,7 +1590,7 @@ impl<'a> Parser<'a> {
             let (ty, _, _) = try!(self.read_declarator(basety));
             try!(self.lexer.skip_symbol(Symbol::ClosingParen));
             return Ok(AST::new(
-                ASTKind::Int(ty.calc_size() as i64, Bits::Bits32),
+                ASTKind::Int(ty.calc_size(self.lexer.get_target()) as i64, Bits::Bits32),
                 self.lexer.get_cur_pos(),
             ));
         }
@@ -1695,18 +1709,18 @@ impl<'a> Parser<'a> {
         };
 
         match tok.kind.clone() {
-            TokenKind::IntNumber(n, bits) => {
-                Ok(AST::new(ASTKind::Int(n, bits), self.lexer.get_cur_pos()))
-            }
-            TokenKind::FloatNumber(f) => Ok(AST::new(ASTKind::Float(f), self.lexer.get_cur_pos())),
+            TokenKind::IntNumber(n, bits, _) => Ok(AST::new(ASTKind::Int(n, bits), tok.pos.clone())),
+            TokenKind::FloatNumber(f) => Ok(AST::new(ASTKind::Float(f), tok.pos.clone())),
             TokenKind::Identifier(ident) => {
                 if let Some(ast) = self.env.get(ident.as_str()) {
+                    // the declaration in env has no position
+                    let mut ast = (*ast).clone();
+                    ast.pos = tok.pos.clone();
                     return match ast.kind {
-                        ASTKind::Variable(_, _) => Ok(AST::new(
-                            ASTKind::Load(Box::new((*ast).clone())),
-                            self.lexer.get_cur_pos(),
-                        )),
-                        _ => Ok((*ast).clone()),
+                        ASTKind::Variable(_, _) => {
+                            Ok(AST::new(ASTKind::Load(Box::new(ast)), tok.pos.clone()))
+                        }
+                        _ => Ok(ast),
                     };
                 }
                 self.show_error_token(
@@ -1715,8 +1729,8 @@ impl<'a> Parser<'a> {
                 );
                 Err(Error::Something)
             }
-            TokenKind::String(s) => Ok(AST::new(ASTKind::String(s), self.lexer.get_cur_pos())),
-            TokenKind::Char(ch) => Ok(AST::new(ASTKind::Char(ch as i32), self.lexer.get_cur_pos())),
+            TokenKind::String(s) => Ok(AST::new(ASTKind::String(s), tok.pos.clone())),
+            TokenKind::Char(ch) => Ok(AST::new(ASTKind::Char(ch as i32), tok.pos.clone())),
             TokenKind::Symbol(sym) => match sym {
                 Symbol::OpeningParen => {
                     let expr = self.read_expr();
@@ -1762,7 +1776,7 @@ impl<'a> Parser<'a> {
         let rhs_ty = cast(try!(self.get_expr_returning_ty(rhs)));
         if matches!(lhs_ty, Type::Ptr(_)) && matches!(rhs_ty, Type::Ptr(_)) {
             if matches!(op, &node::CBinOps::Sub) {
-                return Ok(Type::Long(Sign::Signed));
+                return Ok(Type::ptrdiff(self.lexer.get_target()));
             }
             return Ok(Type::Int(Sign::Signed));
         }
@@ -1777,14 +1791,24 @@ impl<'a> Parser<'a> {
     fn get_expr_returning_ty(&mut self, ast: &AST) -> ParseR<Type> {
         let size = match ast.kind {
             ASTKind::Int(_, Bits::Bits32) => Type::Int(Sign::Signed),
-            ASTKind::Int(_, Bits::Bits64) => Type::Long(Sign::Signed),
+            ASTKind::Int(_, Bits::Bits64) => if self.lexer.get_target().sizes.long == 8 {
+                Type::Long(Sign::Signed)
+            } else {
+                Type::LLong(Sign::Signed)
+            },
             ASTKind::Float(_) => Type::Double,
-            ASTKind::Char(_) => Type::Char(Sign::Signed),
-            ASTKind::String(ref s) => {
-                Type::Array(Box::new(Type::Char(Sign::Signed)), s.len() as i32 + 1)
-            }
-            ASTKind::Load(ref v) => {
-                (*try!(self.get_expr_returning_ty(&*v)).get_elem_ty().unwrap()).clone()
+            ASTKind::Char(_) => Type::plain_char(self.lexer.get_target()),
+            ASTKind::String(ref s) => Type::Array(
+                Box::new(Type::plain_char(self.lexer.get_target())),
+                s.len() as i32 + 1,
+            ),
+            ASTKind::Load(ref v) | ASTKind::UnaryOp(ref v, node::CUnaryOps::Deref) => {
+                match try!(self.get_expr_returning_ty(&*v)).get_elem_ty() {
+                    Some(elem_ty) => elem_ty.clone(),
+                    None => {
+                        return Err(self.expr_ty_error(ast, "indirection requires pointer operand"))
+                    }
+                }
             }
             ASTKind::Variable(ref ty, _) => Type::Ptr(Box::new((*ty).clone())),
             ASTKind::UnaryOp(_, node::CUnaryOps::LNot) => Type::Int(Sign::Signed),
@@ -1794,17 +1818,17 @@ impl<'a> Parser<'a> {
             | ASTKind::UnaryOp(ref expr, node::CUnaryOps::BNot) => {
                 try!(self.get_expr_returning_ty(&*expr))
             }
-            ASTKind::UnaryOp(ref expr, node::CUnaryOps::Deref) => (*try!(
-                self.get_expr_returning_ty(&*expr)
-            ).get_elem_ty()
-                .unwrap())
-                .clone(),
             ASTKind::UnaryOp(ref expr, node::CUnaryOps::Addr) => {
                 Type::Ptr(Box::new(try!(self.get_expr_returning_ty(&*expr))))
             }
             ASTKind::StructRef(ref expr, ref name) => {
-                let ty = try!(self.get_expr_returning_ty(expr));
-                Type::Ptr(Box::new((*ty.get_field_ty(name.as_str()).unwrap()).clone()))
+                match try!(self.get_expr_returning_ty(expr)).get_field_ty(name.as_str()) {
+                    Some(field_ty) => Type::Ptr(Box::new(field_ty.clone())),
+                    None => {
+                        let msg = format!("no member named '{}'", name);
+                        return Err(self.expr_ty_error(ast, msg.as_str()));
+                    }
+                }
             }
             ASTKind::TypeCast(_, ref ty) => ty.clone(),
             ASTKind::BinaryOp(ref lhs, ref rhs, ref op) => {
@@ -1812,16 +1836,25 @@ impl<'a> Parser<'a> {
             }
             ASTKind::TernaryOp(_, ref then, _) => try!(self.get_expr_returning_ty(&*then)),
             ASTKind::FuncCall(ref func, _) => {
-                let func_ty = try!(self.get_expr_returning_ty(func));
-                (*func_ty.get_return_ty().unwrap()).clone()
+                match try!(self.get_expr_returning_ty(func)).get_return_ty() {
+                    Some(ret_ty) => ret_ty.clone(),
+                    None => return Err(self.expr_ty_error(ast, "called object is not a function")),
+                }
+            }
+            _ => {
+                let msg = "the type of this expression is not supported";
+                return Err(self.expr_ty_error(ast, msg));
             }
-            _ => panic!(format!("unsupported: {:?}", ast.kind)),
         };
         Ok(size)
     }
+    fn expr_ty_error(&mut self, ast: &AST, msg: &str) -> Error {
+        self.show_error_at(&ast.pos, msg);
+        Error::Something
+    }
     fn calc_sizeof(&mut self, ast: &AST) -> ParseR<usize> {
         let ty = try!(self.get_expr_returning_ty(ast));
-        Ok(ty.calc_size())
+        Ok(ty.calc_size(self.lexer.get_target()))
     }
 
     fn cast_ast(&mut self, expr: &AST, ty: &Type) -> AST {
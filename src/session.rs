@@ -0,0 +1,353 @@
+use lexer;
+use parser;
+use codegen;
+use node;
+use ast_dump;
+use common::{MacroOption, Options, Output, OutputKind};
+use error::{Diagnostic, Diagnostics};
+use file_provider::RealFiles;
+use std::any::Any;
+use std::io::{stdin, Read};
+use std::panic;
+use std::sync::Arc;
+
+pub const STDIN: &'static str = "-";
+const STDIN_FILENAME: &'static str = "<stdin>";
+
+// One compilation of a source file. A session owns its lexer (and so the parser reading from
+// it) and makes a codegen with its own llvm context and module, and shares nothing with other
+// sessions. So several sessions can run at the same time on different threads.
+pub struct Session {
+    filename: String,
+    // false if the source is given as text (or read from stdin)
+    on_disk: bool,
+    options: Options,
+    lexer: lexer::Lexer,
+    // reported by the lexer so far. they don't stop the compilation
+    warnings: Vec<Diagnostic>,
+}
+
+// fails to compile if a session or its options can't be moved to another thread
+#[allow(dead_code)]
+fn assert_send() {
+    fn is_send<T: Send>() {}
+    is_send::<Session>();
+    is_send::<Options>();
+}
+
+impl Session {
+    // 'source' is a file name. '-' means stdin
+    pub fn new(source: &str, options: &Options) -> Result<Session, Diagnostics> {
+        if source != STDIN {
+            return Ok(Session::with_text(source, None, options));
+        }
+        let mut text = String::new();
+        match stdin().read_to_string(&mut text) {
+            Ok(_) => Ok(Session::with_text(STDIN_FILENAME, Some(text), options)),
+            Err(e) => Err(Diagnostics::new(vec![
+                Diagnostic::without_pos(format!("cannot read stdin: {}", e).as_str()),
+            ])),
+        }
+    }
+
+    // 'text' is the content of the file 'filename', which doesn't have to exist
+    pub fn from_source(filename: &str, text: &str, options: &Options) -> Session {
+        Session::with_text(filename, Some(text.to_string()), options)
+    }
+
+    fn with_text(filename: &str, text: Option<String>, options: &Options) -> Session {
+        let files = match options.file_provider {
+            Some(ref files) => files.clone(),
+            None => Arc::new(RealFiles),
+        };
+        let on_disk = text.is_none();
+        let mut lexer = lexer::Lexer::with_file_provider(
+            filename.to_string(),
+            text,
+            files,
+            options.target.clone(),
+        );
+        for opt in &options.macros {
+            match *opt {
+                MacroOption::Define(ref definition) => lexer.define_macro(definition),
+                MacroOption::Undef(ref name) => lexer.undef_macro(name),
+            }
+        }
+        lexer.set_include_path(options.include_path.clone());
+        Session {
+            filename: filename.to_string(),
+            on_disk: on_disk,
+            options: options.clone(),
+            lexer: lexer,
+            warnings: Vec::new(),
+        }
+    }
+
+    // compile the source into 'options.output_kind' in memory.
+    // 'Executable' gives an object file since linking is left to the caller.
+    // this never exits the process: all errors (even internal ones) are returned as Diagnostics.
+    pub fn compile(&mut self) -> Result<Output, Diagnostics> {
+        self.guard(|session| session.do_compile())
+    }
+
+    // a bug of rucc must not take down the host process. the public entry points run in this,
+    // and a panic is reported as an internal compiler error
+    fn guard<T, F>(&mut self, f: F) -> Result<T, Diagnostics>
+    where
+        F: FnOnce(&mut Session) -> Result<T, Diagnostics>,
+    {
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(cause) => Err(internal_error(cause)),
+        }
+    }
+
+    fn do_compile(&mut self) -> Result<Output, Diagnostics> {
+        let data = match self.options.output_kind {
+            OutputKind::Preprocessed => try!(self.do_preprocess()).into_bytes(),
+            OutputKind::Dependencies => {
+                try!(self.do_preprocess());
+                Vec::new()
+            }
+            OutputKind::AstDump(format) => {
+                let asts = try!(self.do_parse());
+                ast_dump::dump(&asts, self.filename.as_str(), format).into_bytes()
+            }
+            kind => {
+                let mut codegen = try!(self.do_codegen());
+                try!(unsafe { emit(&mut codegen, kind) }.map_err(codegen_error))
+            }
+        };
+        Ok(Output {
+            kind: self.options.output_kind,
+            data: data,
+            dependencies: self.dependencies(),
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    // the token stream of the preprocessor as C source.
+    // '# <line> "<file>"' markers are put when the current file changes (e.g. #include)
+    // or too many lines are skipped.
+    pub fn preprocess(&mut self) -> Result<String, Diagnostics> {
+        self.guard(|session| session.do_preprocess())
+    }
+
+    fn do_preprocess(&mut self) -> Result<String, Diagnostics> {
+        let mut output = "".to_string();
+        let mut cur_filename = "".to_string();
+        let mut cur_line = 0;
+        loop {
+            let tok = match self.lexer.get_preprocessed() {
+                Ok(tok) => tok,
+                Err(_) => break,
+            };
+            let filename = self.lexer.get_filename();
+            if filename != cur_filename || tok.pos.line > cur_line + 8 {
+                if !output.is_empty() {
+                    output.push('\n');
+                }
+                output.push_str(format!("# {} \"{}\"\n", tok.pos.line, filename).as_str());
+                cur_filename = filename;
+                cur_line = tok.pos.line;
+            } else if tok.pos.line > cur_line {
+                for _ in cur_line..tok.pos.line {
+                    output.push('\n');
+                }
+                cur_line = tok.pos.line;
+            } else if tok.space {
+                output.push(' ');
+            }
+            output.push_str(tok.to_c_text().as_str());
+        }
+        output.push('\n');
+        try!(self.check_diagnostics());
+        Ok(output)
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<node::AST>, Diagnostics> {
+        self.guard(|session| session.do_parse())
+    }
+
+    fn do_parse(&mut self) -> Result<Vec<node::AST>, Diagnostics> {
+        let mut asts = Vec::new();
+        parser::Parser::new(&mut self.lexer).run(&mut asts);
+        try!(self.check_diagnostics());
+        Ok(asts)
+    }
+
+    // parse -> codegen. the returned codegen has the optimized llvm module
+    pub fn codegen(&mut self) -> Result<codegen::Codegen, Diagnostics> {
+        self.guard(|session| session.do_codegen())
+    }
+
+    fn do_codegen(&mut self) -> Result<codegen::Codegen, Diagnostics> {
+        unsafe {
+            let mut nodes = Vec::new();
+            let mut codegen =
+                codegen::Codegen::new(self.filename.as_str(), self.options.target.clone());
+            try!(codegen.init_target().map_err(codegen_error));
+            codegen.set_opt_level(self.options.opt_level);
+            if self.options.llvm_line_comments {
+                codegen.enable_line_comments();
+            }
+
+            {
+                let mut parser = parser::Parser::new(&mut self.lexer);
+                loop {
+                    match parser.read_toplevel(&mut nodes) {
+                        Err(parser::Error::EOF) => break,
+                        Err(_) => continue,
+                        _ => {}
+                    }
+                    match codegen.run(&nodes) {
+                        Ok(_) => {}
+                        // TODO: implement err handler for codegen
+                        Err(codegen::Error::MsgWithPos(msg, pos)) => {
+                            let code = parser.lexer.get_surrounding_code_with_err_point(pos.pos);
+                            parser.lexer.add_error(pos.line, msg.as_str(), Some(code));
+                            break;
+                        }
+                        Err(codegen::Error::Msg(msg)) => {
+                            parser.lexer.add_error(0, msg.as_str(), None);
+                            break;
+                        }
+                    }
+                    nodes.clear();
+                }
+            }
+            try!(self.check_diagnostics());
+            try!(codegen.optimize().map_err(codegen_error));
+            Ok(codegen)
+        }
+    }
+
+    // the source file and the headers opened so far
+    // (without system headers for -MM and -MMD)
+    pub fn dependencies(&self) -> Vec<String> {
+        let mut dependencies = Vec::new();
+        if self.on_disk {
+            dependencies.push(self.filename.clone());
+        }
+        dependencies.extend(
+            self.lexer.get_included_files(
+                self.options
+                    .dependency
+                    .as_ref()
+                    .map_or(true, |dependency| dependency.system_headers),
+            ),
+        );
+        dependencies
+    }
+
+    pub fn warnings(&self) -> &Vec<Diagnostic> {
+        &self.warnings
+    }
+
+    // the errors recorded in the lexer, if any. the warnings are kept for the output
+    fn check_diagnostics(&mut self) -> Result<(), Diagnostics> {
+        let diagnostics = self.lexer.take_diagnostics();
+        if diagnostics.iter().any(|diag| diag.is_error()) {
+            let mut all = self.warnings.clone();
+            all.extend(diagnostics);
+            Err(Diagnostics::new(all))
+        } else {
+            self.warnings.extend(diagnostics);
+            Ok(())
+        }
+    }
+}
+
+unsafe fn emit(codegen: &mut codegen::Codegen, output_kind: OutputKind) -> Result<Vec<u8>, codegen::Error> {
+    match output_kind {
+        OutputKind::Preprocessed | OutputKind::AstDump(_) | OutputKind::Dependencies => {
+            unreachable!()
+        }
+        OutputKind::Bitcode => Ok(codegen.llvm_bitcode()),
+        OutputKind::LLVMIR => Ok(codegen.llvm_ir().into_bytes()),
+        OutputKind::Assembly => codegen.assembly(),
+        OutputKind::Object | OutputKind::Executable => codegen.object_code(),
+    }
+}
+
+pub fn internal_error(cause: Box<Any + Send>) -> Diagnostics {
+    let msg = if let Some(msg) = cause.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = cause.downcast_ref::<String>() {
+        msg.to_string()
+    } else {
+        "unknown error".to_string()
+    };
+    Diagnostics::new(vec![
+        Diagnostic::without_pos(format!("internal compiler error: {}", msg).as_str()),
+    ])
+}
+
+pub fn codegen_error(err: codegen::Error) -> Diagnostics {
+    match err {
+        codegen::Error::Msg(msg) | codegen::Error::MsgWithPos(msg, _) => {
+            Diagnostics::new(vec![Diagnostic::without_pos(msg.as_str())])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use target::Target;
+
+    const VARARGS: &'static str = "#include <stdarg.h>\n\
+                                   struct S { long a, b, c; };\n\
+                                   int sum(int n, ...) {\n\
+                                       va_list ap, aq;\n\
+                                       va_start(ap, n);\n\
+                                       va_copy(aq, ap);\n\
+                                       int s = 0;\n\
+                                       for (int i = 0; i < n; i++) s += va_arg(ap, int);\n\
+                                       double d = va_arg(ap, double);\n\
+                                       struct S st = va_arg(ap, struct S);\n\
+                                       char *p = va_arg(ap, char *);\n\
+                                       va_end(aq);\n\
+                                       va_end(ap);\n\
+                                       return s + d + st.a + *p;\n\
+                                   }\n";
+
+    fn parse_on(triple: &str, text: &str) -> Result<Vec<node::AST>, Diagnostics> {
+        let options = Options::with_target(Target::from_triple(triple).unwrap());
+        Session::from_source("test.c", text, &options).parse()
+    }
+
+    #[test]
+    fn stdarg_on_every_target() {
+        for triple in &[
+            "x86_64-unknown-linux-gnu",
+            "aarch64-unknown-linux-gnu",
+            "riscv64-unknown-linux-gnu",
+            "i386-unknown-linux-gnu",
+        ] {
+            assert!(parse_on(triple, VARARGS).is_ok(), "{}", triple);
+        }
+    }
+
+    #[test]
+    fn compile_on_two_threads() {
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                thread::spawn(move || {
+                    let target = Target::from_triple("x86_64-unknown-linux-gnu").unwrap();
+                    let mut options = Options::with_target(target);
+                    options.output_kind = OutputKind::LLVMIR;
+                    let text = format!("int g{0} = {0};\nint f{0}() {{ return g{0} * 2; }}\n", i);
+                    Session::from_source(format!("t{}.c", i).as_str(), text.as_str(), &options)
+                        .compile()
+                        .map(|output| String::from_utf8(output.data).unwrap())
+                })
+            })
+            .collect();
+        for (i, t) in threads.into_iter().enumerate() {
+            let ir = t.join().unwrap().unwrap();
+            assert!(ir.contains(format!("@f{}(", i).as_str()), "{}", ir);
+        }
+    }
+}
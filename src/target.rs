@@ -0,0 +1,544 @@
+// The description of the target machine.
+// Type sizes (sizeof), predefined macros and the llvm data layout are all made from this,
+// so they can't disagree.
+
+extern crate llvm_sys as llvm;
+
+use self::llvm::core::LLVMDisposeMessage;
+use self::llvm::target_machine::{LLVMGetDefaultTargetTriple, LLVMNormalizeTargetTriple};
+use std::ffi::CStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arch {
+    X86, // i386
+    X86_64,
+    AArch64,
+    RiscV64,
+}
+
+// sizes of types in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeSizes {
+    pub short: usize,
+    pub int: usize,
+    pub long: usize,
+    pub long_long: usize,
+    pub float: usize,
+    pub double: usize,
+    pub long_double: usize,
+    pub pointer: usize,
+    // scalar types are aligned to min(size, max_align) in structs
+    pub max_align: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub triple: String,
+    pub arch: Arch,
+    pub sizes: TypeSizes,
+    // plain char is unsigned on aarch64 and riscv64
+    pub char_unsigned: bool,
+    // -mcpu (or -march on x86). None means the host cpu for native targets, otherwise generic
+    pub cpu: Option<String>,
+    // llvm target features like '+avx2' (-march)
+    pub features: Vec<String>,
+}
+
+// LP64: long and pointers are 64 bits
+const LP64: TypeSizes = TypeSizes {
+    short: 2,
+    int: 4,
+    long: 8,
+    long_long: 8,
+    float: 4,
+    double: 8,
+    // long double is the same as double in rucc on every target. so it is not compatible
+    // with the long double of the platform (80 bit on x86, 128 bit on aarch64 and riscv64)
+    long_double: 8,
+    pointer: 8,
+    max_align: 8,
+};
+
+// ILP32: int, long and pointers are 32 bits (i386 and x32)
+const ILP32: TypeSizes = TypeSizes {
+    short: 2,
+    int: 4,
+    long: 4,
+    long_long: 8,
+    float: 4,
+    double: 8,
+    long_double: 8,
+    pointer: 4,
+    max_align: 8,
+};
+
+impl Target {
+    // the machine running rucc, described by the default triple of llvm
+    // (e.g. x86_64-pc-linux-gnu). an unsupported host is an error: rucc doesn't guess
+    pub fn host() -> Result<Target, String> {
+        let triple = host_triple();
+        Target::from_triple(triple.as_str())
+            .map_err(|msg| format!("unsupported host '{}': {}", triple, msg))
+    }
+
+    // --target: 'arch-vendor-os[-env]' (e.g. aarch64-linux-gnu, riscv64-unknown-linux-gnu)
+    pub fn from_triple(triple: &str) -> Result<Target, String> {
+        let arch = match triple.split('-').next().unwrap() {
+            "i386" | "i486" | "i586" | "i686" => Arch::X86,
+            "x86_64" | "amd64" => Arch::X86_64,
+            "aarch64" | "arm64" => Arch::AArch64,
+            "riscv64" => Arch::RiscV64,
+            arch => return Err(format!("unsupported target architecture '{}'", arch)),
+        };
+        let sizes = match arch {
+            // long long and double are 4 byte aligned in i386 structs
+            Arch::X86 => TypeSizes {
+                max_align: 4,
+                ..ILP32
+            },
+            // x32
+            Arch::X86_64 if triple.ends_with("gnux32") => ILP32,
+            _ => LP64,
+        };
+        Ok(Target {
+            triple: triple.to_string(),
+            arch: arch,
+            sizes: sizes,
+            char_unsigned: arch == Arch::AArch64 || arch == Arch::RiscV64,
+            cpu: None,
+            features: Vec::new(),
+        })
+    }
+
+    // whether the code for this target runs on the machine running rucc
+    pub fn is_native(&self) -> bool {
+        match Target::host() {
+            Ok(host) => self.arch == host.arch && self.sizes == host.sizes,
+            Err(_) => false,
+        }
+    }
+
+    // the system 'cc' can link for the host, and for i386 on x86_64 hosts (with '-m32')
+    pub fn can_link_on_host(&self) -> bool {
+        self.is_native()
+            || (self.arch == Arch::X86
+                && Target::host().map_or(false, |host| host.arch == Arch::X86_64))
+    }
+
+    // -m32: x86_64 -> i386
+    pub fn set_m32(&mut self) -> Result<(), String> {
+        match self.arch {
+            Arch::X86 => Ok(()),
+            Arch::X86_64 => {
+                let rest = self.triple.splitn(2, '-').nth(1).unwrap_or("");
+                let triple = format!("i386-{}", rest.trim_right_matches("x32"));
+                let cpu = self.cpu.take();
+                let features = self.features.clone();
+                *self = try!(Target::from_triple(triple.as_str()));
+                self.cpu = cpu;
+                self.features = features;
+                Ok(())
+            }
+            _ => Err(format!("'-m32' is not supported for '{}'", self.triple)),
+        }
+    }
+
+    // -march.
+    //   x86_64:  a cpu name (e.g. haswell)
+    //   aarch64: armv8[.N]-a followed by '+ext' or '+noext' (e.g. armv8.2-a+crypto)
+    //   riscv64: an isa string (e.g. rv64gc, rv64imac_zba)
+    pub fn set_march(&mut self, march: &str) -> Result<(), String> {
+        let features = match self.arch {
+            Arch::X86 | Arch::X86_64 => {
+                self.cpu = Some(march.to_string());
+                return Ok(());
+            }
+            Arch::AArch64 => aarch64_features(march),
+            Arch::RiscV64 => riscv_features(march),
+        };
+        match features {
+            Some(features) => {
+                self.features = features;
+                Ok(())
+            }
+            None => Err(format!("invalid -march '{}' for '{}'", march, self.triple)),
+        }
+    }
+    // -mcpu: an llvm cpu name (e.g. cortex-a53, sifive-u74)
+    pub fn set_cpu(&mut self, cpu: &str) {
+        self.cpu = Some(cpu.to_string());
+    }
+
+    pub fn is_linux(&self) -> bool {
+        self.triple.contains("linux")
+    }
+
+    // the name of the directories for this target on debian style multiarch systems
+    // (e.g. /usr/include/aarch64-linux-gnu). None if the target is not linux
+    pub fn multiarch(&self) -> Option<String> {
+        if !self.is_linux() {
+            return None;
+        }
+        Some(
+            match self.arch {
+                Arch::X86 => "i386-linux-gnu",
+                Arch::X86_64 if self.sizes.pointer == 4 => "x86_64-linux-gnux32",
+                Arch::X86_64 => "x86_64-linux-gnu",
+                Arch::AArch64 => "aarch64-linux-gnu",
+                Arch::RiscV64 => "riscv64-linux-gnu",
+            }.to_string(),
+        )
+    }
+
+    // the integer type that has the same size as pointers.
+    // int on ILP32 (i386 and x32) as GCC does, though long has the same size
+    fn intptr_type(&self) -> &'static str {
+        if self.sizes.int == self.sizes.pointer {
+            "int"
+        } else if self.sizes.long == self.sizes.pointer {
+            "long"
+        } else {
+            "long long"
+        }
+    }
+    // wchar_t of the linux abi of each architecture
+    fn wchar_type(&self) -> &'static str {
+        match self.arch {
+            Arch::X86 => "long",
+            Arch::AArch64 => "unsigned int",
+            Arch::X86_64 | Arch::RiscV64 => "int",
+        }
+    }
+    fn int64_type(&self) -> &'static str {
+        if self.sizes.long == 8 {
+            "long"
+        } else {
+            "long long"
+        }
+    }
+
+    // '#define's read before rucc.h
+    pub fn predefined_macros(&self) -> String {
+        let mut macros = Vec::new();
+        {
+            let mut define =
+                |name: &str, val: String| macros.push(format!("#define {} {}", name, val));
+
+            let sizes = &self.sizes;
+            define("__CHAR_BIT__", "8".to_string());
+            define("__SIZEOF_SHORT__", sizes.short.to_string());
+            define("__SIZEOF_INT__", sizes.int.to_string());
+            define("__SIZEOF_LONG__", sizes.long.to_string());
+            define("__SIZEOF_LONG_LONG__", sizes.long_long.to_string());
+            define("__SIZEOF_FLOAT__", sizes.float.to_string());
+            define("__SIZEOF_DOUBLE__", sizes.double.to_string());
+            define("__SIZEOF_LONG_DOUBLE__", sizes.long_double.to_string());
+            define("__SIZEOF_POINTER__", sizes.pointer.to_string());
+            define("__SIZEOF_SIZE_T__", sizes.pointer.to_string());
+            define("__SIZEOF_PTRDIFF_T__", sizes.pointer.to_string());
+            let wchar = self.wchar_type();
+            let wchar_size = if wchar == "long" { sizes.long } else { sizes.int };
+            define("__SIZEOF_WCHAR_T__", wchar_size.to_string());
+
+            // <limits.h> and <stdint.h> are made from these
+            define("__SCHAR_MAX__", signed_max(1, ""));
+            define("__SHRT_MAX__", signed_max(sizes.short, ""));
+            define("__INT_MAX__", signed_max(sizes.int, ""));
+            define("__LONG_MAX__", signed_max(sizes.long, "L"));
+            define("__LONG_LONG_MAX__", signed_max(sizes.long_long, "LL"));
+            if wchar.starts_with("unsigned") {
+                define("__WCHAR_MAX__", unsigned_max(wchar_size, "U"));
+                define("__WCHAR_MIN__", "0U".to_string());
+            } else {
+                define("__WCHAR_MAX__", signed_max(wchar_size, int_suffix(wchar)));
+                define("__WCHAR_MIN__", "(-__WCHAR_MAX__ - 1)".to_string());
+            }
+            if self.char_unsigned {
+                define("__CHAR_UNSIGNED__", "1".to_string());
+            }
+
+            let intptr = self.intptr_type();
+            let intptr_suffix = int_suffix(intptr);
+            let uintptr_suffix = format!("U{}", intptr_suffix);
+            define("__SIZE_TYPE__", format!("unsigned {}", intptr));
+            define("__PTRDIFF_TYPE__", intptr.to_string());
+            define("__INTPTR_TYPE__", intptr.to_string());
+            define("__UINTPTR_TYPE__", format!("unsigned {}", intptr));
+            define("__WCHAR_TYPE__", wchar.to_string());
+            define("__SIZE_MAX__", unsigned_max(sizes.pointer, uintptr_suffix.as_str()));
+            define("__PTRDIFF_MAX__", signed_max(sizes.pointer, intptr_suffix));
+            define("__INTPTR_MAX__", signed_max(sizes.pointer, intptr_suffix));
+            define("__UINTPTR_MAX__", unsigned_max(sizes.pointer, uintptr_suffix.as_str()));
+
+            let int64 = self.int64_type();
+            let int64_suffix = int_suffix(int64);
+            define("__INT8_TYPE__", "signed char".to_string());
+            define("__INT16_TYPE__", "short".to_string());
+            define("__INT32_TYPE__", "int".to_string());
+            define("__INT64_TYPE__", int64.to_string());
+            define("__UINT8_TYPE__", "unsigned char".to_string());
+            define("__UINT16_TYPE__", "unsigned short".to_string());
+            define("__UINT32_TYPE__", "unsigned int".to_string());
+            define("__UINT64_TYPE__", format!("unsigned {}", int64));
+            define("__INTMAX_TYPE__", int64.to_string());
+            define("__UINTMAX_TYPE__", format!("unsigned {}", int64));
+            define("__INTMAX_MAX__", signed_max(8, int64_suffix));
+            define("__UINTMAX_MAX__", unsigned_max(8, format!("U{}", int64_suffix).as_str()));
+
+            if sizes.long == 8 && sizes.pointer == 8 {
+                define("_LP64", "1".to_string());
+                define("__LP64__", "1".to_string());
+            } else if sizes.int == 4 && sizes.long == 4 && sizes.pointer == 4 {
+                define("_ILP32", "1".to_string());
+                define("__ILP32__", "1".to_string());
+            }
+
+            match self.arch {
+                Arch::X86 => for name in &["__i386", "__i386__", "i386"] {
+                    define(name, "1".to_string());
+                },
+                Arch::X86_64 => for name in &["__x86_64", "__x86_64__", "__amd64", "__amd64__"] {
+                    define(name, "1".to_string());
+                },
+                Arch::AArch64 => {
+                    define("__aarch64__", "1".to_string());
+                    define("__AARCH64EL__", "1".to_string());
+                    define("__ARM_64BIT_STATE", "1".to_string());
+                    define("__ARM_ARCH", "8".to_string());
+                    define("__ARM_ARCH_ISA_A64", "1".to_string());
+                }
+                Arch::RiscV64 => {
+                    define("__riscv", "1".to_string());
+                    define("__riscv_xlen", "64".to_string());
+                }
+            }
+
+            if self.is_linux() {
+                for name in &[
+                    "__ELF__",
+                    "__gnu_linux__",
+                    "__linux",
+                    "__linux__",
+                    "linux",
+                    "__unix",
+                    "__unix__",
+                ] {
+                    define(name, "1".to_string());
+                }
+            }
+        }
+        macros.push("".to_string());
+        macros.join("\n")
+    }
+}
+
+fn host_triple() -> String {
+    unsafe {
+        let triple = LLVMGetDefaultTargetTriple();
+        let normalized = LLVMNormalizeTargetTriple(triple);
+        LLVMDisposeMessage(triple);
+        let host = CStr::from_ptr(normalized).to_string_lossy().into_owned();
+        LLVMDisposeMessage(normalized);
+        host
+    }
+}
+
+// armv8.2-a+crypto+nofp -> +v8.2a, +crypto, -fp
+fn aarch64_features(march: &str) -> Option<Vec<String>> {
+    let mut exts = march.split('+');
+    let version = match exts.next().unwrap() {
+        "armv8-a" => None,
+        arch if arch.starts_with("armv8.") && arch.ends_with("-a") => {
+            Some(format!("+v{}a", &arch[4..arch.len() - 2]))
+        }
+        _ => return None,
+    };
+    let mut features: Vec<String> = version.into_iter().collect();
+    for ext in exts {
+        if ext.is_empty() {
+            return None;
+        }
+        features.push(if ext.starts_with("no") {
+            format!("-{}", &ext[2..])
+        } else {
+            format!("+{}", ext)
+        });
+    }
+    Some(features)
+}
+
+// rv64gc_zba -> +m, +a, +f, +d, +c, +zba
+fn riscv_features(march: &str) -> Option<Vec<String>> {
+    if !march.starts_with("rv64") {
+        return None;
+    }
+    let mut parts = march[4..].split('_');
+    let mut features = Vec::new();
+    for ext in parts.next().unwrap().chars() {
+        match ext {
+            'i' => {}
+            'g' => for ext in &["m", "a", "f", "d"] {
+                features.push(format!("+{}", ext));
+            },
+            'm' | 'a' | 'f' | 'd' | 'c' => features.push(format!("+{}", ext)),
+            _ => return None,
+        }
+    }
+    for ext in parts {
+        if ext.is_empty() {
+            return None;
+        }
+        features.push(format!("+{}", ext));
+    }
+    Some(features)
+}
+
+fn int_suffix(ty: &str) -> &'static str {
+    match ty {
+        "long" => "L",
+        "long long" => "LL",
+        _ => "",
+    }
+}
+
+fn signed_max(size: usize, suffix: &str) -> String {
+    format!("{}{}", (1u64 << (size * 8 - 1)) - 1, suffix)
+}
+
+fn unsigned_max(size: usize, suffix: &str) -> String {
+    let max = if size >= 8 {
+        u64::max_value()
+    } else {
+        (1u64 << (size * 8)) - 1
+    };
+    format!("{}{}", max, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(triple: &str) -> Target {
+        Target::from_triple(triple).unwrap()
+    }
+    fn predefined(triple: &str) -> Vec<String> {
+        target(triple)
+            .predefined_macros()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+    fn assert_defined(defines: &Vec<String>, name: &str, val: &str) {
+        let define = format!("#define {} {}", name, val);
+        assert!(defines.contains(&define), "'{}' is not in {:?}", define, defines);
+    }
+    fn assert_undefined(defines: &Vec<String>, name: &str) {
+        let prefix = format!("#define {} ", name);
+        assert!(!defines.iter().any(|define| define.starts_with(prefix.as_str())));
+    }
+
+    #[test]
+    fn march_x86() {
+        let mut x86_64 = target("x86_64-linux-gnu");
+        assert_eq!(x86_64.set_march("haswell"), Ok(()));
+        assert_eq!(x86_64.cpu, Some("haswell".to_string()));
+        assert!(x86_64.features.is_empty());
+    }
+
+    #[test]
+    fn march_aarch64() {
+        let mut aarch64 = target("aarch64-linux-gnu");
+        assert_eq!(aarch64.set_march("armv8-a"), Ok(()));
+        assert!(aarch64.features.is_empty());
+        assert_eq!(aarch64.set_march("armv8.2-a+crypto+nofp"), Ok(()));
+        assert_eq!(aarch64.features, vec!["+v8.2a", "+crypto", "-fp"]);
+        assert_eq!(aarch64.cpu, None);
+        for march in &["armv7-a", "armv8.2", "armv8-a+", "rv64gc"] {
+            assert_eq!(
+                aarch64.set_march(march),
+                Err(format!("invalid -march '{}' for 'aarch64-linux-gnu'", march))
+            );
+        }
+        // an invalid -march doesn't change the features
+        assert_eq!(aarch64.features, vec!["+v8.2a", "+crypto", "-fp"]);
+    }
+
+    #[test]
+    fn march_riscv64() {
+        let mut riscv64 = target("riscv64-linux-gnu");
+        assert_eq!(riscv64.set_march("rv64i"), Ok(()));
+        assert!(riscv64.features.is_empty());
+        assert_eq!(riscv64.set_march("rv64gc_zba_zbb"), Ok(()));
+        assert_eq!(riscv64.features, vec!["+m", "+a", "+f", "+d", "+c", "+zba", "+zbb"]);
+        for march in &["rv32i", "rv64gx", "rv64imac_", "armv8-a"] {
+            assert!(riscv64.set_march(march).is_err());
+        }
+    }
+
+    #[test]
+    fn predefined_macros_lp64() {
+        let defines = predefined("x86_64-linux-gnu");
+        assert_defined(&defines, "__SIZEOF_LONG__", "8");
+        assert_defined(&defines, "__SIZEOF_POINTER__", "8");
+        assert_defined(&defines, "__LONG_MAX__", "9223372036854775807L");
+        assert_defined(&defines, "__SIZE_TYPE__", "unsigned long");
+        assert_defined(&defines, "__SIZE_MAX__", "18446744073709551615UL");
+        assert_defined(&defines, "__PTRDIFF_TYPE__", "long");
+        assert_defined(&defines, "__INT64_TYPE__", "long");
+        assert_defined(&defines, "__WCHAR_TYPE__", "int");
+        assert_defined(&defines, "__WCHAR_MIN__", "(-__WCHAR_MAX__ - 1)");
+        assert_defined(&defines, "__LP64__", "1");
+        assert_defined(&defines, "__x86_64__", "1");
+        assert_defined(&defines, "__linux__", "1");
+        assert_undefined(&defines, "__ILP32__");
+        assert_undefined(&defines, "__CHAR_UNSIGNED__");
+        assert_undefined(&defines, "__i386__");
+    }
+
+    #[test]
+    fn predefined_macros_ilp32() {
+        let defines = predefined("i386-linux-gnu");
+        assert_defined(&defines, "__SIZEOF_LONG__", "4");
+        assert_defined(&defines, "__SIZEOF_POINTER__", "4");
+        assert_defined(&defines, "__LONG_MAX__", "2147483647L");
+        assert_defined(&defines, "__SIZE_TYPE__", "unsigned int");
+        assert_defined(&defines, "__SIZE_MAX__", "4294967295U");
+        assert_defined(&defines, "__INT64_TYPE__", "long long");
+        assert_defined(&defines, "__INTMAX_MAX__", "9223372036854775807LL");
+        assert_defined(&defines, "__WCHAR_TYPE__", "long");
+        assert_defined(&defines, "__WCHAR_MAX__", "2147483647L");
+        assert_defined(&defines, "__ILP32__", "1");
+        assert_defined(&defines, "__i386__", "1");
+        assert_undefined(&defines, "__LP64__");
+        assert_undefined(&defines, "__x86_64__");
+
+        let x32 = predefined("x86_64-linux-gnux32");
+        assert_defined(&x32, "__SIZE_TYPE__", "unsigned int");
+        assert_defined(&x32, "__WCHAR_TYPE__", "int");
+        assert_defined(&x32, "__ILP32__", "1");
+        assert_defined(&x32, "__x86_64__", "1");
+    }
+
+    #[test]
+    fn predefined_macros_unsigned_char() {
+        let defines = predefined("aarch64-linux-gnu");
+        assert_defined(&defines, "__CHAR_UNSIGNED__", "1");
+        assert_defined(&defines, "__WCHAR_TYPE__", "unsigned int");
+        assert_defined(&defines, "__WCHAR_MAX__", "4294967295U");
+        assert_defined(&defines, "__WCHAR_MIN__", "0U");
+        assert_defined(&defines, "__aarch64__", "1");
+
+        let riscv64 = predefined("riscv64-unknown-linux-gnu");
+        assert_defined(&riscv64, "__CHAR_UNSIGNED__", "1");
+        assert_defined(&riscv64, "__WCHAR_TYPE__", "int");
+        assert_defined(&riscv64, "__riscv_xlen", "64");
+    }
+
+    #[test]
+    fn predefined_macros_not_linux() {
+        let defines = predefined("x86_64-unknown-none");
+        assert_defined(&defines, "__x86_64__", "1");
+        assert_undefined(&defines, "__linux__");
+        assert_undefined(&defines, "__ELF__");
+    }
+}
@@ -1,5 +1,6 @@
 use std::boxed::Box;
 use node::{ASTKind, AST};
+use target::Target;
 
 #[derive(PartialEq, Debug, Clone, Hash)]
 pub enum Sign {
@@ -37,6 +38,26 @@ pub enum Type {
 pub type RectypeName = String;
 
 impl Type {
+    // 'char' without 'signed' or 'unsigned'
+    pub fn plain_char(target: &Target) -> Type {
+        Type::Char(if target.char_unsigned {
+            Sign::Unsigned
+        } else {
+            Sign::Signed
+        })
+    }
+    // ptrdiff_t: the signed integer type as large as pointers
+    // (int on ILP32, long long on LLP64). the same as __PTRDIFF_TYPE__
+    pub fn ptrdiff(target: &Target) -> Type {
+        let sizes = &target.sizes;
+        if sizes.int == sizes.pointer {
+            Type::Int(Sign::Signed)
+        } else if sizes.long == sizes.pointer {
+            Type::Long(Sign::Signed)
+        } else {
+            Type::LLong(Sign::Signed)
+        }
+    }
     pub fn get_elem_ty<'a>(&'a self) -> Option<&'a Type> {
         match self {
             &Type::Ptr(ref elem_ty) | &Type::Array(ref elem_ty, _) => Some(&**elem_ty),
@@ -138,47 +159,59 @@ impl Type {
         }
     }
 
-    pub fn calc_size(&self) -> usize {
+    pub fn calc_size(&self, target: &Target) -> usize {
+        let sizes = &target.sizes;
+        let round_up = |off: usize, align: usize| (off + align - 1) / align * align;
         match self {
             &Type::Void => 0,
             &Type::Char(_) => 1,
-            &Type::Short(_) => 2,
-            &Type::Int(_) => 4,
-            &Type::Long(_) => 8,
-            &Type::LLong(_) => 8,
-            &Type::Float => 4,
-            &Type::Double => 8,
-            &Type::Ptr(ref _elemty) => 8,
-            &Type::Array(ref elemty, ref size) => (*size * elemty.calc_size() as i32) as usize,
+            &Type::Short(_) => sizes.short,
+            &Type::Int(_) => sizes.int,
+            &Type::Long(_) => sizes.long,
+            &Type::LLong(_) => sizes.long_long,
+            &Type::Float => sizes.float,
+            &Type::Double => sizes.double,
+            &Type::Ptr(ref _elemty) => sizes.pointer,
+            &Type::Array(ref elemty, ref size) => {
+                (*size * elemty.calc_size(target) as i32) as usize
+            }
             &Type::Func(ref _ret_type, ref _param_types, ref _is_vararg) => 1,
-            // TODO: must fix this sloppy implementation
             &Type::Struct(ref _name, ref fields) => {
                 let mut size_total = 0;
-                let calc_padding = |off, align| -> usize {
-                    if off % align == 0 {
-                        0
-                    } else {
-                        align - off % align
-                    }
-                };
                 for field in fields {
-                    size_total += if let ASTKind::VariableDecl(ref ty, _, _, _) = field.kind {
-                        let size = ty.calc_size();
-                        size + calc_padding(size_total, size)
-                    } else {
-                        0
-                    };
+                    if let ASTKind::VariableDecl(ref ty, _, _, _) = field.kind {
+                        let offset = round_up(size_total, ty.calc_align(target));
+                        size_total = offset + ty.calc_size(target);
+                    }
                 }
-                size_total
+                round_up(size_total, self.calc_align(target))
             }
+            // the largest field, padded to the alignment of the union
+            // (e.g. union { char c[5]; int i; } is 8 bytes)
             &Type::Union(ref _name, ref fields, ref max_nth) => {
                 if let ASTKind::VariableDecl(ref ty, _, _, _) = fields[*max_nth].kind {
-                    ty.calc_size()
+                    round_up(ty.calc_size(target), self.calc_align(target))
                 } else {
                     0
                 }
             }
-            &Type::Enum => 4,
+            &Type::Enum => sizes.int,
+        }
+    }
+    // the alignment in structs
+    pub fn calc_align(&self, target: &Target) -> usize {
+        match self {
+            &Type::Void | &Type::Func(_, _, _) => 1,
+            &Type::Array(ref elemty, _) => elemty.calc_align(target),
+            &Type::Struct(_, ref fields) | &Type::Union(_, ref fields, _) => fields
+                .iter()
+                .map(|field| match field.kind {
+                    ASTKind::VariableDecl(ref ty, _, _, _) => ty.calc_align(target),
+                    _ => 1,
+                })
+                .max()
+                .unwrap_or(1),
+            _ => ::std::cmp::min(self.calc_size(target), target.sizes.max_align),
         }
     }
 }